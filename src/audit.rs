@@ -0,0 +1,400 @@
+//! Tamper evidence for the version tables.
+//!
+//! Every new version of a project or timeblock stores an HMAC-SHA256 over its columns and the hash
+//! of the version of the same entity before it, so editing or removing a version in the database
+//! breaks the chain from there on. Versions written before schema version 9 carry no hash.
+//!
+//! The HMAC key lives in `~/.tt_audit_key`, outside the database, and is made on first use. Whoever
+//! can write the database but not read the key cannot forge a hash; whoever has the key can, so
+//! keep it from the people whose time is being audited. Losing it breaks every chain.
+//!
+//! Timeblock hashes cover the breaks and origin of each version and, through the `tags` column,
+//! its tags; `verify` also checks the `timeblock_tag` rows against that column, and that every
+//! entity is read from its newest version.
+
+use std::cell::RefCell;
+use std::collections::{ BTreeSet, HashMap, HashSet };
+use std::fs::OpenOptions;
+use std::io::{ Read, Write };
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use rusqlite::Connection;
+use rusqlite::types::{ ToSql, Value as SqlValue };
+use hmac::{ Hmac, Mac };
+use sha2::Sha256;
+
+use super::chrono::{ DateTime, Utc };
+use super::serde_json;
+use super::serde_json::Value;
+use super::doctor;
+use super::{ TimeblockFilter, TimeblockDataSource, DbId, Error };
+use super::{ chrono_to_sql, timeblock_json };
+
+/// The hashed columns of each version table, in hashing order.
+static PROJECT_COLUMNS: [&str; 7] = ["eid", "vid", "vtime", "remote_id", "name", "parent_eid", "alive"];
+static TIMEBLOCK_COLUMNS: [&str; 13] = ["eid", "vid", "vtime", "remote_id", "project_eid", "start", "end", "billable", "notes", "tags", "pauses", "origin", "alive"];
+
+fn columns(table: &str) -> Result<&'static [&'static str], Error> {
+	match table {
+		"project" => { Ok(&PROJECT_COLUMNS) }
+		"timeblock" => { Ok(&TIMEBLOCK_COLUMNS) }
+		_ => { Err(Error::TTError(format!("No hash chain for table: {}", table))) }
+	}
+}
+
+/// The file in the home directory holding the HMAC key.
+#[cfg(not(test))]
+static KEY_FILE: &str = ".tt_audit_key";
+
+thread_local! {
+	static KEY: RefCell<Option<Vec<u8>>> = const { RefCell::new(None) };
+}
+
+/// Reads the HMAC key at `path`, making a random one readable only by the user if there is none
+/// yet.
+fn key_at(path: &Path) -> Result<Vec<u8>, Error> {
+	match OpenOptions::new().write(true).create_new(true).mode(0o600).open(path) {
+		Ok(mut f) => {
+			let mut key = vec![0; 32];
+			std::fs::File::open("/dev/urandom")?.read_exact(&mut key)?;
+			f.write_all(&key)?;
+			Ok(key)
+		}
+		Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+			let mut key = Vec::new();
+			std::fs::File::open(path)?.read_to_end(&mut key)?;
+			if key.is_empty() {
+				return Err(Error::TTError(format!("The audit key is empty: {}", path.display())));
+			}
+			Ok(key)
+		}
+		Err(e) => { Err(Error::IOError(e)) }
+	}
+}
+
+#[cfg(not(test))]
+fn load_key() -> Result<Vec<u8>, Error> {
+	match std::env::home_dir() {
+		Some(mut path) => {
+			path.push(KEY_FILE);
+			key_at(&path)
+		}
+		None => { Err(Error::TTError("Failed finding the home directory for the audit key".to_string())) }
+	}
+}
+
+#[cfg(test)]
+fn load_key() -> Result<Vec<u8>, Error> {
+	Ok(b"tt test key".to_vec())
+}
+
+/// The HMAC-SHA256 of `s` under the audit key, as lowercase hex.
+pub fn mac(s: &str) -> Result<String, Error> {
+	let key = KEY.with(|k| -> Result<Vec<u8>, Error> {
+		if k.borrow().is_none() {
+			*k.borrow_mut() = Some(load_key()?);
+		}
+		Ok(k.borrow().clone().unwrap_or_default())
+	})?;
+	let mut m = match Hmac::<Sha256>::new_varkey(&key) {
+		Ok(m) => { m }
+		Err(_) => { return Err(Error::TTError("Invalid audit key".to_string())) }
+	};
+	m.input(s.as_bytes());
+	Ok(m.result().code().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn json(v: SqlValue) -> Value {
+	match v {
+		SqlValue::Null => { Value::Null }
+		SqlValue::Integer(i) => { json!(i) }
+		SqlValue::Real(f) => { json!(f) }
+		SqlValue::Text(s) => { Value::String(s) }
+		SqlValue::Blob(b) => { Value::String(b.iter().map(|b| format!("{:02x}", b)).collect()) }
+	}
+}
+
+fn hash(prev: &Option<String>, values: &[Value]) -> Result<String, Error> {
+	mac(&serde_json::to_string(&json!([prev, values]))?)
+}
+
+/// The hashed columns of a version and its stored hash.
+type Row = (Vec<Value>, Option<String>);
+
+/// Reads the columns `cols` and the stored hash of the rows of `table` matching `condition`.
+fn rows(conn: &Connection, table: &str, cols: &[&str], condition: &str, params: &[&dyn ToSql]) -> Result<Vec<Row>, Error> {
+	let mut stmt = conn.prepare(&format!("SELECT {}, hash FROM {} WHERE {} ORDER BY eid, vid", cols.join(", "), table, condition))?;
+	let mut out = Vec::new();
+	for r in stmt.query_map(params, |row| {
+		let values: Vec<Value> = (0..cols.len()).map(|i| json(row.get(i))).collect();
+		let hash: Option<String> = row.get(cols.len());
+		(values, hash)
+	})? {
+		out.push(r?);
+	}
+	Ok(out)
+}
+
+/// Stores the hash of a version that was just written.
+pub fn seal(conn: &Connection, table: &'static str, eid: DbId, vid: DbId) -> Result<(), Error> {
+	let cols = columns(table)?;
+	let prev: Option<String> = match rows(conn, table, cols, "eid=? AND vid<?", &[&eid, &vid])?.pop() {
+		Some((_, h)) => { h }
+		None => { None }
+	};
+	let values = match rows(conn, table, cols, "eid=? AND vid=?", &[&eid, &vid])?.pop() {
+		Some((v, _)) => { v }
+		None => { return Err(Error::NotFound(format!("Failed finding {} version: {}.{}", table, eid, vid))) }
+	};
+	conn.execute(&format!("UPDATE {} SET hash=? WHERE eid=? AND vid=?", table), &[&hash(&prev, &values)?, &eid, &vid])?;
+	Ok(())
+}
+
+/// A version whose hash does not follow from its content and the chain before it, or an entity
+/// otherwise altered behind tt's back.
+pub struct Break {
+	pub entity: &'static str,
+	pub id: DbId,
+	pub vid: DbId,
+	pub problem: &'static str,
+}
+
+/// Walks the hash chains of the rows of `table` read as `cols`, adding their breaks to `out` and
+/// returning how many versions were checked and how many carry no hash.
+fn walk(conn: &Connection, table: &'static str, cols: &[&str], out: &mut Vec<Break>) -> Result<(usize, usize), Error> {
+	let mut checked = 0;
+	let mut unhashed = 0;
+	let mut eid: Option<i64> = None;
+	let mut prev: Option<String> = None;
+	for (values, stored) in rows(conn, table, cols, "1", &[])? {
+		let (e, vid) = (values[0].as_i64(), values[1].as_i64().unwrap_or(-1));
+		if e != eid {
+			eid = e;
+			prev = None;
+		}
+		let id = e.unwrap_or(-1);
+		match stored {
+			None => {
+				unhashed += 1;
+				if prev.is_some() {
+					out.push(Break { entity: table, id, vid, problem: "has no hash, though the version before it has one" });
+				}
+			}
+			Some(ref h) => {
+				checked += 1;
+				if *h != hash(&prev, &values)? {
+					out.push(Break { entity: table, id, vid, problem: "does not match its hash; it or a version before it was changed or removed" });
+				}
+			}
+		}
+		// Go on from the stored hash so one edit is reported once.
+		prev = stored;
+	}
+	Ok((checked, unhashed))
+}
+
+/// Adds a break for every entity of `table` not read from its newest version.
+fn stale_current(conn: &Connection, table: &'static str, out: &mut Vec<Break>) -> Result<(), Error> {
+	for (eid, latest, current) in doctor::stale_current(conn, table)? {
+		out.push(match current {
+			Some(vid) => { Break { entity: table, id: eid, vid, problem: "is read from this version instead of its newest one" } }
+			None => { Break { entity: table, id: eid, vid: latest, problem: "has no current version" } }
+		});
+	}
+	Ok(())
+}
+
+/// Adds a break for every timeblock version whose `timeblock_tag` rows differ from its hashed tags.
+fn tag_rows(conn: &Connection, out: &mut Vec<Break>) -> Result<(), Error> {
+	let mut indexed: HashMap<(DbId, DbId), BTreeSet<String>> = HashMap::new();
+	let mut stmt = conn.prepare("SELECT timeblock_eid, timeblock_vid, tag FROM timeblock_tag")?;
+	for r in stmt.query_map(&[], |row| {
+		let eid: DbId = row.get(0);
+		let vid: DbId = row.get(1);
+		let tag: String = row.get(2);
+		(eid, vid, tag)
+	})? {
+		let (eid, vid, tag) = r?;
+		indexed.entry((eid, vid)).or_default().insert(tag);
+	}
+	let none = BTreeSet::new();
+	let mut stmt = conn.prepare("SELECT eid, vid, tags FROM timeblock ORDER BY eid, vid")?;
+	for r in stmt.query_map(&[], |row| {
+		let eid: DbId = row.get(0);
+		let vid: DbId = row.get(1);
+		let tags: String = row.get(2);
+		(eid, vid, tags)
+	})? {
+		let (eid, vid, tags) = r?;
+		let hashed: BTreeSet<String> = tags.split('\n').map(|t| t.trim()).filter(|t| !t.is_empty()).map(|t| t.to_string()).collect();
+		if hashed != *indexed.get(&(eid, vid)).unwrap_or(&none) {
+			out.push(Break { entity: "timeblock", id: eid, vid, problem: "has tag rows that differ from its hashed tags" });
+		}
+	}
+	Ok(())
+}
+
+/// Walks every hash chain and checks the tag rows and current versions, returning how many
+/// versions were checked, how many carry no hash and the breaks found.
+///
+/// Removing the newest versions of an entity and pointing it at the one before leaves its chain
+/// intact; compare the hashes in an earlier `tt audit export` to catch that.
+pub fn verify(conn: &Connection) -> Result<(usize, usize, Vec<Break>), Error> {
+	let mut checked = 0;
+	let mut unhashed = 0;
+	let mut breaks = Vec::new();
+	for &table in ["project", "timeblock"].iter() {
+		let (c, u) = walk(conn, table, columns(table)?, &mut breaks)?;
+		checked += c;
+		unhashed += u;
+		stale_current(conn, table, &mut breaks)?;
+	}
+	tag_rows(conn, &mut breaks)?;
+	Ok((checked, unhashed, breaks))
+}
+
+/// The closed timeblocks in `[from, to)` matching `filter` as they were at `when`, each with its
+/// hash and whether it verifies, plus an HMAC-SHA256 digest over the period, query and entries
+/// under the audit key, for a report made at `generated`.
+///
+/// The digest leaves out when the report was made, so exporting again later with `--as-of` set to
+/// that time gives the same digest unless the data or the report was tampered with. Checking it
+/// takes the audit key.
+pub fn export(conn: &Connection, from: DateTime<Utc>, to: DateTime<Utc>, query: Option<&str>, filter: Option<TimeblockFilter>, when: Option<DateTime<Utc>>, generated: DateTime<Utc>) -> Result<Value, Error> {
+	let tbsrc: &dyn TimeblockDataSource = conn;
+	let (_, _, breaks) = verify(conn)?;
+	let broken: HashSet<DbId> = breaks.iter().filter(|b| b.entity == "timeblock").map(|b| b.id).collect();
+	let mut f = TimeblockFilter::Alive(true).and(TimeblockFilter::Open(false)).and(TimeblockFilter::Between(from, to));
+	if let Some(filter) = filter {
+		f = f.and(filter);
+	}
+	let mut entries = Vec::new();
+	let mut intact = true;
+	for tb in tbsrc.search(Some(f), when)? {
+		let h: Option<String> = conn.query_row("SELECT hash FROM timeblock WHERE eid=? AND vid=?", &[&tb.ev.eid, &tb.ev.vid], |row| row.get(0))?;
+		let ok = h.is_some() && !broken.contains(&tb.ev.eid);
+		intact = intact && ok;
+		let mut entry = timeblock_json(conn, &tb, when)?;
+		entry["vid"] = json!(tb.ev.vid);
+		entry["hash"] = json!(h);
+		entry["intact"] = json!(ok);
+		entries.push(entry);
+	}
+	let mut report = json!({
+		"from": chrono_to_sql(from),
+		"to": chrono_to_sql(to),
+		"query": query,
+		"timeblocks": entries,
+	});
+	report["digest"] = json!(mac(&serde_json::to_string(&report)?)?);
+	report["generated"] = json!(chrono_to_sql(generated));
+	report["intact"] = json!(intact);
+	Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::db;
+	use super::super::{ ProjectDataSource, ProjectRef, TimeblockRef, Origin };
+
+	fn at(s: &str) -> DateTime<Utc> {
+		s.parse::<DateTime<Utc>>().unwrap()
+	}
+
+	/// A project and a closed timeblock with a tag, then the timeblock again with a break added.
+	fn tracked(conn: &Connection) -> DbId {
+		let psrc: &dyn ProjectDataSource = conn;
+		let tbsrc: &dyn TimeblockDataSource = conn;
+		let p = psrc.upsert("Acme".to_string(), "/projects/1".to_string(), None, true).unwrap();
+		let (start, end) = (at("2020-01-01T09:00:00Z"), Some(at("2020-01-01T12:00:00Z")));
+		let tb = tbsrc.upsert(None, None, ProjectRef::EId(p.ev.eid), start, end, true, "x".to_string(), vec!["a".to_string()], vec![], true, Origin::User).unwrap();
+		let pauses = vec![(at("2020-01-01T10:00:00Z"), Some(at("2020-01-01T10:30:00Z")))];
+		tbsrc.upsert(Some(TimeblockRef::EId(tb.ev.eid)), None, ProjectRef::EId(p.ev.eid), start, end, true, "x".to_string(), vec!["a".to_string()], pauses, true, Origin::User).unwrap();
+		tb.ev.eid
+	}
+
+	fn problems(conn: &Connection) -> Vec<(&'static str, DbId, &'static str)> {
+		verify(conn).unwrap().2.into_iter().map(|b| (b.entity, b.vid, b.problem)).collect()
+	}
+
+	#[test]
+	fn chains_each_version_onto_the_one_before() {
+		let conn = db::memory();
+		let eid = tracked(&conn);
+		assert_eq!(verify(&conn).unwrap().0, 3);
+		assert!(problems(&conn).is_empty());
+
+		let first: String = conn.query_row("SELECT hash FROM timeblock WHERE eid=? AND vid=0", &[&eid], |row| row.get(0)).unwrap();
+		let values = rows(&conn, "timeblock", &TIMEBLOCK_COLUMNS, "eid=? AND vid=1", &[&eid]).unwrap().pop().unwrap();
+		assert_eq!(values.1, Some(hash(&Some(first), &values.0).unwrap()));
+	}
+
+	#[test]
+	fn detects_changed_and_removed_versions() {
+		let conn = db::memory();
+		let eid = tracked(&conn);
+		conn.execute("UPDATE timeblock SET pauses='' WHERE eid=? AND vid=1", &[&eid]).unwrap();
+		assert_eq!(problems(&conn), vec![("timeblock", 1, "does not match its hash; it or a version before it was changed or removed")]);
+
+		let conn = db::memory();
+		let eid = tracked(&conn);
+		conn.execute("UPDATE timeblock SET origin='sync' WHERE eid=? AND vid=0", &[&eid]).unwrap();
+		assert_eq!(problems(&conn).len(), 1);
+
+		let conn = db::memory();
+		let eid = tracked(&conn);
+		conn.execute("DELETE FROM timeblock WHERE eid=? AND vid=0", &[&eid]).unwrap();
+		assert_eq!(problems(&conn), vec![("timeblock", 1, "does not match its hash; it or a version before it was changed or removed")]);
+	}
+
+	#[test]
+	fn detects_tag_rows_that_differ_from_the_hashed_tags() {
+		let conn = db::memory();
+		let eid = tracked(&conn);
+		conn.execute("INSERT INTO timeblock_tag (timeblock_eid, timeblock_vid, tag) VALUES (?, 1, 'b')", &[&eid]).unwrap();
+		assert_eq!(problems(&conn), vec![("timeblock", 1, "has tag rows that differ from its hashed tags")]);
+		conn.execute("DELETE FROM timeblock_tag WHERE timeblock_eid=?", &[&eid]).unwrap();
+		assert_eq!(problems(&conn).len(), 2);
+	}
+
+	#[test]
+	fn detects_entities_read_from_an_older_version() {
+		let conn = db::memory();
+		let eid = tracked(&conn);
+		conn.execute("UPDATE timeblock_current SET vid=0 WHERE eid=?", &[&eid]).unwrap();
+		assert_eq!(problems(&conn), vec![("timeblock", 0, "is read from this version instead of its newest one")]);
+		conn.execute("DELETE FROM timeblock_current WHERE eid=?", &[&eid]).unwrap();
+		assert_eq!(problems(&conn), vec![("timeblock", 1, "has no current version")]);
+	}
+
+	#[test]
+	fn makes_a_private_key_once_and_reads_it_back() {
+		use std::os::unix::fs::PermissionsExt;
+		let path = std::env::temp_dir().join(format!("tt_audit_key_{}", std::process::id()));
+		let _ = std::fs::remove_file(&path);
+		let key = key_at(&path).unwrap();
+		assert_eq!(key.len(), 32);
+		assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+		assert_eq!(key_at(&path).unwrap(), key);
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn exports_the_same_digest_until_the_data_changes() {
+		let conn = db::memory();
+		let eid = tracked(&conn);
+		let (from, to) = (at("2020-01-01T00:00:00Z"), at("2020-01-02T00:00:00Z"));
+		let a = export(&conn, from, to, None, None, None, at("2020-02-01T00:00:00Z")).unwrap();
+		let b = export(&conn, from, to, None, None, None, at("2020-03-01T00:00:00Z")).unwrap();
+		assert_eq!(a["digest"], b["digest"]);
+		assert_eq!(a["intact"], json!(true));
+
+		conn.execute("UPDATE timeblock SET notes='y' WHERE eid=? AND vid=1", &[&eid]).unwrap();
+		let c = export(&conn, from, to, None, None, None, at("2020-03-01T00:00:00Z")).unwrap();
+		assert_ne!(a["digest"], c["digest"]);
+		assert_eq!(c["intact"], json!(false));
+		assert_eq!(c["timeblocks"][0]["intact"], json!(false));
+	}
+}
@@ -0,0 +1,104 @@
+//! Timings of the common queries on a large generated history, run with
+//! `cargo test --release bench -- --ignored --nocapture`. `TT_BENCH_COUNT` sets the number of
+//! timeblocks, 100000 by default.
+
+use std::env;
+use std::time::Instant;
+
+use rusqlite::Connection;
+
+use super::chrono::{ DateTime, Utc };
+use super::time::Duration;
+use super::db;
+use super::projects;
+use super::tags;
+use super::{ TimeTracker, Offline, TimeblockFilter, TimeblockRef, TimeblockDataSource, ProjectDataSource, ProjectRef, Origin, DbId, Error };
+use super::{ transaction, period_bounds, chrono_to_sql, LOCAL_REMOTE_ID };
+
+const PROJECTS: usize = 50;
+const OPEN: usize = 2;
+const COUNT: usize = 100000;
+
+/// Runs `f` and prints how long it took.
+fn time<T, F: FnOnce() -> Result<T, Error>>(name: &str, f: F) -> Result<T, Error> {
+	let start = Instant::now();
+	let x = f()?;
+	let d = start.elapsed();
+	println!("{:<28}{:>10.1} ms", name, d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 / 1e6);
+	Ok(x)
+}
+
+/// Moves the versions of an entity to when they would have been written.
+fn backdate(conn: &Connection, table: &str, eid: DbId, vid: DbId, vtime: DateTime<Utc>) -> Result<(), Error> {
+	conn.execute(&format!("UPDATE {} SET vtime=? WHERE eid=? AND vid=?", table), &[&chrono_to_sql(vtime), &eid, &vid])?;
+	Ok(())
+}
+
+/// Fills an in-memory database with `count` timeblocks, half an hour apart up to now and each
+/// closed in a second version, and times the queries behind `status`, `log`, `projects` and `tags`.
+fn run(count: usize) -> Result<(), Error> {
+	let conn = Connection::open_in_memory()?;
+	db::migrate(&conn)?;
+	let psrc: &dyn ProjectDataSource = &conn;
+	let tbsrc: &dyn TimeblockDataSource = &conn;
+	let now = Utc::now();
+
+	let first = now - Duration::minutes(30 * count as i64 + 60);
+	let projects = time(&format!("create {} projects", PROJECTS), || {
+		transaction(&conn, || {
+			let root = psrc.upsert("Bench".to_string(), format!("{}bench", LOCAL_REMOTE_ID), None, true)?;
+			let mut out = vec![root.ev.eid];
+			for i in 1..PROJECTS {
+				let parent = out[(i-1) / 5];
+				out.push(psrc.upsert(format!("Project {}", i), format!("{}bench/{}", LOCAL_REMOTE_ID, i), Some(parent), true)?.ev.eid);
+			}
+			for &eid in out.iter() {
+				backdate(&conn, "project", eid, 0, first)?;
+			}
+			Ok(out)
+		})
+	})?;
+
+	time(&format!("create {} timeblocks", count), || {
+		transaction(&conn, || {
+			for i in 0..count {
+				let start = now - Duration::minutes(30 * (count - i) as i64);
+				let project = ProjectRef::EId(projects[i % projects.len()]);
+				let tags = if i % 10 == 0 { vec!["meeting".to_string()] } else { vec![] };
+				let tb = tbsrc.upsert(None, None, project.clone(), start, None, i % 3 == 0, format!("block {}", i), tags.clone(), vec![], true, Origin::User)?;
+				backdate(&conn, "timeblock", tb.ev.eid, 0, start)?;
+				if i + OPEN < count {
+					let end = start + Duration::minutes(25);
+					tbsrc.upsert(Some(TimeblockRef::EId(tb.ev.eid)), None, project, start, Some(end), i % 3 == 0, tb.notes, tags, vec![], true, Origin::User)?;
+					backdate(&conn, "timeblock", tb.ev.eid, 1, end)?;
+				}
+			}
+			Ok(())
+		})
+	})?;
+
+	let s = Offline { conn: &conn };
+	let (week_from, week_to) = period_bounds("week", now)?;
+	time("status", || s.status(None))?;
+	time("open timeblocks", || s.open_timeblocks())?;
+	time("fqn of every project", || {
+		for &eid in projects.iter() {
+			psrc.fqn(ProjectRef::EId(eid), None)?;
+		}
+		Ok(())
+	})?;
+	time("log", || tbsrc.search(Some(TimeblockFilter::Alive(true)), None))?;
+	time("log as of a day ago", || tbsrc.search(Some(TimeblockFilter::Alive(true)), Some(now - Duration::days(1))))?;
+	time("log this week", || tbsrc.search(Some(TimeblockFilter::Between(week_from, week_to)), None))?;
+	time("log tag:meeting", || tbsrc.search(Some(TimeblockFilter::Tag("meeting".to_string())), None))?;
+	time("totals this week", || projects::totals(&conn, week_from, week_to, None, None))?;
+	time("tags", || tags::list(&conn, None))?;
+	Ok(())
+}
+
+#[test]
+#[ignore]
+fn bench() {
+	let count = env::var("TT_BENCH_COUNT").ok().and_then(|c| c.parse::<usize>().ok()).unwrap_or(COUNT);
+	run(count).unwrap();
+}
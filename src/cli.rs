@@ -4,16 +4,306 @@ pub fn build_cli() -> App<'static, 'static> {
 	App::new("tt")
 		.subcommand(SubCommand::with_name("down")
 		)
+		.subcommand(SubCommand::with_name("up")
+			.about("Push closed timeblocks upstream")
+		)
 		.subcommand(SubCommand::with_name("projects")
+			.arg(Arg::with_name("tree")
+				.long("tree")
+				.help("Show projects nested under their parents")
+			)
+			.arg(Arg::with_name("active")
+				.long("active")
+				.help("Hide archived projects")
+			)
+			.arg(Arg::with_name("search")
+				.long("search")
+				.value_name("TEXT")
+				.help("Only show projects whose name contains TEXT")
+				.takes_value(true)
+			)
+			.arg(Arg::with_name("depth")
+				.long("depth")
+				.value_name("N")
+				.help("Only show projects up to N levels deep")
+				.takes_value(true)
+			)
+			.arg(Arg::with_name("period")
+				.long("period")
+				.value_name("PERIOD")
+				.help("Show the time tracked on each project during PERIOD")
+				.possible_values(&["today", "yesterday", "week", "month", "year", "all"])
+				.takes_value(true)
+			)
+			.arg(Arg::with_name("query")
+				.long("query")
+				.value_name("QUERY")
+				.help("With --period, only count the time on timeblocks matching QUERY, as for `tt log`")
+				.requires("period")
+				.takes_value(true)
+			)
+			.subcommand(SubCommand::with_name("add")
+				.about("Create a local project, and any missing parents")
+				.arg(Arg::with_name("path")
+					.required(true)
+					.index(1)
+				)
+			)
+			.subcommand(SubCommand::with_name("rename")
+				.arg(Arg::with_name("path")
+					.required(true)
+					.index(1)
+				)
+				.arg(Arg::with_name("name")
+					.required(true)
+					.index(2)
+				)
+			)
+			.subcommand(SubCommand::with_name("move")
+				.arg(Arg::with_name("path")
+					.required(true)
+					.index(1)
+				)
+				.arg(Arg::with_name("parent")
+					.help("New parent, or / for the top level")
+					.required(true)
+					.index(2)
+				)
+			)
+			.subcommand(SubCommand::with_name("archive")
+				.arg(Arg::with_name("path")
+					.required(true)
+					.index(1)
+				)
+			)
+			.subcommand(SubCommand::with_name("unarchive")
+				.arg(Arg::with_name("path")
+					.required(true)
+					.index(1)
+				)
+			)
+			.subcommand(SubCommand::with_name("reset")
+				.about("Drop local edits of a synced project so the next sync takes the remote's")
+				.arg(Arg::with_name("path")
+					.required(true)
+					.index(1)
+				)
+			)
 		)
 		.subcommand(SubCommand::with_name("status")
+			.about("Show the open timers and the time tracked today")
+			.after_help("As JSON: {\"open\": [{\"project\", \"duration\", \"state\"}, ...], \"today\"}, with durations in \
+			             seconds. Older releases printed {\"open\": [[project, \"hh:mm:ss\"], ...]}.")
+			.arg(Arg::with_name("prompt")
+				.long("prompt")
+				.help("Print a short line for a shell prompt, using the status.template setting")
+				.conflicts_with_all(&["waybar", "i3blocks", "quiet"])
+			)
+			.arg(Arg::with_name("template")
+				.long("template")
+				.value_name("TEMPLATE")
+				.help("Template for --prompt, --waybar and --i3blocks, with {project}, {name}, {elapsed}, {state} and {today}")
+				.takes_value(true)
+			)
+			.arg(Arg::with_name("waybar")
+				.long("waybar")
+				.help("Print JSON for a waybar custom module")
+				.conflicts_with_all(&["i3blocks", "quiet"])
+			)
+			.arg(Arg::with_name("i3blocks")
+				.long("i3blocks")
+				.help("Print JSON for an i3blocks block with format=json")
+				.conflicts_with("quiet")
+			)
+			.arg(Arg::with_name("quiet")
+				.long("quiet")
+				.short("q")
+				.help("Print nothing; exit with 0 if a timer is running and 1 otherwise")
+			)
 		)
-		.subcommand(SubCommand::with_name("punchin")
+		.subcommand(SubCommand::with_name("tags")
+			.about("List, rename and merge tags, or change the tags of a timeblock")
+			.subcommand(SubCommand::with_name("list")
+				.about("List the tags in use, with the number of timeblocks for each")
+			)
+			.subcommand(SubCommand::with_name("add")
+				.about("Add tags to a timeblock")
+				.arg(Arg::with_name("id")
+					.required(true)
+					.index(1)
+				)
+				.arg(Arg::with_name("tags")
+					.required(true)
+					.multiple(true)
+					.index(2)
+				)
+			)
+			.subcommand(SubCommand::with_name("rm")
+				.about("Remove tags from a timeblock")
+				.arg(Arg::with_name("id")
+					.required(true)
+					.index(1)
+				)
+				.arg(Arg::with_name("tags")
+					.required(true)
+					.multiple(true)
+					.index(2)
+				)
+			)
+			.subcommand(SubCommand::with_name("rename")
+				.about("Rename a tag, along with the tags under it")
+				.arg(Arg::with_name("from")
+					.required(true)
+					.index(1)
+				)
+				.arg(Arg::with_name("to")
+					.required(true)
+					.index(2)
+				)
+			)
+			.subcommand(SubCommand::with_name("merge")
+				.about("Replace one or more tags by another")
+				.arg(Arg::with_name("from")
+					.required(true)
+					.multiple(true)
+					.index(1)
+				)
+				.arg(Arg::with_name("into")
+					.long("into")
+					.value_name("TAG")
+					.required(true)
+					.takes_value(true)
+				)
+			)
+		)
+		.subcommand(SubCommand::with_name("watch")
+			.about("Show a live dashboard of the open timers, totals and recent timeblocks")
+		)
+		.subcommand(SubCommand::with_name("log")
+			.about("List timeblocks")
+			.arg(Arg::with_name("tag")
+				.long("tag")
+				.value_name("PATTERN")
+				.help("Only show timeblocks tagged PATTERN or a tag under it, such as client:acme under client; a trailing * matches any tag starting with PATTERN")
+				.takes_value(true)
+			)
+			.arg(Arg::with_name("query")
+				.index(1)
+				.multiple(true)
+				.allow_hyphen_values(true)
+				.help("Only show timeblocks matching QUERY, such as 'project:acme/* tag:meeting since:monday -billable'; terms are project:GLOB, project:GLOB/** for whole subtrees, tag:TAG, since:, until: and on: with a date, period or weekday, id:N, notes:TEXT, billable, open, closed and plain words found in the notes, combined with and, or, not, - and parentheses; put the query after -- when it starts with -")
+			)
+		)
+		.subcommand(SubCommand::with_name("pause")
+			.about("Start a break on the running timers")
+		)
+		.subcommand(SubCommand::with_name("resume")
+			.about("End the break on the paused timers")
+		)
+		.subcommand(SubCommand::with_name("switch")
+			.about("Stop all running timers and start one on another project")
 			.arg(Arg::with_name("project")
 				.required(true)
 				.index(1)
 			)
 		)
+		.subcommand(SubCommand::with_name("alias")
+			.about("Manage short names for projects")
+			.subcommand(SubCommand::with_name("add")
+				.arg(Arg::with_name("name")
+					.required(true)
+					.index(1)
+				)
+				.arg(Arg::with_name("project")
+					.required(true)
+					.index(2)
+				)
+			)
+			.subcommand(SubCommand::with_name("rm")
+				.arg(Arg::with_name("name")
+					.required(true)
+					.index(1)
+				)
+			)
+			.subcommand(SubCommand::with_name("list")
+			)
+		)
+		.subcommand(SubCommand::with_name("favorites")
+			.about("List the most used projects")
+			.arg(Arg::with_name("n")
+				.required(false)
+				.index(1)
+			)
+		)
+		.subcommand(SubCommand::with_name("recent")
+			.about("List the most recently used projects")
+			.arg(Arg::with_name("n")
+				.required(false)
+				.index(1)
+			)
+		)
+		.subcommand(SubCommand::with_name("config")
+			.about("Show or change configuration")
+			.arg(Arg::with_name("key")
+				.required(false)
+				.index(1)
+			)
+			.arg(Arg::with_name("value")
+				.required(false)
+				.index(2)
+			)
+		)
+		.subcommand(SubCommand::with_name("undo")
+			.about("Revert the last N timeblock changes")
+			.arg(Arg::with_name("n")
+				.required(false)
+				.index(1)
+			)
+			.arg(Arg::with_name("force")
+				.long("force")
+				.help("Undo changes that have already been synced")
+			)
+		)
+		.subcommand(SubCommand::with_name("redo")
+			.about("Re-apply the last N undone changes")
+			.arg(Arg::with_name("n")
+				.required(false)
+				.index(1)
+			)
+		)
+		.subcommand(SubCommand::with_name("history")
+			.about("Show every version of a timeblock or project")
+			.arg(Arg::with_name("entity")
+				.required(true)
+				.possible_values(&["timeblock", "project"])
+				.index(1)
+			)
+			.arg(Arg::with_name("id")
+				.help("Local id or remote id")
+				.required(true)
+				.index(2)
+			)
+		)
+		.subcommand(SubCommand::with_name("punchin")
+			.arg(Arg::with_name("project")
+				.required(false)
+				.index(1)
+			)
+			.arg(Arg::with_name("interactive")
+				.short("i")
+				.long("interactive")
+				.help("Pick the project interactively")
+			)
+			.arg(Arg::with_name("tag")
+				.short("t")
+				.long("tag")
+				.value_name("TAG")
+				.help("Tag the new timeblock; may be given more than once")
+				.takes_value(true)
+				.multiple(true)
+				.number_of_values(1)
+			)
+		)
 		.subcommand(SubCommand::with_name("punchout")
 			.arg(Arg::with_name("project")
 				.required(false)
@@ -27,9 +317,165 @@ pub fn build_cli() -> App<'static, 'static> {
 			.help("Sets a custom config file")
 			.takes_value(true)
 		)
+		.arg(Arg::with_name("as-of")
+			.long("as-of")
+			.value_name("TIME")
+			.help("Shows the state as it was at TIME")
+			.takes_value(true)
+			.global(true)
+		)
+		.arg(Arg::with_name("format")
+			.long("format")
+			.value_name("FORMAT")
+			.help("Output format; defaults to table on a terminal and json otherwise")
+			.possible_values(&["table", "json", "csv", "plain"])
+			.takes_value(true)
+			.global(true)
+		)
+		.arg(Arg::with_name("duration")
+			.long("duration")
+			.value_name("STYLE")
+			.help("Show durations as hh:mm:ss or as decimal hours; JSON always gives seconds")
+			.possible_values(&["hms", "decimal"])
+			.takes_value(true)
+			.global(true)
+		)
+		.subcommand(SubCommand::with_name("shell")
+			.about("Run several commands in one session, with history and completion")
+		)
+		.subcommand(SubCommand::with_name("__complete")
+			.about("Print completions for a partial command line, one per line")
+			.setting(AppSettings::Hidden)
+			.setting(AppSettings::TrailingVarArg)
+			.arg(Arg::with_name("words")
+				.multiple(true)
+				.allow_hyphen_values(true)
+			)
+		)
+		.subcommand(SubCommand::with_name("doctor")
+			.about("Check the database for inconsistent or suspicious data")
+			.arg(Arg::with_name("fix")
+				.long("fix")
+				.help("Apply the suggested fixes; they only add new versions, so they can be undone")
+			)
+		)
+		.subcommand(SubCommand::with_name("audit")
+			.about("Prove that tracked time was not altered after the fact")
+			.setting(AppSettings::SubcommandRequiredElseHelp)
+			.subcommand(SubCommand::with_name("verify")
+				.about("Check the hash chains, tag rows and current versions of all projects and timeblocks")
+			)
+			.subcommand(SubCommand::with_name("export")
+				.about("Print the closed timeblocks of a period with their hashes and a keyed digest over them")
+				.arg(Arg::with_name("period")
+					.long("period")
+					.value_name("PERIOD")
+					.help("Export the timeblocks of PERIOD")
+					.possible_values(&["today", "yesterday", "week", "month", "year", "all"])
+					.default_value("month")
+				)
+				.arg(Arg::with_name("from")
+					.long("from")
+					.value_name("TIME")
+					.help("Export the timeblocks from TIME instead of a whole period")
+					.requires("to")
+					.takes_value(true)
+				)
+				.arg(Arg::with_name("to")
+					.long("to")
+					.value_name("TIME")
+					.help("Export the timeblocks up to TIME")
+					.requires("from")
+					.takes_value(true)
+				)
+				.arg(Arg::with_name("query")
+					.index(1)
+					.multiple(true)
+					.allow_hyphen_values(true)
+					.help("Only export timeblocks matching QUERY, as for `tt log`")
+				)
+			)
+		)
+		.subcommand(SubCommand::with_name("db")
+			.about("Maintain the database")
+			.setting(AppSettings::SubcommandRequiredElseHelp)
+			.subcommand(SubCommand::with_name("migrate")
+				.about("Bring the database schema up to date, after saving a copy next to it")
+				.arg(Arg::with_name("dry-run")
+					.long("dry-run")
+					.help("Only list the migrations that would be applied")
+				)
+			)
+			.subcommand(SubCommand::with_name("compact")
+				.about("Remove old unhashed versions that changed nothing, and shrink the file")
+				.arg(Arg::with_name("keep-days")
+					.long("keep-days")
+					.value_name("DAYS")
+					.help("Keep every version written in the last DAYS days")
+					.default_value("30")
+				)
+				.arg(Arg::with_name("dry-run")
+					.long("dry-run")
+					.help("Only count the versions that would be removed")
+				)
+			)
+		)
 		.subcommand(SubCommand::with_name("completions")
 			.about("Generate completion scripts for your shell")
 			.setting(AppSettings::ArgRequiredElseHelp)
 			.arg(Arg::with_name("shell").possible_values(&Shell::variants())))
 }
 
+
+/// The visible subcommands of `build_cli`, each with its own subcommands, for completion.
+pub static SUBCOMMANDS: [(&str, &[&str]); 24] = [
+	("down", &[]),
+	("up", &[]),
+	("projects", &["add", "rename", "move", "archive", "unarchive", "reset"]),
+	("status", &[]),
+	("tags", &["list", "add", "rm", "rename", "merge"]),
+	("watch", &[]),
+	("log", &[]),
+	("pause", &[]),
+	("resume", &[]),
+	("switch", &[]),
+	("alias", &["add", "rm", "list"]),
+	("favorites", &[]),
+	("recent", &[]),
+	("config", &[]),
+	("undo", &[]),
+	("redo", &[]),
+	("history", &[]),
+	("punchin", &[]),
+	("punchout", &[]),
+	("shell", &[]),
+	("doctor", &[]),
+	("audit", &["verify", "export"]),
+	("db", &["migrate", "compact"]),
+	("completions", &[]),
+];
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use clap::ErrorKind;
+
+	fn exists(words: &[&str]) -> bool {
+		let args = ["tt"].iter().chain(words.iter()).chain(["--help"].iter());
+		match build_cli().get_matches_from_safe(args) {
+			Err(e) => { e.kind == ErrorKind::HelpDisplayed }
+			Ok(_) => { false }
+		}
+	}
+
+	#[test]
+	fn lists_only_real_subcommands() {
+		for &(name, subs) in SUBCOMMANDS.iter() {
+			assert!(exists(&[name]), "{}", name);
+			for sub in subs {
+				assert!(exists(&[name, sub]), "{} {}", name, sub);
+			}
+		}
+		assert!(!exists(&["nonsense"]));
+	}
+}
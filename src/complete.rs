@@ -0,0 +1,203 @@
+use std::cmp::Reverse;
+
+use clap;
+
+use super::cli;
+use super::projects;
+use super::tags;
+use super::shell;
+use super::{ TimeTracker, TimeblockFilter, TimeblockDataSource, ProjectDataSource, Error };
+use super::{ alias_list, CONFIG };
+
+/// Options whose value is the next word on the command line.
+static VALUE_OPTIONS: [&str; 9] = ["--as-of", "--format", "--duration", "--template", "--search", "--depth", "--period", "--tag", "--query"];
+const RECENT_IDS: usize = 50;
+
+/// Subcommands that take project names, by the position of their project arguments.
+fn takes_project(path: &str, position: usize) -> bool {
+	match path {
+		"punchin" | "punchout" | "switch" | "projects add" | "projects rename" | "projects archive" | "projects unarchive" | "projects reset" => { position == 0 }
+		"projects move" => { position < 2 }
+		"alias add" => { position == 1 }
+		_ => { false }
+	}
+}
+
+fn project_names(s: &dyn TimeTracker) -> Result<Vec<String>, Error> {
+	let psrc: &dyn ProjectDataSource = s.conn();
+	let mut out: Vec<String> = projects::fqns(&psrc.list(None)?.into_iter().filter(|p| p.alive).collect::<Vec<_>>()).into_values().collect();
+	out.extend(alias_list(s.conn())?.into_iter().map(|(n, _)| n));
+	Ok(out)
+}
+
+/// The ids of the most recently started timeblocks.
+fn timeblock_ids(s: &dyn TimeTracker) -> Result<Vec<String>, Error> {
+	let tbsrc: &dyn TimeblockDataSource = s.conn();
+	let mut tbs: Vec<_> = tbsrc.search(Some(TimeblockFilter::Alive(true)), None)?;
+	tbs.sort_by_key(|tb| Reverse(tb.start));
+	Ok(tbs.iter().take(RECENT_IDS).map(|tb| format!("{}", tb.ev.eid)).collect())
+}
+
+fn tag_names(s: &dyn TimeTracker) -> Result<Vec<String>, Error> {
+	Ok(tags::list(s.conn(), None)?.into_iter().map(|(t, _)| t).collect())
+}
+
+/// Reads a word as typed on a shell command line, tolerating an unfinished quote.
+fn unquote(word: &str) -> String {
+	match shell::split_words(word) {
+		Ok(w) => { w.join(" ") }
+		Err(_) => { word.chars().filter(|&c| c != '"' && c != '\'').collect() }
+	}
+}
+
+/// Completions for the last of `words`, the command line after `tt` as far as the cursor.
+///
+/// Candidates starting with the word are preferred; failing that, ones containing it.
+pub fn candidates(s: &dyn TimeTracker, words: &[String]) -> Result<Vec<String>, Error> {
+	let (cur, before) = match words.split_last() {
+		Some((cur, before)) => { (unquote(cur), before) }
+		None => { ("".to_string(), &[][..]) }
+	};
+	let prev = before.last().map(|w| w.as_str());
+
+	let mut args = Vec::new();
+	let mut skip = false;
+	for w in before {
+		if skip {
+			skip = false;
+		} else if VALUE_OPTIONS.contains(&w.as_str()) {
+			skip = true;
+		} else if !w.starts_with('-') {
+			args.push(unquote(w));
+		}
+	}
+
+	let mut path = Vec::new();
+	let mut rest = &args[..];
+	let mut subs: Vec<&str> = cli::SUBCOMMANDS.iter().map(|c| c.0).collect();
+	if let Some(&(name, children)) = rest.first().and_then(|w| cli::SUBCOMMANDS.iter().find(|c| c.0 == w)) {
+		path.push(name);
+		rest = &rest[1..];
+		subs = children.to_vec();
+		if let Some(&sub) = rest.first().and_then(|w| children.iter().find(|c| **c == w)) {
+			path.push(sub);
+			rest = &rest[1..];
+			subs = vec![];
+		}
+	}
+	let path = path.join(" ");
+
+	let all: Vec<String> = if prev == Some("--tag") {
+		tag_names(s)?
+	} else if cur.starts_with('-') {
+		vec![]
+	} else if rest.is_empty() && !subs.is_empty() {
+		subs.iter().map(|n| n.to_string()).collect()
+	} else if takes_project(&path, rest.len()) {
+		if !cur.is_empty() && cur.chars().all(|c| c.is_ascii_digit()) { vec![] } else { project_names(s)? }
+	} else {
+		match (path.as_str(), rest.len()) {
+			("history", 0) => { vec!["timeblock".to_string(), "project".to_string()] }
+			("history", 1) if rest[0] == "project" => {
+				let psrc: &dyn ProjectDataSource = s.conn();
+				psrc.list(None)?.iter().map(|p| format!("{}", p.ev.eid)).collect()
+			}
+			("history", 1) => { timeblock_ids(s)? }
+			("config", 0) => { CONFIG.iter().map(|c| c.0.to_string()).collect() }
+			("config", 1) => {
+				CONFIG.iter().filter(|c| c.0 == rest[0]).flat_map(|c| c.2.iter().map(|v| v.to_string())).collect()
+			}
+			("tags add", 0) | ("tags rm", 0) => { timeblock_ids(s)? }
+			("tags add", _) | ("tags rm", _) | ("tags rename", 0) | ("tags merge", _) => { tag_names(s)? }
+			("alias rm", 0) => { alias_list(s.conn())?.into_iter().map(|(n, _)| n).collect() }
+			("completions", 0) => { clap::Shell::variants().iter().map(|v| v.to_string()).collect() }
+			_ => { vec![] }
+		}
+	};
+
+	let lcur = cur.to_lowercase();
+	let mut out: Vec<String> = all.iter().filter(|c| c.to_lowercase().starts_with(lcur.as_str())).cloned().collect();
+	if out.is_empty() {
+		out = all.into_iter().filter(|c| c.to_lowercase().contains(lcur.as_str())).collect();
+	}
+	out.sort();
+	out.dedup();
+	Ok(out)
+}
+
+/// A completion script for `shell` that asks `tt __complete` for candidates.
+pub fn script(shell: &str) -> Option<&'static str> {
+	match shell {
+		"bash" => { Some(BASH) }
+		"zsh" => { Some(ZSH) }
+		"fish" => { Some(FISH) }
+		_ => { None }
+	}
+}
+
+static BASH: &str = r#"_tt() {
+	local IFS=$'\n' cur words cword i
+	_get_comp_words_by_ref -n : cur words cword
+	COMPREPLY=($(tt __complete -- "${words[@]:1:cword}" 2>/dev/null))
+	__ltrim_colon_completions "$cur"
+	for i in "${!COMPREPLY[@]}"; do
+		COMPREPLY[i]=$(printf '%q' "${COMPREPLY[i]}")
+	done
+}
+complete -o default -F _tt tt
+"#;
+
+static ZSH: &str = r#"#compdef tt
+_tt() {
+	local -a candidates
+	candidates=("${(@f)$(tt __complete -- "${(@)words[2,CURRENT]}" 2>/dev/null)}")
+	compadd -U -- "${(@)candidates:#}"
+}
+compdef _tt tt
+"#;
+
+static FISH: &str = r#"complete -c tt -f -a '(tt __complete -- (commandline -opc)[2..-1] (commandline -ct) 2>/dev/null)'
+"#;
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::db;
+	use super::super::{ Offline, ProjectRef, TimeblockRef, Origin };
+	use super::super::chrono::{ DateTime, Utc };
+
+	fn complete(s: &dyn TimeTracker, line: &[&str]) -> Vec<String> {
+		candidates(s, &line.iter().map(|w| w.to_string()).collect::<Vec<_>>()).unwrap()
+	}
+
+	#[test]
+	fn completes_subcommands_projects_tags_and_flag_values() {
+		let conn = db::memory();
+		let s = Offline { conn: &conn };
+		let psrc: &dyn ProjectDataSource = &conn;
+		let tbsrc: &dyn TimeblockDataSource = &conn;
+		let acme = psrc.upsert("Acme".to_string(), "/projects/1".to_string(), None, true).unwrap();
+		psrc.upsert("Beta Corp".to_string(), "/projects/2".to_string(), None, true).unwrap();
+		let start = "2020-01-01T09:00:00Z".parse::<DateTime<Utc>>().unwrap();
+		let tb = tbsrc.upsert(None, None, ProjectRef::EId(acme.ev.eid), start, None, false, "".to_string(), vec!["meeting".to_string(), "review".to_string()], vec![], true, Origin::User).unwrap();
+
+		assert_eq!(complete(&s, &["pro"]), vec!["projects"]);
+		assert_eq!(complete(&s, &["projects", "ar"]), vec!["archive"]);
+		assert_eq!(complete(&s, &["tags", ""]), vec!["add", "list", "merge", "rename", "rm"]);
+
+		assert_eq!(complete(&s, &["punchin", "a"]), vec!["Acme"]);
+		assert_eq!(complete(&s, &["switch", "\"Beta C"]), vec!["Beta Corp"]);
+		assert_eq!(complete(&s, &["punchin", "corp"]), vec!["Beta Corp"]);
+		assert!(complete(&s, &["punchin", "12"]).is_empty());
+
+		assert_eq!(complete(&s, &["log", "--tag", "m"]), vec!["meeting"]);
+		assert_eq!(complete(&s, &["tags", "add", ""]), vec![format!("{}", tb.ev.eid)]);
+		assert_eq!(complete(&s, &["tags", "add", "1", "r"]), vec!["review"]);
+
+		assert_eq!(complete(&s, &["log", "--format", "json", "switch"]), Vec::<String>::new());
+		assert!(complete(&s, &["log", "--"]).is_empty());
+		assert_eq!(complete(&s, &["completions", "fi"]), vec!["fish"]);
+		tbsrc.upsert(Some(TimeblockRef::EId(tb.ev.eid)), None, ProjectRef::EId(acme.ev.eid), start, None, false, "".to_string(), vec![], vec![], false, Origin::User).unwrap();
+		assert!(complete(&s, &["history", "timeblock", ""]).is_empty());
+	}
+}
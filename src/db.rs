@@ -0,0 +1,556 @@
+use std::collections::HashSet;
+use std::path::{ Path, PathBuf };
+
+use rusqlite::{ Connection, DatabaseName };
+
+use super::chrono::{ DateTime, Utc };
+use super::audit;
+use super::{ DbId, Error };
+use super::{ chrono_to_sql, transaction };
+
+/// What each schema version adds, indexed by version; the last one is what this tt expects.
+static MIGRATIONS: [&str; 10] = [
+	"projects, timeblocks and metadata",
+	"undo log",
+	"configuration",
+	"breaks",
+	"project aliases",
+	"local project overrides",
+	"timeblock tags table",
+	"current-version tables and indexes",
+	"sync lock",
+	"version hashes",
+];
+
+/// The schema version this tt works with.
+pub fn latest() -> i32 {
+	MIGRATIONS.len() as i32 - 1
+}
+
+/// The schema version of the database, or -1 for an empty one.
+pub fn version(conn: &Connection) -> Result<i32, Error> {
+	let exists: i64 = conn.query_row("SELECT COUNT(*) FROM sqlite_master WHERE type='table' AND name='metadata'", &[], |row| row.get(0))?;
+	if exists == 0 {
+		return Ok(-1);
+	}
+	let v = conn.prepare("SELECT version FROM metadata")?.query_map(&[], |row| row.get(0))?.next();
+	match v {
+		Some(v) => { Ok(v?) }
+		None => { Err(Error::TTError("The metadata table is empty".to_string())) }
+	}
+}
+
+/// The migrations the database still needs, by version and description.
+pub fn pending(conn: &Connection) -> Result<Vec<(i32, &'static str)>, Error> {
+	let v = version(conn)?;
+	if v > latest() {
+		return Err(Error::TTError(format!("The database has schema version {}, newer than the {} this tt supports; please upgrade tt", v, latest())));
+	}
+	Ok(((v+1)..(latest()+1)).map(|v| (v, MIGRATIONS[v as usize])).collect())
+}
+
+static SQL_0_0: [&str; 2] = ["
+	CREATE TABLE project_entity (
+		id INTEGER PRIMARY KEY
+	);
+","
+	CREATE TABLE project (
+		remote_id TEXT NOT NULL,
+		name TEXT NOT NULL,
+		parent_eid INTEGER DEFAULT NULL REFERENCES project_entity(id),
+		alive BOOLEAN DEFAULT 1,
+
+		eid INTEGER NOT NULL REFERENCES project_entity(id),
+		vid INTEGER NOT NULL,
+		vtime TIMESTAMP NOT NULL,
+
+		UNIQUE (eid, vid)
+	);
+"
+];
+
+static SQL_0_1: [&str; 2] = ["
+	CREATE TABLE timeblock_entity (
+		id INTEGER PRIMARY KEY,
+		last_sync_vid INTEGER DEFAULT NULL,
+		last_sync_time TIMESTAMP DEFAULT NULL
+	);
+","
+	CREATE TABLE timeblock (
+		remote_id TEXT DEFAULT NULL,
+		project_eid INTEGER NOT NULL REFERENCES project_entity(id),
+		start TIMESTAMP NOT NULL,
+		end TIMESTAMP DEFAULT NULL,
+		billable BOOLEAN,
+		notes TEXT NOT NULL DEFAULT '',
+		tags TEXT NOT NULL DEFAULT '',
+		alive BOOLEAN DEFAULT 1,
+		
+		eid INTEGER NOT NULL REFERENCES timeblock_entity(id),
+		vid INTEGER NOT NULL,
+		vtime TIMESTAMP NOT NULL,
+
+		CHECK (remote_id IS NULL OR end IS NOT NULL)
+		UNIQUE (eid, vid),
+		UNIQUE (eid, remote_id)
+	);
+"];
+
+/// The undo log, and whether each timeblock version was made here (`user`) or brought in by a
+/// sync (`sync`).
+static SQL_1_0: [&str; 3] = ["
+	CREATE TABLE undo (
+		id INTEGER PRIMARY KEY,
+		eid INTEGER NOT NULL REFERENCES timeblock_entity(id),
+		undone_vid INTEGER NOT NULL,
+		restore_vid INTEGER NOT NULL,
+		redo_vid INTEGER DEFAULT NULL,
+		vtime TIMESTAMP NOT NULL
+	);
+",
+	"ALTER TABLE timeblock ADD COLUMN origin TEXT NOT NULL DEFAULT 'user'",
+	"UPDATE timeblock SET origin='sync' WHERE remote_id IS NOT NULL",
+];
+
+static SQL_2_0: [&str; 1] = ["
+	CREATE TABLE config (
+		key TEXT PRIMARY KEY,
+		value TEXT NOT NULL
+	);
+"];
+
+/// The breaks of each timeblock version.
+static SQL_3_0: [&str; 1] = [
+	"ALTER TABLE timeblock ADD COLUMN pauses TEXT NOT NULL DEFAULT ''",
+];
+
+static SQL_4_0: [&str; 1] = ["
+	CREATE TABLE alias (
+		name TEXT PRIMARY KEY,
+		project_eid INTEGER NOT NULL REFERENCES project_entity(id)
+	);
+"];
+
+static SQL_5_0: [&str; 1] = ["
+	CREATE TABLE project_override (
+		project_eid INTEGER PRIMARY KEY REFERENCES project_entity(id),
+		name TEXT DEFAULT NULL,
+		parent_eid INTEGER DEFAULT NULL REFERENCES project_entity(id),
+		reparented BOOLEAN NOT NULL DEFAULT 0,
+		alive BOOLEAN DEFAULT NULL
+	);
+"];
+
+/// The tags of each timeblock version; `timeblock.tags` keeps the same tags joined by newlines.
+static SQL_6_0: [&str; 2] = ["
+	CREATE TABLE timeblock_tag (
+		timeblock_eid INTEGER NOT NULL REFERENCES timeblock_entity(id),
+		timeblock_vid INTEGER NOT NULL,
+		tag TEXT NOT NULL,
+		PRIMARY KEY (timeblock_eid, timeblock_vid, tag)
+	);
+","
+	CREATE INDEX timeblock_tag_tag ON timeblock_tag (tag);
+"];
+
+/// The latest version of each project and timeblock, kept up to date by `upsert` so current
+/// state can be read without scanning every version.
+static SQL_7_0: [&str; 8] = ["
+	CREATE TABLE project_current (
+		eid INTEGER PRIMARY KEY REFERENCES project_entity(id),
+		vid INTEGER NOT NULL
+	);
+","
+	CREATE TABLE timeblock_current (
+		eid INTEGER PRIMARY KEY REFERENCES timeblock_entity(id),
+		vid INTEGER NOT NULL
+	);
+","
+	CREATE INDEX project_remote_id ON project (remote_id);
+","
+	CREATE INDEX project_eid_vtime ON project (eid, vtime);
+","
+	CREATE INDEX timeblock_remote_id ON timeblock (remote_id);
+","
+	CREATE INDEX timeblock_project_eid ON timeblock (project_eid);
+","
+	CREATE INDEX timeblock_start ON timeblock (start);
+","
+	CREATE INDEX timeblock_eid_vtime ON timeblock (eid, vtime);
+"];
+
+/// Who holds the sync lock, so only one `down` or `up` runs at a time.
+static SQL_8_0: [&str; 1] = ["
+	CREATE TABLE sync_lock (
+		id INTEGER PRIMARY KEY CHECK (id=1),
+		pid INTEGER NOT NULL,
+		since TIMESTAMP NOT NULL
+	);
+"];
+
+static SQL_9_0: [&str; 2] = [
+	"ALTER TABLE project ADD COLUMN hash TEXT DEFAULT NULL",
+	"ALTER TABLE timeblock ADD COLUMN hash TEXT DEFAULT NULL",
+];
+
+fn apply(conn: &Connection, v: i32) -> Result<(), Error> {
+	match v {
+		0 => {
+			conn.execute("
+				CREATE TABLE metadata (
+					version INT NOT NULL DEFAULT 0,
+					teamwork_api_key TEXT,
+					teamwork_base_url TEXT,
+					teamwork_user_id INTEGER,
+					CHECK (
+						(teamwork_api_key IS NULL AND teamwork_base_url IS NULL AND teamwork_user_id IS NULL) OR
+						(teamwork_api_key IS NOT NULL AND teamwork_base_url IS NOT NULL AND teamwork_user_id IS NOT NULL)
+					)
+				);
+			", &[])?;
+			conn.execute("
+				INSERT INTO metadata (version) VALUES (0);
+			", &[])?;
+			conn.execute(SQL_0_0[0], &[])?;
+			conn.execute(SQL_0_0[1], &[])?;
+			conn.execute(SQL_0_1[0], &[])?;
+			conn.execute(SQL_0_1[1], &[])?;
+		}
+		1 => {
+			conn.execute(SQL_1_0[0], &[])?;
+			// Every version of a synced timeblock is taken to come from sync.
+			conn.execute(SQL_1_0[1], &[])?;
+			conn.execute(SQL_1_0[2], &[])?;
+		}
+		2 => {
+			conn.execute(SQL_2_0[0], &[])?;
+		}
+		3 => {
+			conn.execute(SQL_3_0[0], &[])?;
+		}
+		4 => {
+			conn.execute(SQL_4_0[0], &[])?;
+		}
+		5 => {
+			conn.execute(SQL_5_0[0], &[])?;
+		}
+		6 => {
+			conn.execute(SQL_6_0[0], &[])?;
+			conn.execute(SQL_6_0[1], &[])?;
+			let mut stmt = conn.prepare("SELECT eid, vid, tags FROM timeblock WHERE tags != ''")?;
+			let rows = stmt.query_map(&[], |row| {
+				let eid: DbId = row.get(0);
+				let vid: DbId = row.get(1);
+				let tags: String = row.get(2);
+				(eid, vid, tags)
+			})?;
+			let mut insert = conn.prepare("INSERT OR IGNORE INTO timeblock_tag (timeblock_eid, timeblock_vid, tag) VALUES (?, ?, ?)")?;
+			for r in rows {
+				let (eid, vid, tags) = r?;
+				for t in tags.split('\n').map(|t| t.trim()).filter(|t| !t.is_empty()) {
+					insert.execute(&[&eid, &vid, &t])?;
+				}
+			}
+		}
+		7 => {
+			for sql in SQL_7_0.iter() {
+				conn.execute(sql, &[])?;
+			}
+			conn.execute("INSERT INTO project_current (eid, vid) SELECT eid, MAX(vid) FROM project GROUP BY eid", &[])?;
+			conn.execute("INSERT INTO timeblock_current (eid, vid) SELECT eid, MAX(vid) FROM timeblock GROUP BY eid", &[])?;
+		}
+		8 => {
+			conn.execute(SQL_8_0[0], &[])?;
+		}
+		9 => {
+			// Versions written before this carry no hash; the chains start at their first new version.
+			conn.execute(SQL_9_0[0], &[])?;
+			conn.execute(SQL_9_0[1], &[])?;
+		}
+		_ => {
+			return Err(Error::TTError(format!("Unknown schema version {}", v)));
+		}
+	}
+	conn.execute("UPDATE metadata SET version=?", &[&v])?;
+	Ok(())
+}
+
+/// The file the main database of `conn` lives in, if any.
+fn path(conn: &Connection) -> Result<Option<PathBuf>, Error> {
+	let file: String = conn.query_row("PRAGMA database_list", &[], |row| row.get(2))?;
+	Ok(if file.is_empty() { None } else { Some(PathBuf::from(file)) })
+}
+
+/// Copies the database at `path` next to it before migrating from `from`, returning the copy.
+///
+/// The copy goes through SQLite's backup API on a second connection, which can still read while
+/// the caller holds the write lock, and includes whatever is in the write-ahead log.
+fn backup(path: &Path, from: i32) -> Result<PathBuf, Error> {
+	let mut name = path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+	name.push(format!(".v{}.bak", from));
+	let to = path.with_file_name(name);
+	Connection::open(path)?.backup(DatabaseName::Main, &to, None)?;
+	Ok(to)
+}
+
+/// Brings the database up to the latest schema in one transaction, returning the migrations applied.
+///
+/// A database file with data in it is copied first, under the same write lock as the migrations
+/// so no other tt can change it in between. A database with a newer schema than this tt knows is
+/// refused rather than touched.
+pub fn migrate(conn: &Connection) -> Result<Vec<(i32, &'static str)>, Error> {
+	if pending(conn)?.is_empty() {
+		return Ok(vec![]);
+	}
+	transaction(conn, || {
+		// Another tt may have migrated while this one waited for the lock.
+		let todo = pending(conn)?;
+		let from = match todo.first() {
+			Some(&(v, _)) => { v - 1 }
+			None => { return Ok(todo) }
+		};
+		if let (Some(path), true) = (path(conn)?, from >= 0) {
+			let to = backup(&path, from)?;
+			eprintln!("Migrating the database from schema version {} to {}; a copy was saved to {}", from, latest(), to.display());
+		}
+		for &(v, _) in todo.iter() {
+			apply(conn, v)?;
+		}
+		Ok(todo)
+	})
+}
+
+/// Selects the rowid, eid and vid of unhashed versions of `table` written before `?1` that repeat
+/// the version before them in all of `columns`, leaving out current versions and whatever `keep`
+/// rules out.
+fn redundant(table: &str, columns: &[&str], keep: &str) -> String {
+	let same: Vec<String> = columns.iter().map(|c| format!("prev.{0} IS x.{0}", c)).collect();
+	format!("
+		SELECT x.rowid, x.eid, x.vid FROM {0} AS x
+		INNER JOIN {0} AS prev ON prev.eid=x.eid AND prev.vid=(SELECT MAX(vid) FROM {0} WHERE eid=x.eid AND vid < x.vid)
+		WHERE x.vtime < ?1 AND x.hash IS NULL AND {1}
+		AND NOT EXISTS (SELECT 1 FROM {0}_current AS c WHERE c.eid=x.eid AND c.vid=x.vid)
+		{2}
+	", table, same.join(" AND "), keep)
+}
+
+/// Deletes unhashed versions that change nothing from the version before them, returning for each
+/// table how many versions it had and how many were (or, for a dry run, would be) removed.
+///
+/// Only versions written before schema version 9 carry no hash, and hashed versions are never
+/// removed, so the hash chains and earlier audit exports stay as they were. Versions newer than
+/// `before`, current versions, synced timeblock versions and any version the undo log refers to
+/// are kept too, so `--as-of`, `history`, `undo` and `redo` see the same states, and entities whose
+/// hash chain does not verify are left alone.
+pub fn compact(conn: &Connection, before: DateTime<Utc>, dry_run: bool) -> Result<Vec<(&'static str, i64, i64)>, Error> {
+	let tables: [(&'static str, String); 2] = [
+		("project", redundant("project", &["remote_id", "name", "parent_eid", "alive"], "")),
+		("timeblock", redundant("timeblock", &["remote_id", "project_eid", "start", "end", "billable", "notes", "tags", "pauses", "alive"], "
+			AND x.remote_id IS NULL
+			AND NOT EXISTS (SELECT 1 FROM timeblock_entity AS e WHERE e.id=x.eid AND e.last_sync_vid=x.vid)
+			AND NOT EXISTS (SELECT 1 FROM undo AS u WHERE u.eid=x.eid AND x.vid IN (u.undone_vid, u.restore_vid, u.redo_vid))
+		")),
+	];
+	let before = chrono_to_sql(before);
+	let out = transaction(conn, || {
+		let (_, _, breaks) = audit::verify(conn)?;
+		let mut out = Vec::new();
+		for &(table, ref sql) in tables.iter() {
+			let total: i64 = conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), &[], |row| row.get(0))?;
+			let broken: HashSet<DbId> = breaks.iter().filter(|b| b.entity == table).map(|b| b.id).collect();
+			let mut stmt = conn.prepare(sql)?;
+			let mut found: Vec<(i64, DbId, DbId)> = Vec::new();
+			for r in stmt.query_map(&[&before], |row| (row.get(0), row.get(1), row.get(2)))? {
+				let r = r?;
+				if !broken.contains(&r.1) {
+					found.push(r);
+				}
+			}
+			if !dry_run {
+				for &(rowid, _, _) in found.iter() {
+					conn.execute(&format!("DELETE FROM {} WHERE rowid=?", table), &[&rowid])?;
+				}
+			}
+			out.push((table, total, found.len() as i64));
+		}
+		if !dry_run {
+			conn.execute("DELETE FROM timeblock_tag WHERE NOT EXISTS (SELECT 1 FROM timeblock AS tb WHERE tb.eid=timeblock_eid AND tb.vid=timeblock_vid)", &[])?;
+		}
+		Ok(out)
+	})?;
+	if !dry_run && out.iter().any(|x| x.2 > 0) {
+		// Give the freed pages back to the file system; VACUUM cannot run inside a transaction.
+		conn.execute_batch("VACUUM").map_err(|e| Error::TTError(format!("Removed the old versions, but shrinking the database file failed: {}", e)))?;
+	}
+	Ok(out)
+}
+
+/// A fresh in-memory database at the latest schema.
+#[cfg(test)]
+pub fn memory() -> Connection {
+	let conn = Connection::open_in_memory().unwrap();
+	migrate(&conn).unwrap();
+	conn
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	use rusqlite::Connection;
+	use super::*;
+
+	/// A database at schema version `v`.
+	fn at_version(conn: &Connection, v: i32) {
+		for i in 0..(v+1) {
+			apply(conn, i).unwrap();
+		}
+	}
+
+	#[test]
+	fn migrates_an_empty_database_to_the_latest_schema() {
+		let conn = Connection::open_in_memory().unwrap();
+		assert_eq!(version(&conn).unwrap(), -1);
+		assert_eq!(migrate(&conn).unwrap().len(), MIGRATIONS.len());
+		assert_eq!(version(&conn).unwrap(), latest());
+		assert!(pending(&conn).unwrap().is_empty());
+		assert!(migrate(&conn).unwrap().is_empty());
+	}
+
+	#[test]
+	fn refuses_a_newer_schema() {
+		let conn = memory();
+		conn.execute("UPDATE metadata SET version=?", &[&(latest()+1)]).unwrap();
+		assert!(pending(&conn).is_err());
+		assert!(migrate(&conn).is_err());
+		assert_eq!(version(&conn).unwrap(), latest()+1);
+	}
+
+	#[test]
+	fn carries_data_over_from_older_schemas() {
+		let conn = Connection::open_in_memory().unwrap();
+		at_version(&conn, 5);
+		conn.execute_batch("
+			INSERT INTO project_entity (id) VALUES (1);
+			INSERT INTO project (remote_id, name, alive, eid, vid, vtime) VALUES ('/projects/1', 'Acme', 1, 1, 0, '2020-01-01T08:00:00+00:00');
+			INSERT INTO timeblock_entity (id) VALUES (1);
+			INSERT INTO timeblock (project_eid, start, tags, eid, vid, vtime) VALUES (1, '2020-01-01T09:00:00+00:00', '', 1, 0, '2020-01-01T09:00:00+00:00');
+			INSERT INTO timeblock (project_eid, start, tags, eid, vid, vtime) VALUES (1, '2020-01-01T09:00:00+00:00', 'a
+b', 1, 1, '2020-01-01T10:00:00+00:00');
+		").unwrap();
+		let applied: Vec<i32> = migrate(&conn).unwrap().into_iter().map(|(v, _)| v).collect();
+		assert_eq!(applied, (6..(latest()+1)).collect::<Vec<_>>());
+		let tags: Vec<String> = conn.prepare("SELECT tag FROM timeblock_tag WHERE timeblock_eid=1 AND timeblock_vid=1 ORDER BY tag").unwrap()
+			.query_map(&[], |row| row.get(0)).unwrap().map(|t| t.unwrap()).collect();
+		assert_eq!(tags, vec!["a", "b"]);
+		let current: i64 = conn.query_row("SELECT vid FROM timeblock_current WHERE eid=1", &[], |row| row.get(0)).unwrap();
+		assert_eq!(current, 1);
+	}
+
+	#[test]
+	fn backs_up_a_database_file_before_migrating() {
+		let path = ::std::env::temp_dir().join(format!("tt-migrate-{}.sqlite", ::std::process::id()));
+		let copy = path.with_file_name(format!("tt-migrate-{}.sqlite.v8.bak", ::std::process::id()));
+		{
+			let conn = Connection::open(&path).unwrap();
+			conn.execute_batch("PRAGMA journal_mode=WAL").unwrap();
+			at_version(&conn, 8);
+			// Left in the write-ahead log, which the copy must include.
+			conn.execute("INSERT INTO config (key, value) VALUES ('format.duration', 'decimal')", &[]).unwrap();
+			migrate(&conn).unwrap();
+			assert_eq!(version(&conn).unwrap(), latest());
+		}
+		{
+			let old = Connection::open(&copy).unwrap();
+			assert_eq!(version(&old).unwrap(), 8);
+			let value: String = old.query_row("SELECT value FROM config WHERE key='format.duration'", &[], |row| row.get(0)).unwrap();
+			assert_eq!(value, "decimal");
+		}
+		for p in [&path, &copy].iter() {
+			fs::remove_file(p).unwrap();
+		}
+	}
+
+	use super::super::{ ProjectDataSource, ProjectRef, TimeblockDataSource, TimeblockRef, Origin, set_current };
+
+	/// Appends a copy of the latest version of project `eid`, sealed, as a sync before upserts
+	/// skipped no-ops would have.
+	fn repeat_project(conn: &Connection, eid: DbId) -> DbId {
+		let vid: DbId = conn.query_row("SELECT MAX(vid)+1 FROM project WHERE eid=?", &[&eid], |row| row.get(0)).unwrap();
+		conn.execute("INSERT INTO project (remote_id, name, parent_eid, alive, eid, vid, vtime) SELECT remote_id, name, parent_eid, alive, eid, ?, vtime FROM project WHERE eid=? AND vid=?", &[&vid, &eid, &(vid-1)]).unwrap();
+		audit::seal(conn, "project", eid, vid).unwrap();
+		set_current(conn, "project", eid, vid).unwrap();
+		vid
+	}
+
+	fn vids(conn: &Connection, table: &str, eid: DbId) -> Vec<DbId> {
+		let mut stmt = conn.prepare(&format!("SELECT vid FROM {} WHERE eid=? ORDER BY vid", table)).unwrap();
+		let v: Vec<DbId> = stmt.query_map(&[&eid], |row| row.get(0)).unwrap().map(|v| v.unwrap()).collect();
+		v
+	}
+
+	#[test]
+	fn upserts_that_change_nothing_add_no_version() {
+		let conn = memory();
+		let psrc: &dyn ProjectDataSource = &conn;
+		let tbsrc: &dyn TimeblockDataSource = &conn;
+		let p = psrc.upsert("Acme".to_string(), "/projects/1".to_string(), None, true).unwrap();
+		assert_eq!(psrc.upsert("Acme".to_string(), "/projects/1".to_string(), None, true).unwrap().ev.vid, 0);
+		let start = "2020-01-01T09:00:00Z".parse::<DateTime<Utc>>().unwrap();
+		let tb = tbsrc.upsert(None, None, ProjectRef::EId(p.ev.eid), start, None, false, "x".to_string(), vec!["a".to_string()], vec![], true, Origin::User).unwrap();
+		let same = tbsrc.upsert(Some(TimeblockRef::EId(tb.ev.eid)), None, ProjectRef::EId(p.ev.eid), start, None, false, "x".to_string(), vec!["a".to_string()], vec![], true, Origin::User).unwrap();
+		assert_eq!(same.ev.vid, 0);
+		assert_eq!(vids(&conn, "timeblock", tb.ev.eid), vec![0]);
+	}
+
+	/// Drops the hashes of every project version so far, as if written before schema version 9.
+	fn unhash_projects(conn: &Connection) {
+		conn.execute("UPDATE project SET hash=NULL", &[]).unwrap();
+	}
+
+	#[test]
+	fn compaction_removes_repeated_unhashed_versions_only() {
+		let conn = memory();
+		let psrc: &dyn ProjectDataSource = &conn;
+		let p = psrc.upsert("Acme".to_string(), "/projects/1".to_string(), None, true).unwrap();
+		repeat_project(&conn, p.ev.eid);
+		repeat_project(&conn, p.ev.eid);
+		unhash_projects(&conn);
+		psrc.upsert("Acme Corp".to_string(), "/projects/1".to_string(), None, true).unwrap();
+		repeat_project(&conn, p.ev.eid);
+		psrc.upsert("Acme Inc".to_string(), "/projects/1".to_string(), None, true).unwrap();
+		let hashes = |conn: &Connection| -> Vec<Option<String>> {
+			let mut stmt = conn.prepare("SELECT hash FROM project WHERE hash IS NOT NULL ORDER BY vid").unwrap();
+			let h: Vec<Option<String>> = stmt.query_map(&[], |row| row.get(0)).unwrap().map(|h| h.unwrap()).collect();
+			h
+		};
+		let sealed = hashes(&conn);
+		let later = Utc::now() + ::time::Duration::seconds(1);
+
+		assert_eq!(compact(&conn, later, true).unwrap()[0], ("project", 6, 2));
+		assert_eq!(vids(&conn, "project", p.ev.eid), vec![0, 1, 2, 3, 4, 5]);
+
+		assert_eq!(compact(&conn, later, false).unwrap()[0], ("project", 6, 2));
+		assert_eq!(vids(&conn, "project", p.ev.eid), vec![0, 3, 4, 5]);
+		assert_eq!(hashes(&conn), sealed);
+		let (checked, _, breaks) = audit::verify(&conn).unwrap();
+		assert_eq!((checked, breaks.len()), (3, 0));
+		assert_eq!(psrc.get(ProjectRef::EId(p.ev.eid), None).unwrap().unwrap().name, "Acme Inc");
+
+		assert_eq!(compact(&conn, later, false).unwrap()[0], ("project", 4, 0));
+	}
+
+	#[test]
+	fn compaction_keeps_recent_versions_and_broken_chains() {
+		let conn = memory();
+		let psrc: &dyn ProjectDataSource = &conn;
+		let a = psrc.upsert("A".to_string(), "/projects/1".to_string(), None, true).unwrap();
+		repeat_project(&conn, a.ev.eid);
+		unhash_projects(&conn);
+		psrc.upsert("A2".to_string(), "/projects/1".to_string(), None, true).unwrap();
+		assert_eq!(compact(&conn, Utc::now() - ::time::Duration::days(1), false).unwrap()[0].2, 0);
+
+		conn.execute("UPDATE project SET name='Forged' WHERE eid=? AND vid=2", &[&a.ev.eid]).unwrap();
+		assert_eq!(compact(&conn, Utc::now() + ::time::Duration::seconds(1), false).unwrap()[0].2, 0);
+		assert_eq!(vids(&conn, "project", a.ev.eid), vec![0, 1, 2]);
+	}
+}
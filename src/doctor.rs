@@ -0,0 +1,397 @@
+use std::collections::{ HashMap, HashSet };
+
+use rusqlite::Connection;
+
+use super::chrono::{ DateTime, Utc, Local };
+use super::time::Duration;
+use super::serde_json;
+use super::{ Project, ProjectRef, ProjectDataSource, Timeblock, TimeblockRef, TimeblockFilter, TimeblockDataSource, DbId, Error };
+use super::{ close_timeblock, config_get, local_to_utc, set_current, transaction };
+
+/// Open timeblocks started longer ago than this were most likely forgotten.
+const STALE_OPEN_DAYS: i64 = 7;
+
+/// A change that repairs a problem by appending new versions.
+enum Fix {
+	/// End the timeblock at the given time, unless it already ends earlier.
+	End(DbId, DateTime<Utc>),
+	/// Move the project to the top level.
+	Unparent(DbId),
+	/// Point the current-version table of `table` at the given, latest version of the entity.
+	Current(&'static str, DbId, DbId),
+}
+
+impl Fix {
+	fn describe(&self) -> String {
+		match *self {
+			Fix::End(_, t) => { format!("end it at {}", t.with_timezone(&Local).format("%Y-%m-%d %H:%M")) }
+			Fix::Unparent(_) => { "move it to the top level".to_string() }
+			Fix::Current(_, _, _) => { "use the latest version".to_string() }
+		}
+	}
+}
+
+pub struct Problem {
+	check: &'static str,
+	entity: &'static str,
+	id: DbId,
+	problem: String,
+	fix: Option<Fix>,
+}
+
+fn entities_without_first_version(conn: &Connection, table: &'static str, out: &mut Vec<Problem>) -> Result<(), Error> {
+	let mut stmt = conn.prepare(&format!("SELECT e.id FROM {0}_entity AS e WHERE NOT EXISTS (SELECT 1 FROM {0} AS x WHERE x.eid=e.id AND x.vid=0)", table))?;
+	for id in stmt.query_map(&[], |row| row.get(0))? {
+		out.push(Problem { check: "first-version", entity: table, id: id?, problem: "has no version 0".to_string(), fix: None });
+	}
+	Ok(())
+}
+
+/// Entities of `table` not read from their newest version, with that version and the one they
+/// are read from, if any.
+pub fn stale_current(conn: &Connection, table: &str) -> Result<Vec<(DbId, DbId, Option<DbId>)>, Error> {
+	let mut stmt = conn.prepare(&format!("
+		SELECT x.eid, MAX(x.vid), c.vid FROM {0} AS x LEFT JOIN {0}_current AS c ON c.eid=x.eid
+		GROUP BY x.eid HAVING c.vid IS NULL OR c.vid != MAX(x.vid)
+	", table))?;
+	let rows = stmt.query_map(&[], |row| {
+		let eid: DbId = row.get(0);
+		let latest: DbId = row.get(1);
+		let current: Option<DbId> = row.get(2);
+		(eid, latest, current)
+	})?;
+	let mut out = Vec::new();
+	for r in rows {
+		out.push(r?);
+	}
+	Ok(out)
+}
+
+fn stale_current_versions(conn: &Connection, table: &'static str, out: &mut Vec<Problem>) -> Result<(), Error> {
+	for (eid, latest, current) in stale_current(conn, table)? {
+		let problem = match current {
+			Some(v) => { format!("is read as version {} instead of the latest, {}", v, latest) }
+			None => { "is missing from the current versions".to_string() }
+		};
+		out.push(Problem { check: "current-version", entity: table, id: eid, problem, fix: Some(Fix::Current(table, eid, latest)) });
+	}
+	Ok(())
+}
+
+/// Projects whose chain of parents leads back to themselves.
+fn project_cycles(projects: &[Project], out: &mut Vec<Problem>) {
+	let by_eid: HashMap<DbId, &Project> = projects.iter().map(|p| (p.ev.eid, p)).collect();
+	let mut reported = HashSet::new();
+	for p in projects {
+		let mut chain = vec![p.ev.eid];
+		let mut cur = p.parent_eid;
+		while let Some(eid) = cur {
+			if let Some(i) = chain.iter().position(|&x| x == eid) {
+				// Report each cycle once, offering to cut it where it was last changed.
+				let cycle = &chain[i..];
+				if cycle.iter().all(|x| reported.insert(*x)) {
+					let cut = *cycle.iter().max_by_key(|x| by_eid.get(x).map(|p| p.ev.vtime)).unwrap_or(&eid);
+					let ids: Vec<String> = cycle.iter().map(|x| x.to_string()).collect();
+					out.push(Problem { check: "parent-cycle", entity: "project", id: cut, problem: format!("is in a cycle of parents: {}", ids.join(" -> ")), fix: Some(Fix::Unparent(cut)) });
+				}
+				break;
+			}
+			chain.push(eid);
+			cur = by_eid.get(&eid).and_then(|x| x.parent_eid);
+		}
+	}
+}
+
+/// Overlapping timeblocks within each group of `tbs`, sorted by start.
+fn overlaps(tbs: &[&Timeblock], now: DateTime<Utc>, out: &mut Vec<Problem>) {
+	let mut latest: Option<&Timeblock> = None;
+	for &tb in tbs {
+		if let Some(prev) = latest {
+			let prev_end = prev.end.unwrap_or(now);
+			if tb.start < prev_end {
+				// Synced timeblocks cannot take new versions, so those are left alone.
+				let fix = if prev.start < tb.start && prev.remote_id.is_none() { Some(Fix::End(prev.ev.eid, tb.start)) } else { None };
+				out.push(Problem { check: "overlap", entity: "timeblock", id: prev.ev.eid, problem: format!("overlaps timeblock {}", tb.ev.eid), fix });
+			}
+			if tb.end.unwrap_or(now) <= prev_end {
+				continue;
+			}
+		}
+		latest = Some(tb);
+	}
+}
+
+/// Looks for inconsistent or suspicious data.
+pub fn check(conn: &Connection) -> Result<Vec<Problem>, Error> {
+	let psrc: &dyn ProjectDataSource = conn;
+	let tbsrc: &dyn TimeblockDataSource = conn;
+	let now = Utc::now();
+	let mut out = Vec::new();
+
+	entities_without_first_version(conn, "project", &mut out)?;
+	entities_without_first_version(conn, "timeblock", &mut out)?;
+	stale_current_versions(conn, "project", &mut out)?;
+	stale_current_versions(conn, "timeblock", &mut out)?;
+
+	// Read the version tables themselves rather than trusting the current-version tables.
+	let projects = psrc.list(Some(now))?;
+	project_cycles(&projects, &mut out);
+	let mut by_remote_id: HashMap<&str, Vec<DbId>> = HashMap::new();
+	for p in projects.iter() {
+		by_remote_id.entry(p.remote_id.as_str()).or_default().push(p.ev.eid);
+	}
+	for (remote_id, eids) in by_remote_id.iter().filter(|x| x.1.len() > 1) {
+		for &eid in eids.iter().skip(1) {
+			out.push(Problem { check: "duplicate-remote-id", entity: "project", id: eid, problem: format!("shares remote id {} with project {}", remote_id, eids[0]), fix: None });
+		}
+	}
+
+	let project_eids: HashSet<DbId> = projects.iter().map(|p| p.ev.eid).collect();
+	let mut tbs = tbsrc.search(Some(TimeblockFilter::Alive(true)), Some(now))?;
+	tbs.sort_by_key(|tb| (tb.start, tb.ev.eid));
+	let mut by_remote_id: HashMap<&str, DbId> = HashMap::new();
+	for tb in tbs.iter() {
+		match tb.project_eid() {
+			Some(eid) if !project_eids.contains(&eid) => {
+				out.push(Problem { check: "missing-project", entity: "timeblock", id: tb.ev.eid, problem: format!("belongs to missing project {}", eid), fix: None });
+			}
+			_ => { }
+		}
+		if tb.end.is_none() && now - tb.start > Duration::days(STALE_OPEN_DAYS) {
+			// End it at the midnight after it started, the most it can plausibly have run.
+			let day = tb.start.with_timezone(&Local).date().naive_local().succ();
+			let end = local_to_utc(day.and_hms(0, 0, 0))?;
+			out.push(Problem { check: "stale-open", entity: "timeblock", id: tb.ev.eid, problem: format!("has been open since {}", tb.start.with_timezone(&Local).format("%Y-%m-%d %H:%M")), fix: Some(Fix::End(tb.ev.eid, end)) });
+		}
+		if let Some(ref remote_id) = tb.remote_id {
+			match by_remote_id.get(remote_id.as_str()) {
+				Some(&first) => {
+					out.push(Problem { check: "duplicate-remote-id", entity: "timeblock", id: tb.ev.eid, problem: format!("shares remote id {} with timeblock {}", remote_id, first), fix: None });
+				}
+				None => { by_remote_id.insert(remote_id.as_str(), tb.ev.eid); }
+			}
+		}
+	}
+
+	// Timers on different projects may legitimately run together unless that is disabled.
+	if config_get(conn, "timers.concurrent")? == "allow" {
+		let mut by_project: HashMap<Option<DbId>, Vec<&Timeblock>> = HashMap::new();
+		for tb in tbs.iter() {
+			by_project.entry(tb.project_eid()).or_default().push(tb);
+		}
+		for group in by_project.values() {
+			overlaps(group, now, &mut out);
+		}
+	} else {
+		overlaps(&tbs.iter().collect::<Vec<_>>(), now, &mut out);
+	}
+
+	out.sort_by(|a, b| (a.check, a.entity, a.id).cmp(&(b.check, b.entity, b.id)));
+	Ok(out)
+}
+
+fn current_timeblock(conn: &Connection, eid: DbId) -> Result<Timeblock, Error> {
+	let tbsrc: &dyn TimeblockDataSource = conn;
+	match tbsrc.get(TimeblockRef::EId(eid), None)? {
+		Some(tb) => { Ok(tb) }
+		None => { Err(Error::NotFound(format!("Failed finding timeblock: {}", eid))) }
+	}
+}
+
+fn apply(conn: &Connection, fix: &Fix) -> Result<(), Error> {
+	let tbsrc: &dyn TimeblockDataSource = conn;
+	let psrc: &dyn ProjectDataSource = conn;
+	transaction(conn, || {
+		match *fix {
+			Fix::End(eid, end) => {
+				// Another fix may already have ended it earlier.
+				let tb = current_timeblock(conn, eid)?;
+				if tb.end.is_none_or(|e| e > end) {
+					close_timeblock(conn, &tb, end)?;
+				}
+			}
+			Fix::Unparent(eid) => {
+				let p = match psrc.get(ProjectRef::EId(eid), None)? {
+					Some(p) => { p }
+					None => { return Err(Error::NotFound(format!("Failed finding project: {}", eid))) }
+				};
+				// Also drop any local override, which would otherwise put the parent back.
+				conn.execute("UPDATE project_override SET parent_eid=NULL, reparented=0 WHERE project_eid=?", &[&eid])?;
+				psrc.upsert(p.name.clone(), p.remote_id.clone(), None, p.alive)?;
+			}
+			Fix::Current(table, eid, vid) => {
+				set_current(conn, table, eid, vid)?;
+			}
+		}
+		Ok(())
+	})
+}
+
+/// Reports `problems`, repairing the ones that can be when `fix` is set, and returns the rows and
+/// the number of problems left.
+pub fn report(conn: &Connection, problems: &[Problem], fix: bool) -> (serde_json::Value, usize) {
+	let mut left = 0;
+	let rows: Vec<serde_json::Value> = problems.iter().map(|p| {
+		let remedy = match p.fix {
+			Some(ref f) if fix => {
+				match apply(conn, f) {
+					Ok(()) => { format!("fixed: {}", f.describe()) }
+					Err(e) => {
+						left += 1;
+						format!("failed to {}: {}", f.describe(), e)
+					}
+				}
+			}
+			Some(ref f) => {
+				left += 1;
+				format!("can {}", f.describe())
+			}
+			None => {
+				left += 1;
+				"".to_string()
+			}
+		};
+		json!({"check": p.check, "entity": p.entity, "id": p.id, "problem": p.problem, "fix": remedy})
+	}).collect();
+	(json!(rows), left)
+}
+
+/// Whether any of `problems` has a safe fix.
+pub fn fixable(problems: &[Problem]) -> bool {
+	problems.iter().any(|p| p.fix.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::{ cli, db, dispatch, Offline, Origin };
+
+	fn at(s: &str) -> DateTime<Utc> {
+		s.parse::<DateTime<Utc>>().unwrap()
+	}
+
+	fn found(conn: &Connection) -> Vec<(&'static str, &'static str, DbId)> {
+		check(conn).unwrap().iter().map(|p| (p.check, p.entity, p.id)).collect()
+	}
+
+	fn project(conn: &Connection, name: &str, remote_id: &str, parent: Option<DbId>) -> DbId {
+		let psrc: &dyn ProjectDataSource = conn;
+		psrc.upsert(name.to_string(), remote_id.to_string(), parent, true).unwrap().ev.eid
+	}
+
+	fn timeblock(conn: &Connection, remote_id: Option<&str>, project: DbId, start: &str, end: Option<&str>) -> DbId {
+		let tbsrc: &dyn TimeblockDataSource = conn;
+		tbsrc.upsert(None, remote_id.map(|r| r.to_string()), ProjectRef::EId(project), at(start), end.map(at), false, "".to_string(), vec![], vec![], true, Origin::User).unwrap().ev.eid
+	}
+
+	fn end_of(conn: &Connection, eid: DbId) -> Option<DateTime<Utc>> {
+		current_timeblock(conn, eid).unwrap().end
+	}
+
+	#[test]
+	fn cuts_parent_cycles_at_the_last_change() {
+		let conn = db::memory();
+		let a = project(&conn, "A", "/projects/1", None);
+		let b = project(&conn, "B", "/projects/2", Some(a));
+		project(&conn, "A", "/projects/1", Some(b));
+		assert_eq!(found(&conn), vec![("parent-cycle", "project", a)]);
+
+		let (_, left) = report(&conn, &check(&conn).unwrap(), true);
+		assert_eq!(left, 0);
+		assert!(found(&conn).is_empty());
+		let psrc: &dyn ProjectDataSource = &conn;
+		assert_eq!(psrc.get(ProjectRef::EId(a), None).unwrap().unwrap().parent_eid, None);
+		assert_eq!(psrc.get(ProjectRef::EId(b), None).unwrap().unwrap().parent_eid, Some(a));
+	}
+
+	#[test]
+	fn reports_duplicate_remote_ids_without_a_fix() {
+		let conn = db::memory();
+		let p = project(&conn, "Acme", "/projects/1", None);
+		let first = timeblock(&conn, Some("7"), p, "2020-01-01T09:00:00Z", Some("2020-01-01T10:00:00Z"));
+		let second = timeblock(&conn, Some("7"), p, "2020-01-02T09:00:00Z", Some("2020-01-02T10:00:00Z"));
+		assert_ne!(first, second);
+		assert_eq!(found(&conn), vec![("duplicate-remote-id", "timeblock", second)]);
+		assert_eq!(report(&conn, &check(&conn).unwrap(), true).1, 1);
+	}
+
+	#[test]
+	fn reports_timeblocks_of_missing_projects_without_a_fix() {
+		let conn = db::memory();
+		let p = project(&conn, "Acme", "/projects/1", None);
+		let tb = timeblock(&conn, None, p, "2020-01-01T09:00:00Z", Some("2020-01-01T10:00:00Z"));
+		conn.execute_batch("PRAGMA foreign_keys=OFF").unwrap();
+		conn.execute("UPDATE timeblock SET project_eid=? WHERE eid=?", &[&(p+100), &tb]).unwrap();
+		assert_eq!(found(&conn), vec![("missing-project", "timeblock", tb)]);
+		assert_eq!(report(&conn, &check(&conn).unwrap(), true).1, 1);
+	}
+
+	#[test]
+	fn ends_stale_open_timeblocks_at_the_next_midnight() {
+		let conn = db::memory();
+		let p = project(&conn, "Acme", "/projects/1", None);
+		let tb = timeblock(&conn, None, p, "2020-01-01T09:00:00Z", None);
+		assert_eq!(found(&conn), vec![("stale-open", "timeblock", tb)]);
+
+		let (_, left) = report(&conn, &check(&conn).unwrap(), true);
+		assert_eq!(left, 0);
+		assert!(found(&conn).is_empty());
+		let day = at("2020-01-01T09:00:00Z").with_timezone(&Local).date().naive_local().succ();
+		assert_eq!(end_of(&conn, tb), Some(local_to_utc(day.and_hms(0, 0, 0)).unwrap()));
+	}
+
+	#[test]
+	fn ends_overlapping_timeblocks_where_the_next_starts() {
+		let conn = db::memory();
+		let p = project(&conn, "Acme", "/projects/1", None);
+		let a = timeblock(&conn, None, p, "2020-01-01T09:00:00Z", Some("2020-01-01T11:00:00Z"));
+		let b = timeblock(&conn, None, p, "2020-01-01T10:00:00Z", Some("2020-01-01T12:00:00Z"));
+		assert_eq!(found(&conn), vec![("overlap", "timeblock", a)]);
+
+		let (_, left) = report(&conn, &check(&conn).unwrap(), true);
+		assert_eq!(left, 0);
+		assert!(found(&conn).is_empty());
+		assert_eq!(end_of(&conn, a), Some(at("2020-01-01T10:00:00Z")));
+		assert_eq!(end_of(&conn, b), Some(at("2020-01-01T12:00:00Z")));
+	}
+
+	#[test]
+	fn passes_once_fix_has_run() {
+		let conn = db::memory();
+		let s = Offline { conn: &conn };
+		let run = |args: &[&str]| dispatch(&cli::build_cli().get_matches_from(args.iter()), &s);
+		let p = project(&conn, "Acme", "/projects/1", None);
+		timeblock(&conn, None, p, "2020-01-01T09:00:00Z", None);
+
+		assert_eq!(run(&["tt", "doctor"]).unwrap_err().exit_code(), 1);
+		run(&["tt", "doctor", "--fix"]).unwrap();
+		run(&["tt", "doctor"]).unwrap();
+	}
+
+	#[test]
+	fn points_stale_current_versions_at_the_latest() {
+		let conn = db::memory();
+		let psrc: &dyn ProjectDataSource = &conn;
+		let p = psrc.upsert("Acme".to_string(), "/projects/1".to_string(), None, true).unwrap();
+		psrc.upsert("Acme Corp".to_string(), "/projects/1".to_string(), None, true).unwrap();
+		conn.execute("UPDATE project_current SET vid=0 WHERE eid=?", &[&p.ev.eid]).unwrap();
+
+		let problems = check(&conn).unwrap();
+		assert_eq!(problems.len(), 1);
+		assert_eq!(problems[0].check, "current-version");
+		let (_, left) = report(&conn, &problems, true);
+		assert_eq!(left, 0);
+		assert!(check(&conn).unwrap().is_empty());
+		assert_eq!(psrc.get(ProjectRef::EId(p.ev.eid), None).unwrap().unwrap().name, "Acme Corp");
+	}
+
+	#[test]
+	fn counts_problems_without_a_fix_as_left() {
+		let conn = db::memory();
+		conn.execute("INSERT INTO project_entity VALUES (NULL)", &[]).unwrap();
+		let problems = check(&conn).unwrap();
+		assert_eq!(problems.len(), 1);
+		assert_eq!(problems[0].check, "first-version");
+		assert_eq!(report(&conn, &problems, true).1, 1);
+	}
+}
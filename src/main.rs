@@ -14,9 +14,28 @@ extern crate serde_json;
 extern crate tokio_core;
 extern crate futures;
 extern crate chrono;
+extern crate termion;
+extern crate libc;
+extern crate sha2;
+extern crate hmac;
 
+mod audit;
+#[cfg(test)]
+mod bench;
 mod cli;
+mod complete;
+mod db;
+mod doctor;
+mod matching;
+mod output;
+mod picker;
+mod projects;
+mod tags;
+mod prompt;
+mod query;
+mod shell;
 mod teamwork;
+mod watch;
 
 use time::{
 	Duration
@@ -24,10 +43,15 @@ use time::{
 use chrono::{
 	DateTime,
 	Utc,
-	ParseError,
+	Local,
+	NaiveDate,
+	NaiveDateTime,
+	TimeZone,
+	Datelike,
 };
 use rusqlite::Connection;
 use rusqlite::types::ToSql;
+use std::sync::atomic::{ AtomicBool, Ordering };
 
 type RemoteId = String;
 type DbId = i64;
@@ -42,6 +66,50 @@ pub fn sql_to_chrono(s: String) -> Result<DateTime<Utc>, Error> {
 	}
 }
 
+/// Parses a user supplied time, either RFC3339 or a local `YYYY-MM-DD[ HH:MM[:SS]]`.
+pub fn parse_time(s: &str) -> Result<DateTime<Utc>, Error> {
+	if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+		return Ok(t.with_timezone(&Utc));
+	}
+	for f in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M"].iter() {
+		if let Ok(t) = NaiveDateTime::parse_from_str(s, f) {
+			return local_to_utc(t);
+		}
+	}
+	match NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+		Ok(d) => { local_to_utc(d.and_hms(0, 0, 0)) }
+		Err(e) => { Err(Error::Usage(format!("Invalid time '{}': {}", s, e))) }
+	}
+}
+/// The bounds of a named period (`today`, `yesterday`, `week`, `month`, `year` or `all`) containing `now`.
+pub fn period_bounds(name: &str, now: DateTime<Utc>) -> Result<(DateTime<Utc>, DateTime<Utc>), Error> {
+	let today = now.with_timezone(&Local).date().naive_local();
+	let (from, to) = match name {
+		"today" => { (today, today.succ()) }
+		"yesterday" => { (today.pred(), today) }
+		"week" => {
+			let monday = today - Duration::days(today.weekday().num_days_from_monday() as i64);
+			(monday, monday + Duration::days(7))
+		}
+		"month" => {
+			let first = NaiveDate::from_ymd(today.year(), today.month(), 1);
+			let next = if today.month() == 12 { NaiveDate::from_ymd(today.year()+1, 1, 1) } else { NaiveDate::from_ymd(today.year(), today.month()+1, 1) };
+			(first, next)
+		}
+		"year" => { (NaiveDate::from_ymd(today.year(), 1, 1), NaiveDate::from_ymd(today.year()+1, 1, 1)) }
+		"all" => { return Ok((Utc.timestamp(0, 0), now)) }
+		_ => { return Err(Error::Usage(format!("Unknown period: {}", name))) }
+	};
+	Ok((local_to_utc(from.and_hms(0, 0, 0))?, local_to_utc(to.and_hms(0, 0, 0))?))
+}
+
+fn local_to_utc(t: NaiveDateTime) -> Result<DateTime<Utc>, Error> {
+	match Local.from_local_datetime(&t).earliest() {
+		Some(t) => { Ok(t.with_timezone(&Utc)) }
+		None => { Err(Error::TTError(format!("Invalid local time: {}", t))) }
+	}
+}
+
 #[derive(Debug, Clone)]
 pub struct EntityVersion {
 	eid: DbId,
@@ -57,6 +125,33 @@ pub struct Project {
 	alive: bool,
 	ev: EntityVersion
 }
+impl Project {
+	/// Whether the project only exists in this database.
+	fn is_local(&self) -> bool {
+		self.remote_id.starts_with(LOCAL_REMOTE_ID)
+	}
+	fn from_row(row: &rusqlite::Row) -> Result<Project, Error> {
+		Ok(Project {
+			remote_id: row.get(0),
+			name: row.get(1),
+			parent_eid: row.get(2),
+			alive: row.get(3),
+			ev: EntityVersion {
+				eid: row.get(4),
+				vid: row.get(5),
+				vtime: sql_to_chrono(row.get(6))?
+			}
+		})
+	}
+	fn fields(&self) -> Vec<(&'static str, serde_json::Value)> {
+		vec![
+			("remote_id", json!(self.remote_id)),
+			("name", json!(self.name)),
+			("parent_eid", json!(self.parent_eid)),
+			("alive", json!(self.alive)),
+		]
+	}
+}
 #[derive(Debug, Clone)]
 pub enum ProjectRef {
 	EV(EntityVersion),
@@ -64,152 +159,132 @@ pub enum ProjectRef {
 	RemoteId(RemoteId),
 	Obj(Project),
 }
-static SQL_0_0: [&'static str; 2] = ["
-	CREATE TABLE project_entity (
-		id INTEGER PRIMARY KEY
-	);
-","
-	CREATE TABLE project (
-		remote_id TEXT NOT NULL,
-		name TEXT NOT NULL,
-		parent_eid INTEGER DEFAULT NULL REFERENCES project_entity(id),
-		alive BOOLEAN DEFAULT 1,
-
-		eid INTEGER NOT NULL REFERENCES project_entity(id),
-		vid INTEGER NOT NULL,
-		vtime TIMESTAMP NOT NULL,
-
-		UNIQUE (eid, vid)
-	);
-"
-];
+/// Remote id prefix of projects created with `tt projects add`.
+static LOCAL_REMOTE_ID: &str = "/local/";
+
+/// A locally overridden name, parent (`Some(None)` for top-level) and liveness.
+type Override = (Option<String>, Option<Option<DbId>>, Option<bool>);
+
+/// The locally overridden name, parent and liveness of a synced project, if any.
+fn project_override(conn: &Connection, eid: DbId) -> Result<Option<Override>, Error> {
+	let mut stmt = conn.prepare("SELECT name, parent_eid, reparented, alive FROM project_override WHERE project_eid=?")?;
+	let x = stmt.query_map(&[&eid], |row| {
+		let name: Option<String> = row.get(0);
+		let parent_eid: Option<DbId> = row.get(1);
+		let reparented: bool = row.get(2);
+		let alive: Option<bool> = row.get(3);
+		(name, if reparented { Some(parent_eid) } else { None }, alive)
+	})?.next();
+	match x {
+		Some(x) => { Ok(Some(x?)) }
+		None => { Ok(None) }
+	}
+}
+
 pub trait ProjectDataSource {
-	fn upsert(&self, name: String, remote_id: RemoteId, parent_eid: Option<DbId>) -> Result<Project, Error>;
-	fn get(&self, proj: ProjectRef, when: Option<DateTime<Utc>>) -> Result<Option<Project>, rusqlite::Error>;
+	fn upsert(&self, name: String, remote_id: RemoteId, parent_eid: Option<DbId>, alive: bool) -> Result<Project, Error>;
+	fn get(&self, proj: ProjectRef, when: Option<DateTime<Utc>>) -> Result<Option<Project>, Error>;
 	fn list(&self, when: Option<DateTime<Utc>>) -> Result<Vec<Project>, Error>;
 	fn parents(&self, proj: ProjectRef, when: Option<DateTime<Utc>>) -> Result<Vec<Project>, Error>;
 	fn fqn(&self, proj: ProjectRef, when: Option<DateTime<Utc>>) -> Result<String, Error>;
+	fn history(&self, proj: ProjectRef) -> Result<Vec<Project>, Error>;
 }
 
 impl ProjectDataSource for rusqlite::Connection {
-	fn upsert(&self, name: String, remote_id: RemoteId, parent_eid: Option<DbId>) -> Result<Project, Error> {
+	fn upsert(&self, name: String, remote_id: RemoteId, parent_eid: Option<DbId>, alive: bool) -> Result<Project, Error> {
 		//println!("ProjectDataSource.upsert(name={}, remote_id={})", name, remote_id);
-		let psrc: &dyn ProjectDataSource = self;
-		match psrc.get(ProjectRef::RemoteId(remote_id.clone()), None)? {
-			Some(p) => {
-				let vtime = Utc::now();
-				let vid = p.ev.vid+1;
-				self.prepare("INSERT INTO project (remote_id, name, parent_eid, eid, vid, vtime) VALUES (?, ?, ?, ?, ?, ?)")?.execute(&[&remote_id, &name, &parent_eid.to_sql()?, &p.ev.eid, &vid, &chrono_to_sql(vtime)])?;
-				Ok(Project {
-					remote_id: remote_id,
-					name: name,
-					parent_eid: parent_eid,
-					alive: true,
-					ev: EntityVersion {
-						eid: p.ev.eid,
-						vid: vid,
-						vtime: vtime
-					}
-				})
-			}
-			_ => {
-				self.prepare("INSERT INTO project_entity VALUES (NULL)")?.execute(&[])?;
-				let eid: DbId = self.last_insert_rowid();
-				let vtime = Utc::now();
-				let vid = 0;
-				self.prepare("INSERT INTO project (remote_id, name, parent_eid, eid, vid, vtime) VALUES (?, ?, ?, ?, ?, ?)")?.execute(&[&remote_id, &name, &parent_eid.to_sql()?, &eid, &vid, &chrono_to_sql(vtime)])?;
-				Ok(Project {
-					remote_id: remote_id,
-					name: name,
-					parent_eid: parent_eid,
-					alive: true,
-					ev: EntityVersion {
-						eid: eid,
-						vid: vid,
-						vtime: vtime
+		transaction(self, || {
+			let psrc: &dyn ProjectDataSource = self;
+			match psrc.get(ProjectRef::RemoteId(remote_id.clone()), None)? {
+				Some(p) => {
+					// Local edits of a synced project win over whatever the remote says.
+					let (name, parent_eid, alive) = match project_override(self, p.ev.eid)? {
+						Some((oname, oparent_eid, oalive)) => {
+							(oname.unwrap_or(name), oparent_eid.unwrap_or(parent_eid), oalive.unwrap_or(alive))
+						}
+						None => { (name, parent_eid, alive) }
+					};
+					// Sync re-sends every project, so most calls change nothing.
+					if p.name == name && p.parent_eid == parent_eid && p.alive == alive {
+						return Ok(p);
 					}
-				})
+					let vtime = Utc::now();
+					let vid = p.ev.vid+1;
+					self.prepare("INSERT INTO project (remote_id, name, parent_eid, alive, eid, vid, vtime) VALUES (?, ?, ?, ?, ?, ?, ?)")?.execute(&[&remote_id, &name, &parent_eid.to_sql()?, &alive, &p.ev.eid, &vid, &chrono_to_sql(vtime)])?;
+					set_current(self, "project", p.ev.eid, vid)?;
+					audit::seal(self, "project", p.ev.eid, vid)?;
+					Ok(Project {
+						remote_id,
+						name,
+						parent_eid,
+						alive,
+						ev: EntityVersion {
+							eid: p.ev.eid,
+							vid,
+							vtime
+						}
+					})
+				}
+				_ => {
+					self.prepare("INSERT INTO project_entity VALUES (NULL)")?.execute(&[])?;
+					let eid: DbId = self.last_insert_rowid();
+					let vtime = Utc::now();
+					let vid = 0;
+					self.prepare("INSERT INTO project (remote_id, name, parent_eid, alive, eid, vid, vtime) VALUES (?, ?, ?, ?, ?, ?, ?)")?.execute(&[&remote_id, &name, &parent_eid.to_sql()?, &alive, &eid, &vid, &chrono_to_sql(vtime)])?;
+					set_current(self, "project", eid, vid)?;
+					audit::seal(self, "project", eid, vid)?;
+					Ok(Project {
+						remote_id,
+						name,
+						parent_eid,
+						alive,
+						ev: EntityVersion {
+							eid,
+							vid,
+							vtime
+						}
+					})
+				}
 			}
-		}
+		})
 	}
 
-	fn get(&self, proj: ProjectRef, when: Option<DateTime<Utc>>) -> Result<Option<Project>, rusqlite::Error> {
-		match proj {
+	fn get(&self, proj: ProjectRef, when: Option<DateTime<Utc>>) -> Result<Option<Project>, Error> {
+		let (column, a) = match proj {
 			ProjectRef::Obj(p) => {
-				Ok(Some(p))
-			}
-			_ => {
-				let mut stmt = match proj {
-					ProjectRef::EV(_) => {
-						self.prepare("SELECT p.* FROM project AS p WHERE p.eid=? AND p.vid IN (SELECT MAX(vid) FROM project AS p2 WHERE p2.eid=p.eid AND p2.vtime <= ?) LIMIT 1")?
-					}
-					ProjectRef::EId(_) => {
-						self.prepare("SELECT p.* FROM project AS p WHERE p.eid=? AND p.vid IN (SELECT MAX(vid) FROM project AS p2 WHERE p2.eid=p.eid AND p2.vtime <= ?) LIMIT 1")?
-					}
-					ProjectRef::RemoteId(_) => {
-						self.prepare("SELECT p.* FROM project AS p WHERE p.remote_id=? AND p.vid IN (SELECT MAX(vid) FROM project AS p2 WHERE p2.eid=p.eid AND p2.vtime <= ?) LIMIT 1")?
-					}
-					ProjectRef::Obj(_) => {
-						panic!("Impossible")
-					}
-				};
-				let a = match proj {
-					ProjectRef::EV(ev) => { format!("{}", ev.eid) }
-					ProjectRef::EId(eid) => { format!("{}", eid) }
-					ProjectRef::RemoteId(remote_id) => { remote_id.clone() }
-					ProjectRef::Obj(_) => { panic!("Impossible") }
-				};
-
-				let t = chrono_to_sql(when.unwrap_or(Utc::now()));
-				let x = stmt.query_map(&[&a, &t], |row| {
-					let y: String = row.get(6);
-					//println!("{}", y);
-					Some(Project {
-						remote_id: row.get(0),
-						name: row.get(1),
-						parent_eid: row.get(2),
-						alive: row.get(3),
-						ev: EntityVersion {
-							eid: row.get(4),
-							vid: row.get(5),
-							vtime: sql_to_chrono(row.get(6)).unwrap()
-						}
-					})
-				})?.next().unwrap_or(Ok(None));
-				x
+				return Ok(Some(p));
 			}
+			ProjectRef::EV(ev) => { ("eid", format!("{}", ev.eid)) }
+			ProjectRef::EId(eid) => { ("eid", format!("{}", eid)) }
+			ProjectRef::RemoteId(remote_id) => { ("remote_id", remote_id.clone()) }
+		};
+		let (from, mut args) = versions("project", "p", when);
+		let mut stmt = self.prepare(&format!("SELECT p.* FROM {} WHERE p.{}=? LIMIT 1", from, column))?;
+		args.push(a);
+		let x = stmt.query_map(&args.iter().map(|x| x as &dyn ToSql).collect::<Vec<_>>(), Project::from_row)?.next();
+		match x {
+			Some(p) => { Ok(Some(p??)) }
+			None => { Ok(None) }
 		}
 	}
 	fn list(&self, when: Option<DateTime<Utc>>) -> Result<Vec<Project>, Error> {
-		let mut stmt = self.prepare("SELECT p.* FROM project AS p WHERE p.vid IN (SELECT MAX(vid) FROM project AS p2 WHERE p2.eid=p.eid AND p2.vtime <= ?) ORDER BY eid")?;
-		let t = match when {
-			Some(t) => { t }
-			_ => { Utc::now() }
-		};
+		let (from, args) = versions("project", "p", when);
+		let mut stmt = self.prepare(&format!("SELECT p.* FROM {} ORDER BY p.eid", from))?;
 
-		let out = stmt.query_map(&[&chrono_to_sql(t)], |row| {
-			Project {
-				remote_id: row.get(0),
-				name: row.get(1),
-				parent_eid: row.get(2),
-				alive: row.get(3),
-				ev: EntityVersion {
-					eid: row.get(4),
-					vid: row.get(5),
-					vtime: sql_to_chrono(row.get(6)).unwrap()
-				}
-			}
-		})?.map(|x| x.unwrap()).collect();
+		let mut out = Vec::new();
+		for p in stmt.query_map(&args.iter().map(|x| x as &dyn ToSql).collect::<Vec<_>>(), Project::from_row)? {
+			out.push(p??);
+		}
 		Ok(out)
 	}
+	/// The project and its ancestors, top level first; a cycle in `parent_eid` ends the chain.
 	fn parents(&self, proj: ProjectRef, when: Option<DateTime<Utc>>) -> Result<Vec<Project>, Error> {
 		let psrc: &dyn ProjectDataSource = self;
 		let mut cur = psrc.get(proj, when)?;
-		let mut output = Vec::new();
-		while cur.is_some() {
-			let p = cur.unwrap();
+		let mut output: Vec<Project> = Vec::new();
+		while let Some(p) = cur {
 			match p.parent_eid {
-				Some(eid) => {
+				Some(eid) if !output.iter().any(|x| x.ev.eid == eid) && eid != p.ev.eid => {
 					cur = psrc.get(ProjectRef::EId(eid), when)?
 				}
 				_ => {
@@ -222,10 +297,45 @@ impl ProjectDataSource for rusqlite::Connection {
 		Ok(output)
 	}
 	fn fqn(&self, p: ProjectRef, when: Option<DateTime<Utc>>) -> Result<String, Error> {
-		let parents = self.parents(p, None)?;
+		let parents = self.parents(p, when)?;
 		let names: Vec<String> = parents.iter().map(|x| str::replace(x.name.as_str(), "/", "\\/").clone()).collect();
 		Ok(names.join("/"))
 	}
+	fn history(&self, proj: ProjectRef) -> Result<Vec<Project>, Error> {
+		let psrc: &dyn ProjectDataSource = self;
+		let eid = match psrc.get(proj.clone(), None)? {
+			Some(p) => { p.ev.eid }
+			None => { return Err(Error::NotFound(format!("Failed finding project: {:?}", proj))) }
+		};
+		let mut stmt = self.prepare("SELECT p.* FROM project AS p WHERE p.eid=? ORDER BY p.vid")?;
+		let out = stmt.query_map(&[&eid], Project::from_row)?;
+		let mut v = Vec::new();
+		for p in out {
+			v.push(p??);
+		}
+		Ok(v)
+	}
+}
+
+/// The start and, once resumed, the end of a break.
+type Pause = (DateTime<Utc>, Option<DateTime<Utc>>);
+
+/// Breaks as stored with a timeblock version: one `start/end` per line, with no end while running.
+fn pauses_to_sql(pauses: &[Pause]) -> String {
+	pauses.iter().map(|&(s, e)| format!("{}/{}", chrono_to_sql(s), e.map(chrono_to_sql).unwrap_or_default())).collect::<Vec<_>>().join("\n")
+}
+fn sql_to_pauses(s: String) -> Result<Vec<Pause>, Error> {
+	let mut out = Vec::new();
+	for line in s.split('\n').filter(|l| !l.is_empty()) {
+		let mut parts = line.splitn(2, '/');
+		let start = sql_to_chrono(parts.next().unwrap_or("").to_string())?;
+		let end = match parts.next() {
+			Some(e) if !e.is_empty() => { Some(sql_to_chrono(e.to_string())?) }
+			_ => { None }
+		};
+		out.push((start, end));
+	}
+	Ok(out)
 }
 
 #[derive(Debug)]
@@ -237,102 +347,218 @@ pub struct Timeblock {
 	billable: bool,
 	notes: String,
 	tags: Vec<String>,
+	/// The breaks taken, in order; the last one may still be running.
+	pauses: Vec<Pause>,
 	alive: bool,
 	ev: EntityVersion
 }
+impl Timeblock {
+	fn from_row(row: &rusqlite::Row) -> Result<Timeblock, Error> {
+		let tags: String = row.get(6);
+		Ok(Timeblock {
+			remote_id: row.get(0),
+			project: ProjectRef::EId(row.get(1)),
+			start: sql_to_chrono(row.get(2))?,
+			end: match row.get(3) {
+				Some(s) => { Some(sql_to_chrono(s)?) }
+				None => { None }
+			},
+			billable: row.get(4),
+			notes: row.get(5),
+			tags: tags.split('\n').filter(|t| !t.is_empty()).map(|t| t.to_string()).collect(),
+			pauses: sql_to_pauses(row.get(12))?,
+			alive: row.get(7),
+			ev: EntityVersion {
+				eid: row.get(8),
+				vid: row.get(9),
+				vtime: sql_to_chrono(row.get(10))?
+			}
+		})
+	}
+	fn project_eid(&self) -> Option<DbId> {
+		match self.project {
+			ProjectRef::EV(ref ev) => { Some(ev.eid) }
+			ProjectRef::EId(eid) => { Some(eid) }
+			ProjectRef::Obj(ref p) => { Some(p.ev.eid) }
+			ProjectRef::RemoteId(_) => { None }
+		}
+	}
+	fn fields(&self) -> Vec<(&'static str, serde_json::Value)> {
+		vec![
+			("remote_id", json!(self.remote_id)),
+			("project_eid", json!(self.project_eid())),
+			("start", json!(chrono_to_sql(self.start))),
+			("end", json!(self.end.map(chrono_to_sql))),
+			("billable", json!(self.billable)),
+			("notes", json!(self.notes)),
+			("tags", json!(self.tags)),
+			("pauses", json!(self.pauses.iter().map(|&(s, e)| json!([chrono_to_sql(s), e.map(chrono_to_sql)])).collect::<Vec<_>>())),
+			("alive", json!(self.alive)),
+		]
+	}
+}
+/// Where a timeblock version came from; `undo` only reverts changes made here.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Origin {
+	User,
+	Sync,
+}
+impl Origin {
+	fn as_str(&self) -> &'static str {
+		match *self {
+			Origin::User => { "user" }
+			Origin::Sync => { "sync" }
+		}
+	}
+}
 #[derive(Debug)]
 pub enum TimeblockRef {
 	EV(EntityVersion),
 	EId(DbId),
 	RemoteId(RemoteId),
-	Obj(Timeblock)
-}
-static SQL_0_1: [&'static str; 2] = ["
-	CREATE TABLE timeblock_entity (
-		id INTEGER PRIMARY KEY,
-		last_sync_vid INTEGER DEFAULT NULL,
-		last_sync_time TIMESTAMP DEFAULT NULL
-	);
-","
-	CREATE TABLE timeblock (
-		remote_id TEXT DEFAULT NULL,
-		project_eid INTEGER NOT NULL REFERENCES project_entity(id),
-		start TIMESTAMP NOT NULL,
-		end TIMESTAMP DEFAULT NULL,
-		billable BOOLEAN,
-		notes TEXT NOT NULL DEFAULT '',
-		tags TEXT NOT NULL DEFAULT '',
-		alive BOOLEAN DEFAULT 1,
-		
-		eid INTEGER NOT NULL REFERENCES timeblock_entity(id),
-		vid INTEGER NOT NULL,
-		vtime TIMESTAMP NOT NULL,
-
-		CHECK (remote_id IS NULL OR end IS NOT NULL)
-		UNIQUE (eid, vid),
-		UNIQUE (eid, remote_id)
-	);
-"];
-
-pub enum TimeblockFilter<'a> {
+	Obj(Box<Timeblock>)
+}
+/// Makes `vid` the version of entity `eid` of `table` that reads without an as-of time go to.
+fn set_current(conn: &Connection, table: &'static str, eid: DbId, vid: DbId) -> Result<(), Error> {
+	conn.execute(&format!("INSERT OR REPLACE INTO {}_current (eid, vid) VALUES (?, ?)", table), &[&eid, &vid])?;
+	Ok(())
+}
+
+/// A FROM clause giving the version of each `table` entity current at `when` as `alias`, and its
+/// parameters. Without `when` this reads `<table>_current` instead of scanning every version.
+fn versions(table: &str, alias: &str, when: Option<DateTime<Utc>>) -> (String, Vec<String>) {
+	match when {
+		Some(t) => {
+			(format!("{0} AS {1} INNER JOIN (SELECT eid, MAX(vid) AS vid FROM {0} WHERE vtime <= ? GROUP BY eid) AS {1}_cur ON {1}.eid={1}_cur.eid AND {1}.vid={1}_cur.vid", table, alias), vec![chrono_to_sql(t)])
+		}
+		None => {
+			(format!("{0}_current AS {1}_cur INNER JOIN {0} AS {1} ON {1}.eid={1}_cur.eid AND {1}.vid={1}_cur.vid", table, alias), Vec::new())
+		}
+	}
+}
+
+pub enum TimeblockFilter {
 	Ref(TimeblockRef),
-	And(&'a TimeblockFilter<'a>, &'a TimeblockFilter<'a>),
-	Or(&'a TimeblockFilter<'a>, &'a TimeblockFilter<'a>),
+	And(Box<TimeblockFilter>, Box<TimeblockFilter>),
+	Or(Box<TimeblockFilter>, Box<TimeblockFilter>),
+	Not(Box<TimeblockFilter>),
 	Project(Option<ProjectRef>),
+	/// Any of the given projects; none matches nothing.
+	ProjectIn(Vec<DbId>),
+	/// The project and every project under it.
+	ProjectSubtree(ProjectRef),
 	Open(bool),
+	Alive(bool),
+	Billable(bool),
 	Tag(String),
-	AtTime(DateTime<Utc>),
+	/// Case-insensitive text in the notes.
+	NotesContain(String),
+	/// Timeblocks overlapping the given start (inclusive) and end (exclusive); open ones run until now.
+	Between(DateTime<Utc>, DateTime<Utc>),
 }
-impl<'a> TimeblockFilter<'a> {
-	fn where_clause(&'a self) -> (String, Vec<&dyn rusqlite::types::ToSql>) {
+impl TimeblockFilter {
+	pub fn and(self, other: TimeblockFilter) -> TimeblockFilter {
+		TimeblockFilter::And(Box::new(self), Box::new(other))
+	}
+
+	pub fn or(self, other: TimeblockFilter) -> TimeblockFilter {
+		TimeblockFilter::Or(Box::new(self), Box::new(other))
+	}
+
+	/// The condition on `tb` and its parameters, with projects as they were at `when`.
+	fn where_clause(&self, when: Option<DateTime<Utc>>) -> (String, Vec<Box<dyn ToSql>>) {
 		match *self {
 			TimeblockFilter::Ref(ref tb) => {
-				match tb {
-					&TimeblockRef::EV(ref ev) => {
-						("(tb.eid=?)".to_string(), vec![&ev.eid])
+				match *tb {
+					TimeblockRef::EV(ref ev) => {
+						("(tb.eid=?)".to_string(), vec![Box::new(ev.eid)])
 					}
-					&TimeblockRef::EId(ref eid) => {
-						("(tb.eid=?)".to_string(), vec![eid])
+					TimeblockRef::EId(eid) => {
+						("(tb.eid=?)".to_string(), vec![Box::new(eid)])
 					}
-					&TimeblockRef::RemoteId(ref remote_id) => {
-						("(tb.remote_id=?)".to_string(), vec![remote_id])
+					TimeblockRef::RemoteId(ref remote_id) => {
+						("(tb.remote_id=?)".to_string(), vec![Box::new(remote_id.clone())])
 					}
-					&TimeblockRef::Obj(ref tb) => {
-						("(tb.eid=?)".to_string(), vec![&tb.ev.eid])
+					TimeblockRef::Obj(ref tb) => {
+						("(tb.eid=?)".to_string(), vec![Box::new(tb.ev.eid)])
 					}
 				}
 			}
 			TimeblockFilter::Project(ref p) => {
-				match p {
-					&Some(ProjectRef::EV(ref ev)) => {
-						("(p.eid=?)".to_string(), vec![&ev.eid])
+				match *p {
+					Some(ProjectRef::EV(ref ev)) => {
+						("(tb.project_eid=?)".to_string(), vec![Box::new(ev.eid)])
 					}
-					&Some(ProjectRef::EId(ref eid)) => {
-						("(p.eid=?)".to_string(), vec![eid])
+					Some(ProjectRef::EId(eid)) => {
+						("(tb.project_eid=?)".to_string(), vec![Box::new(eid)])
 					}
-					&Some(ProjectRef::RemoteId(ref remote_id)) => {
-						("(p.eid=?)".to_string(), vec![remote_id])
+					Some(ProjectRef::RemoteId(ref remote_id)) => {
+						("(tb.project_eid IN (SELECT eid FROM project WHERE remote_id=?))".to_string(), vec![Box::new(remote_id.clone())])
 					}
-					&Some(ProjectRef::Obj(ref p)) => {
-						("(p.eid=?)".to_string(), vec![&p.ev.eid])
+					Some(ProjectRef::Obj(ref p)) => {
+						("(tb.project_eid=?)".to_string(), vec![Box::new(p.ev.eid)])
 					}
 					_ => {
 						("1".to_string(), Vec::new())
 					}
 				}
 			}
-			TimeblockFilter::And(a, b) => {
-				let (at, mut aa) = a.where_clause();
-				let (bt, ba) = b.where_clause();
+			TimeblockFilter::ProjectIn(ref eids) => {
+				if eids.is_empty() {
+					return ("0".to_string(), Vec::new());
+				}
+				let marks: Vec<&str> = eids.iter().map(|_| "?").collect();
+				(format!("(tb.project_eid IN ({}))", marks.join(", ")), eids.iter().map(|&e| Box::new(e) as Box<dyn ToSql>).collect())
+			}
+			TimeblockFilter::And(ref a, ref b) => {
+				let (at, mut aa) = a.where_clause(when);
+				let (bt, ba) = b.where_clause(when);
 				aa.extend(ba);
 				(format!("({} AND {})", at, bt), aa)
 			}
-			TimeblockFilter::Or(a, b) => {
-				let (at, mut aa) = a.where_clause();
-				let (bt, ba) = b.where_clause();
+			TimeblockFilter::Or(ref a, ref b) => {
+				let (at, mut aa) = a.where_clause(when);
+				let (bt, ba) = b.where_clause(when);
 				aa.extend(ba);
 				(format!("({} OR {})", at, bt), aa)
 			}
+			TimeblockFilter::Not(ref a) => {
+				let (at, aa) = a.where_clause(when);
+				(format!("(NOT {})", at), aa)
+			}
+			TimeblockFilter::ProjectSubtree(ref p) => {
+				let (from, args) = versions("project", "ps", when);
+				let (root, arg): (&str, Box<dyn ToSql>) = match *p {
+					ProjectRef::EV(ref ev) => { ("?", Box::new(ev.eid)) }
+					ProjectRef::EId(eid) => { ("?", Box::new(eid)) }
+					ProjectRef::RemoteId(ref remote_id) => {
+						("(SELECT p.eid FROM project_current AS pc INNER JOIN project AS p ON p.eid=pc.eid AND p.vid=pc.vid WHERE p.remote_id=?)", Box::new(remote_id.clone()))
+					}
+					ProjectRef::Obj(ref p) => { ("?", Box::new(p.ev.eid)) }
+				};
+				// UNION rather than UNION ALL, so a cycle in parent_eid cannot recurse forever.
+				(format!("(tb.project_eid IN (
+					WITH RECURSIVE sub(eid) AS (
+						SELECT {}
+						UNION
+						SELECT ps.eid FROM {} INNER JOIN sub ON ps.parent_eid=sub.eid
+					)
+					SELECT eid FROM sub
+				))", root, from), std::iter::once(arg).chain(args.into_iter().map(|a| Box::new(a) as Box<dyn ToSql>)).collect())
+			}
+			TimeblockFilter::Alive(alive) => {
+				("(tb.alive=?)".to_string(), vec![Box::new(alive)])
+			}
+			TimeblockFilter::Billable(billable) => {
+				("(IFNULL(tb.billable, 0)=?)".to_string(), vec![Box::new(billable)])
+			}
+			TimeblockFilter::NotesContain(ref text) => {
+				let pattern = format!("%{}%", text.replace("\\", "\\\\").replace("%", "\\%").replace("_", "\\_"));
+				("(tb.notes LIKE ? ESCAPE '\\')".to_string(), vec![Box::new(pattern)])
+			}
+			TimeblockFilter::Between(from, to) => {
+				("(tb.start < ? AND (tb.end IS NULL OR tb.end > ?))".to_string(), vec![Box::new(chrono_to_sql(to)), Box::new(chrono_to_sql(from))])
+			}
 			TimeblockFilter::Open(true) => {
 				("(tb.end IS NULL)".to_string(), Vec::new())
 			}
@@ -340,172 +566,135 @@ impl<'a> TimeblockFilter<'a> {
 				("(tb.end IS NOT NULL)".to_string(), Vec::new())
 			}
 			TimeblockFilter::Tag(ref tag) => {
-				("(tb.tags LIKE ?)".to_string(), Vec::new())
-			}
-			TimeblockFilter::AtTime(ref t) => {
-				let x = chrono_to_sql(*t).to_string();
-				(format!("(tb.vtime <= '{}')", x).to_string(), Vec::new())
+				let (clause, args) = tags::where_clause(tag);
+				(clause, args.into_iter().map(|a| Box::new(a) as Box<dyn ToSql>).collect())
 			}
 		}
 	}
 }
 
 pub trait TimeblockDataSource {
-	fn upsert(&self, tb: Option<TimeblockRef>, remote_id: Option<RemoteId>, project: ProjectRef, start: DateTime<Utc>, end: Option<DateTime<Utc>>, billable: bool, notes: String, tags: Vec<String>, alive: bool) -> Result<Timeblock, Error>;
-	fn get(&self, tb: TimeblockRef, when: Option<DateTime<Utc>>) -> Result<Option<Timeblock>, rusqlite::Error>;
-	fn search(&self, filter: Option<TimeblockFilter>) -> Result<Vec<Timeblock>, rusqlite::Error>;
+	#[allow(clippy::too_many_arguments)]
+	fn upsert(&self, tb: Option<TimeblockRef>, remote_id: Option<RemoteId>, project: ProjectRef, start: DateTime<Utc>, end: Option<DateTime<Utc>>, billable: bool, notes: String, tags: Vec<String>, pauses: Vec<Pause>, alive: bool, origin: Origin) -> Result<Timeblock, Error>;
+	fn get(&self, tb: TimeblockRef, when: Option<DateTime<Utc>>) -> Result<Option<Timeblock>, Error>;
+	/// The timeblocks matching `filter` as they were at `when`, one version of each.
+	fn search(&self, filter: Option<TimeblockFilter>, when: Option<DateTime<Utc>>) -> Result<Vec<Timeblock>, Error>;
 	fn last_sync(&self) -> Result<Option<DateTime<Utc>>, Error>;
+	fn history(&self, tb: TimeblockRef) -> Result<Vec<Timeblock>, Error>;
 }
 
 impl TimeblockDataSource for rusqlite::Connection {
-	fn search(&self, filter: Option<TimeblockFilter>) -> Result<Vec<Timeblock>, rusqlite::Error> {
-		let q = filter.unwrap_or(TimeblockFilter::AtTime(Utc::now()));
-		let (where_clause, args) = q.where_clause();
-		let sql = format!("SELECT tb.* FROM timeblock AS tb INNER JOIN project AS p ON p.eid=tb.project_eid WHERE tb.vid IN (SELECT MAX(vid) FROM timeblock AS tb2 WHERE tb2.eid=tb.eid) AND {}", where_clause);
+	fn search(&self, filter: Option<TimeblockFilter>, when: Option<DateTime<Utc>>) -> Result<Vec<Timeblock>, Error> {
+		let (from, versions_args) = versions("timeblock", "tb", when);
+		let (where_clause, args) = match filter {
+			Some(f) => { f.where_clause(when) }
+			None => { ("1".to_string(), Vec::new()) }
+		};
+		let sql = format!("SELECT tb.* FROM {} WHERE {} ORDER BY tb.eid", from, where_clause);
 
-		let a = args.as_slice();
+		let mut a: Vec<&dyn ToSql> = versions_args.iter().map(|x| x as &dyn ToSql).collect();
+		a.extend(args.iter().map(|x| x.as_ref()));
 		let mut stmt = self.prepare(sql.as_str())?;
-		let out = stmt.query_map(a, |row| {
-			Timeblock {
-				remote_id: row.get(0),
-				project: ProjectRef::EId(row.get(1)),
-				start: sql_to_chrono(row.get(2)).unwrap(),
-				end: match row.get(3) {
-					Some(s) => { Some(sql_to_chrono(s).unwrap()) }
-					None => { None }
-				},
-				billable: row.get(4),
-				notes: row.get(5),
-				tags: vec![row.get(6)],
-				alive: row.get(7),
-				ev: EntityVersion {
-					eid: row.get(8),
-					vid: row.get(9),
-					vtime: sql_to_chrono(row.get(10)).unwrap()
-				}
-			}
-		})?.map(|x| x.unwrap()).collect();
+		let mut out = Vec::new();
+		for tb in stmt.query_map(&a, Timeblock::from_row)? {
+			out.push(tb??);
+		}
 		Ok(out)
 	}
 
-	fn upsert(&self, tb: Option<TimeblockRef>, remote_id: Option<RemoteId>, project: ProjectRef, start: DateTime<Utc>, end: Option<DateTime<Utc>>, billable: bool, notes: String, tags: Vec<String>, alive: bool) -> Result<Timeblock, Error> {
-		let psrc: &dyn ProjectDataSource = self;
-		let tbsrc: &dyn TimeblockDataSource = self;
-		let rproj = psrc.get(project.clone(), None)?;
-		if rproj.is_none() {
-			return Err(Error::TTError(format!("Failed finding project: {:?}", project).to_string()));
-		}
-		let proj = rproj.unwrap();
-		let g = match tb {
-			Some(tb) => { tbsrc.get(tb, None)? }
-			None => None
-		};
-		match g {
-			Some(tb) => {
-				let vtime = Utc::now();
-				let vid = tb.ev.vid+1;
-				let t = tags.join("\n").to_string();
-				let oend = match end {
-					Some(s) => { Some(chrono_to_sql(s)) }
-					None => None
-				};
-				self.prepare("INSERT INTO timeblock (remote_id, project_eid, start, end, billable, notes, tags, alive, eid, vid, vtime) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")?.execute(&[&remote_id, &proj.ev.eid, &chrono_to_sql(start), &oend, &billable, &notes, &t, &alive, &tb.ev.eid, &vid, &chrono_to_sql(vtime)])?;
-				Ok(Timeblock {
-					remote_id: remote_id,
-					project: ProjectRef::EId(proj.ev.eid),
-					start: start,
-					end: end,
-					billable: billable,
-					notes: notes,
-					tags: tags,
-					alive: alive,
-					ev: EntityVersion {
-						eid: tb.ev.eid,
-						vid: vid,
-						vtime: vtime
-					}
-				})
-			}
-			_ => {
-				self.prepare("INSERT INTO timeblock_entity VALUES (NULL, NULL, NULL)")?.execute(&[])?;
-				let eid: DbId = self.last_insert_rowid();
-				let vtime = Utc::now();
-				let vid = 0;
-				let t = tags.join("\n").to_string();
-				let oend = match end {
-					Some(s) => { Some(chrono_to_sql(s)) }
-					None => { None }
-				};
-				self.prepare("INSERT INTO timeblock (remote_id, project_eid, start, end, billable, notes, tags, alive, eid, vid, vtime) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")?.execute(&[&remote_id, &proj.ev.eid, &chrono_to_sql(start), &oend, &billable, &notes, &t, &alive, &eid, &vid, &chrono_to_sql(vtime)])?;
-				Ok(Timeblock {
-					remote_id: remote_id,
-					project: ProjectRef::EId(proj.ev.eid),
-					start: start,
-					end: end,
-					billable: billable,
-					notes: notes,
-					tags: tags,
-					alive: alive,
-					ev: EntityVersion {
-						eid: eid,
-						vid: vid,
-						vtime: vtime
-					}
-				})
+	#[allow(clippy::too_many_arguments)]
+	fn upsert(&self, tb: Option<TimeblockRef>, remote_id: Option<RemoteId>, project: ProjectRef, start: DateTime<Utc>, end: Option<DateTime<Utc>>, billable: bool, notes: String, tags: Vec<String>, pauses: Vec<Pause>, alive: bool, origin: Origin) -> Result<Timeblock, Error> {
+		transaction(self, || {
+			let psrc: &dyn ProjectDataSource = self;
+			let tbsrc: &dyn TimeblockDataSource = self;
+			let proj = match psrc.get(project.clone(), None)? {
+				Some(p) => { p }
+				None => { return Err(Error::NotFound(format!("Failed finding project: {:?}", project))) }
+			};
+			let tags = tags::normalize(tags);
+			let g = match tb {
+				Some(tb) => { tbsrc.get(tb, None)? }
+				None => None
+			};
+			match g {
+				// Nothing changed, so there is no new version to record.
+				Some(tb) if tb.remote_id == remote_id && tb.project_eid() == Some(proj.ev.eid) && tb.start == start && tb.end == end && tb.billable == billable && tb.notes == notes && tb.tags == tags && tb.pauses == pauses && tb.alive == alive => {
+					Ok(tb)
+				}
+				Some(tb) => {
+					let vtime = Utc::now();
+					let vid = tb.ev.vid+1;
+					let t = tags.join("\n").to_string();
+					let oend = end.map(chrono_to_sql);
+					self.prepare("INSERT INTO timeblock (remote_id, project_eid, start, end, billable, notes, tags, pauses, alive, eid, vid, vtime, origin) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")?.execute(&[&remote_id, &proj.ev.eid, &chrono_to_sql(start), &oend, &billable, &notes, &t, &pauses_to_sql(&pauses), &alive, &tb.ev.eid, &vid, &chrono_to_sql(vtime), &origin.as_str()])?;
+					tags::insert(self, tb.ev.eid, vid, &tags)?;
+					set_current(self, "timeblock", tb.ev.eid, vid)?;
+					audit::seal(self, "timeblock", tb.ev.eid, vid)?;
+					Ok(Timeblock {
+						remote_id,
+						project: ProjectRef::EId(proj.ev.eid),
+						start,
+						end,
+						billable,
+						notes,
+						tags,
+						pauses,
+						alive,
+						ev: EntityVersion {
+							eid: tb.ev.eid,
+							vid,
+							vtime
+						}
+					})
+				}
+				_ => {
+					self.prepare("INSERT INTO timeblock_entity VALUES (NULL, NULL, NULL)")?.execute(&[])?;
+					let eid: DbId = self.last_insert_rowid();
+					let vtime = Utc::now();
+					let vid = 0;
+					let t = tags.join("\n").to_string();
+					let oend = end.map(chrono_to_sql);
+					self.prepare("INSERT INTO timeblock (remote_id, project_eid, start, end, billable, notes, tags, pauses, alive, eid, vid, vtime, origin) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")?.execute(&[&remote_id, &proj.ev.eid, &chrono_to_sql(start), &oend, &billable, &notes, &t, &pauses_to_sql(&pauses), &alive, &eid, &vid, &chrono_to_sql(vtime), &origin.as_str()])?;
+					tags::insert(self, eid, vid, &tags)?;
+					set_current(self, "timeblock", eid, vid)?;
+					audit::seal(self, "timeblock", eid, vid)?;
+					Ok(Timeblock {
+						remote_id,
+						project: ProjectRef::EId(proj.ev.eid),
+						start,
+						end,
+						billable,
+						notes,
+						tags,
+						pauses,
+						alive,
+						ev: EntityVersion {
+							eid,
+							vid,
+							vtime
+						}
+					})
+				}
 			}
-		}
+		})
 	}
 
-	fn get(&self, tb: TimeblockRef, when: Option<DateTime<Utc>>) -> Result<Option<Timeblock>, rusqlite::Error> {
-		match tb {
+	fn get(&self, tb: TimeblockRef, when: Option<DateTime<Utc>>) -> Result<Option<Timeblock>, Error> {
+		let (column, a) = match tb {
 			TimeblockRef::Obj(tb) => {
-				Ok(Some(tb))
-			}
-			_ => {
-				let mut stmt = match tb {
-					TimeblockRef::EV(_) => {
-						self.prepare("SELECT p.* FROM timeblock AS p WHERE p.eid=? AND p.vid IN (SELECT MAX(vid) FROM timeblock AS p2 WHERE p2.eid=p.eid AND p2.vtime <= ?) LIMIT 1")?
-					}
-					TimeblockRef::EId(_) => {
-						self.prepare("SELECT p.* FROM timeblock AS p WHERE p.eid=? AND p.vid IN (SELECT MAX(vid) FROM timeblock AS p2 WHERE p2.eid=p.eid AND p2.vtime <= ?) LIMIT 1")?
-					}
-					TimeblockRef::RemoteId(_) => {
-						self.prepare("SELECT p.* FROM timeblock AS p WHERE p.remote_id=? AND p.vid IN (SELECT MAX(vid) FROM timeblock AS p2 WHERE p2.eid=p.eid AND p2.vtime <= ?) LIMIT 1")?
-					}
-					TimeblockRef::Obj(_) => {
-						panic!("Impossible")
-					}
-				};
-				let a = match tb {
-					TimeblockRef::EV(ev) => { format!("{}", ev.eid) }
-					TimeblockRef::EId(eid) => { format!("{}", eid) }
-					TimeblockRef::RemoteId(remote_id) => { remote_id.clone() }
-					TimeblockRef::Obj(_) => { panic!("Impossible") }
-				};
-
-				let t = chrono_to_sql(when.unwrap_or(Utc::now()));
-				let x = stmt.query_map(&[&a, &t], |row| {
-					Some(Timeblock {
-						remote_id: row.get(0),
-						project: ProjectRef::EId(row.get(1)),
-						start: sql_to_chrono(row.get(2)).unwrap(),
-						end: match row.get(3) {
-							Some(s) => { Some(sql_to_chrono(s).unwrap()) }
-							None => { None }
-						},
-						billable: row.get(4),
-						notes: row.get(5),
-						tags: vec![row.get(6)],
-						alive: row.get(7),
-						ev: EntityVersion {
-							eid: row.get(8),
-							vid: row.get(9),
-							vtime: sql_to_chrono(row.get(10)).unwrap()
-						}
-					})
-				})?.next().unwrap_or(Ok(None));
-				x
+				return Ok(Some(*tb));
 			}
+			TimeblockRef::EV(ev) => { ("eid", format!("{}", ev.eid)) }
+			TimeblockRef::EId(eid) => { ("eid", format!("{}", eid)) }
+			TimeblockRef::RemoteId(remote_id) => { ("remote_id", remote_id.clone()) }
+		};
+		let (from, mut args) = versions("timeblock", "tb", when);
+		let mut stmt = self.prepare(&format!("SELECT tb.* FROM {} WHERE tb.{}=? LIMIT 1", from, column))?;
+		args.push(a);
+		let x = stmt.query_map(&args.iter().map(|x| x as &dyn ToSql).collect::<Vec<_>>(), Timeblock::from_row)?.next();
+		match x {
+			Some(tb) => { Ok(Some(tb??)) }
+			None => { Ok(None) }
 		}
 	}
 
@@ -518,6 +707,50 @@ impl TimeblockDataSource for rusqlite::Connection {
 			}
 		})?
 	}
+
+	fn history(&self, tb: TimeblockRef) -> Result<Vec<Timeblock>, Error> {
+		let tbsrc: &dyn TimeblockDataSource = self;
+		let desc = format!("{:?}", tb);
+		let eid = match tbsrc.get(tb, None)? {
+			Some(tb) => { tb.ev.eid }
+			None => { return Err(Error::NotFound(format!("Failed finding timeblock: {}", desc))) }
+		};
+		let mut stmt = self.prepare("SELECT tb.* FROM timeblock AS tb WHERE tb.eid=? ORDER BY tb.vid")?;
+		let out = stmt.query_map(&[&eid], Timeblock::from_row)?;
+		let mut v = Vec::new();
+		for tb in out {
+			v.push(tb??);
+		}
+		Ok(v)
+	}
+}
+
+/// Renders entity versions with, for each one, the fields that changed from the previous version.
+fn diff_versions(versions: Vec<(EntityVersion, Vec<(&'static str, serde_json::Value)>)>) -> serde_json::Value {
+	let mut out = Vec::new();
+	let mut prev: Option<Vec<(&'static str, serde_json::Value)>> = None;
+	for (ev, fields) in versions {
+		let mut changes = serde_json::Map::new();
+		for (i, &(name, ref value)) in fields.iter().enumerate() {
+			match prev {
+				Some(ref p) if p[i].1 == *value => { }
+				Some(ref p) => {
+					changes.insert(name.to_string(), json!({"old": p[i].1, "new": value}));
+				}
+				None => {
+					changes.insert(name.to_string(), json!({"new": value}));
+				}
+			}
+		}
+		out.push(json!({
+			"eid": ev.eid,
+			"vid": ev.vid,
+			"vtime": chrono_to_sql(ev.vtime),
+			"changes": changes
+		}));
+		prev = Some(fields);
+	}
+	serde_json::Value::Array(out)
 }
 
 #[derive(Debug)]
@@ -527,7 +760,47 @@ pub enum Error {
 	HyperError(hyper::Error),
 	SerdeError(serde_json::Error),
 	TTError(String),
-	ChronoError(chrono::ParseError)
+	ChronoError(chrono::ParseError),
+	NotFound(String),
+	Usage(String),
+	SyncError(String),
+	/// No timer is running, for `status --quiet`; reported only through the exit status.
+	Idle
+}
+
+impl Error {
+	/// The process exit status for this error: 2 for usage errors, 3 when something was not
+	/// found, 4 when talking to the remote failed, 5 for database errors and 1 otherwise.
+	pub fn exit_code(&self) -> i32 {
+		match *self {
+			Error::Usage(_) => { 2 }
+			Error::NotFound(_) => { 3 }
+			Error::HyperError(_) | Error::SyncError(_) => { 4 }
+			Error::RusqliteError(_) => { 5 }
+			_ => { 1 }
+		}
+	}
+}
+
+impl std::fmt::Display for Error {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match *self {
+			Error::RusqliteError(ref e) => { write!(f, "database error: {}", e) }
+			Error::IOError(ref e) => { write!(f, "{}", e) }
+			Error::HyperError(ref e) => { write!(f, "sync failed: {}", e) }
+			Error::SerdeError(ref e) => { write!(f, "invalid JSON: {}", e) }
+			Error::ChronoError(ref e) => { write!(f, "invalid time: {}", e) }
+			Error::TTError(ref s) | Error::NotFound(ref s) | Error::Usage(ref s) => { write!(f, "{}", s) }
+			Error::SyncError(ref s) => { write!(f, "sync failed: {}", s) }
+			Error::Idle => { write!(f, "no timer is running") }
+		}
+	}
+}
+
+impl std::error::Error for Error {
+	fn description(&self) -> &str {
+		"time tracker error"
+	}
 }
 
 impl std::convert::From<rusqlite::Error> for Error {
@@ -556,200 +829,1278 @@ impl std::convert::From<chrono::ParseError> for Error {
 	}
 }
 
+/// Loads one specific version of a timeblock.
+fn timeblock_version(conn: &Connection, eid: DbId, vid: DbId) -> Result<Timeblock, Error> {
+	let mut stmt = conn.prepare("SELECT tb.* FROM timeblock AS tb WHERE tb.eid=? AND tb.vid=?")?;
+	let x = stmt.query_map(&[&eid, &vid], Timeblock::from_row)?.next();
+	match x {
+		Some(tb) => { tb? }
+		None => { Err(Error::NotFound(format!("Failed finding timeblock version: {}.{}", eid, vid))) }
+	}
+}
+
+/// Loads the version of a timeblock before `vid`, which need not be `vid-1` once compacted.
+fn previous_timeblock_version(conn: &Connection, eid: DbId, vid: DbId) -> Result<Timeblock, Error> {
+	let mut stmt = conn.prepare("SELECT tb.* FROM timeblock AS tb WHERE tb.eid=? AND tb.vid<? ORDER BY tb.vid DESC LIMIT 1")?;
+	let x = stmt.query_map(&[&eid, &vid], Timeblock::from_row)?.next();
+	match x {
+		Some(tb) => { tb? }
+		None => { Err(Error::NotFound(format!("Failed finding the version before: {}.{}", eid, vid))) }
+	}
+}
+
+/// Appends a new version of the timeblock carrying the state of `from`.
+fn restore_timeblock(conn: &Connection, from: &Timeblock, alive: bool) -> Result<Timeblock, Error> {
+	let t: &dyn TimeblockDataSource = conn;
+	t.upsert(Some(TimeblockRef::EId(from.ev.eid)), from.remote_id.clone(), from.project.clone(), from.start, from.end, from.billable, from.notes.clone(), from.tags.clone(), from.pauses.clone(), alive, Origin::User)
+}
+
+/// Appends a version of the timeblock with its breaks replaced by `pauses`.
+fn set_pauses(conn: &Connection, tb: &Timeblock, pauses: Vec<Pause>) -> Result<Timeblock, Error> {
+	let t: &dyn TimeblockDataSource = conn;
+	t.upsert(Some(TimeblockRef::EId(tb.ev.eid)), tb.remote_id.clone(), tb.project.clone(), tb.start, tb.end, tb.billable, tb.notes.clone(), tb.tags.clone(), pauses, tb.alive, Origin::User)
+}
+
+/// Appends a version of the timeblock ending at `end`, along with any break still running.
+fn close_timeblock(conn: &Connection, tb: &Timeblock, end: DateTime<Utc>) -> Result<Timeblock, Error> {
+	let t: &dyn TimeblockDataSource = conn;
+	let pauses = tb.pauses.iter().map(|&(ps, pe)| (ps, Some(pe.unwrap_or(end)))).collect();
+	t.upsert(Some(TimeblockRef::EId(tb.ev.eid)), tb.remote_id.clone(), tb.project.clone(), tb.start, Some(end), tb.billable, tb.notes.clone(), tb.tags.clone(), pauses, tb.alive, Origin::User)
+}
+
+/// Runs `f` inside a transaction, rolling back everything it wrote if it fails.
+///
+/// The outermost transaction is immediate, taking the write lock before `f` reads anything, so
+/// another tt process cannot change the rows `f` bases its writes on; nested ones are savepoints.
+fn transaction<T, F: FnOnce() -> Result<T, Error>>(conn: &Connection, f: F) -> Result<T, Error> {
+	let (begin, commit, rollback) = if conn.is_autocommit() {
+		("BEGIN IMMEDIATE", "COMMIT", "ROLLBACK")
+	} else {
+		("SAVEPOINT tt", "RELEASE tt", "ROLLBACK TO tt; RELEASE tt")
+	};
+	conn.execute_batch(begin)?;
+	match f() {
+		Ok(x) => {
+			conn.execute_batch(commit)?;
+			Ok(x)
+		}
+		Err(e) => {
+			conn.execute_batch(rollback)?;
+			Err(e)
+		}
+	}
+}
+
+/// How long to wait for another tt process to finish writing.
+const BUSY_TIMEOUT_MS: u64 = 10000;
+
+/// A sync lock older than this was left behind by a process that died, and is taken over.
+const SYNC_LOCK_STALE_MINUTES: i64 = 30;
+
+/// Runs `f` while holding the sync lock, failing if another process holds it.
+fn with_sync_lock<T, F: FnOnce() -> Result<T, Error>>(conn: &Connection, f: F) -> Result<T, Error> {
+	let pid = std::process::id() as i64;
+	transaction(conn, || {
+		let stale = chrono_to_sql(Utc::now() - Duration::minutes(SYNC_LOCK_STALE_MINUTES));
+		conn.execute("DELETE FROM sync_lock WHERE since < ?", &[&stale])?;
+		let holder = conn.prepare("SELECT pid, since FROM sync_lock")?.query_map(&[], |row| {
+			let pid: i64 = row.get(0);
+			let since: String = row.get(1);
+			(pid, since)
+		})?.next();
+		if let Some(h) = holder {
+			let (other, since) = h?;
+			return Err(Error::SyncError(format!("Another sync (process {}) has been running since {}", other, since)));
+		}
+		conn.execute("INSERT INTO sync_lock (id, pid, since) VALUES (1, ?, ?)", &[&pid, &chrono_to_sql(Utc::now())])?;
+		Ok(())
+	})?;
+	let result = f();
+	conn.execute("DELETE FROM sync_lock WHERE pid=?", &[&pid])?;
+	result
+}
+
+/// Whether `tb` is on a break at `now`.
+fn is_paused(tb: &Timeblock, now: DateTime<Utc>) -> bool {
+	tb.pauses.iter().any(|&(ps, pe)| ps <= now && pe.is_none_or(|pe| pe > now))
+}
+
+/// Time worked on a timeblock up to `now`, not counting its breaks.
+fn net_duration(tb: &Timeblock, now: DateTime<Utc>) -> Duration {
+	net_between(tb, tb.start, now)
+}
+
+/// Time worked on a timeblock between `from` and `to`, not counting its breaks.
+fn net_between(tb: &Timeblock, from: DateTime<Utc>, to: DateTime<Utc>) -> Duration {
+	let start = std::cmp::max(tb.start, from);
+	let end = std::cmp::min(tb.end.unwrap_or(to), to);
+	if end <= start {
+		return Duration::zero();
+	}
+	let mut d = end - start;
+	for &(ps, pe) in tb.pauses.iter() {
+		let ps = std::cmp::max(ps, start);
+		let pe = std::cmp::min(pe.unwrap_or(end), end);
+		if pe > ps {
+			d = d - (pe - ps);
+		}
+	}
+	d
+}
+
+fn alias_get(conn: &Connection, name: &str) -> Result<Option<DbId>, Error> {
+	let mut stmt = conn.prepare("SELECT project_eid FROM alias WHERE name=?")?;
+	let x = stmt.query_map(&[&name], |row| { let eid: DbId = row.get(0); eid })?.next();
+	match x {
+		Some(eid) => { Ok(Some(eid?)) }
+		None => { Ok(None) }
+	}
+}
+
+fn alias_list(conn: &Connection) -> Result<Vec<(String, DbId)>, Error> {
+	let mut stmt = conn.prepare("SELECT name, project_eid FROM alias ORDER BY name")?;
+	let rows = stmt.query_map(&[], |row| {
+		let name: String = row.get(0);
+		let eid: DbId = row.get(1);
+		(name, eid)
+	})?;
+	let mut out = Vec::new();
+	for r in rows {
+		out.push(r?);
+	}
+	Ok(out)
+}
+
+/// Known configuration keys, their default and their allowed values (empty for free-form).
+static CONFIG: [(&str, &str, &[&str]); 4] = [
+	("timers.concurrent", "allow", &["allow", "deny", "switch"]),
+	("sync.breaks", "net", &["net", "split"]),
+	("format.duration", "hms", &["hms", "decimal"]),
+	("status.template", "{name} {elapsed}", &[]),
+];
+
+fn config_get(conn: &Connection, key: &str) -> Result<String, Error> {
+	let default = match CONFIG.iter().find(|c| c.0 == key) {
+		Some(c) => { c.1 }
+		None => { return Err(Error::Usage(format!("Unknown config key: {}", key))) }
+	};
+	let mut stmt = conn.prepare("SELECT value FROM config WHERE key=?")?;
+	let x = stmt.query_map(&[&key], |row| { let v: String = row.get(0); v })?.next();
+	match x {
+		Some(v) => { Ok(v?) }
+		None => { Ok(default.to_string()) }
+	}
+}
+
+fn config_set(conn: &Connection, key: &str, value: &str) -> Result<(), Error> {
+	match CONFIG.iter().find(|c| c.0 == key) {
+		Some(c) if c.2.is_empty() || c.2.contains(&value) => { }
+		Some(c) => { return Err(Error::Usage(format!("Invalid value for {}: {} (expected one of {})", key, value, c.2.join(", ")))) }
+		None => { return Err(Error::Usage(format!("Unknown config key: {}", key))) }
+	}
+	conn.execute("INSERT OR REPLACE INTO config (key, value) VALUES (?, ?)", &[&key, &value])?;
+	Ok(())
+}
+
 pub struct Status {
-	open: Vec<(Project, Duration)>
+	open: Vec<(Project, Duration, bool)>
+}
+
+/// A tracker without a sync backend, for commands that only read the local database.
+struct Offline<'a> {
+	conn: &'a Connection
+}
+
+impl<'a> TimeTracker for Offline<'a> {
+	fn conn(&self) -> &Connection {
+		self.conn
+	}
+	fn down(&self) -> Result<(), Error> {
+		Err(Error::TTError("No sync backend configured".to_string()))
+	}
+	fn up(&self) -> Result<(), Error> {
+		Err(Error::TTError("No sync backend configured".to_string()))
+	}
 }
 
 trait TimeTracker {
 	fn conn(&self) -> &Connection;
 	
-	fn status(&self) -> Result<Status, Error> {
+	fn status(&self, when: Option<DateTime<Utc>>) -> Result<Status, Error> {
 		let t: &dyn TimeblockDataSource = self.conn();
 		let p: &dyn ProjectDataSource = self.conn();
-		let s = t.search(Some(TimeblockFilter::Open(true)))?;
-		Ok(Status {
-			open: s.iter().map(|tb| {
-				(p.get(tb.project.clone(), None).unwrap().unwrap(), Utc::now() - tb.start)
-			}).collect()
-		})
+		let now = when.unwrap_or(Utc::now());
+		let mut out = Vec::new();
+		for tb in t.search(Some(TimeblockFilter::Open(true)), when)?.iter().filter(|tb| tb.alive) {
+			let paused = is_paused(tb, now);
+			let proj = match p.get(tb.project.clone(), when)? {
+				Some(proj) => { proj }
+				None => { return Err(Error::NotFound(format!("Failed finding project: {:?}", tb.project))) }
+			};
+			out.push((proj, net_duration(tb, now), paused));
+		}
+		Ok(Status { open: out })
 	}
 
 	fn down(&self) -> Result<(), Error>;
 	fn up(&self) -> Result<(), Error>;
 
-	fn punchin(&self, proj: &Project) -> Result<(), Error> {
+	fn open_timeblocks(&self) -> Result<Vec<Timeblock>, Error> {
 		let t: &dyn TimeblockDataSource = self.conn();
-		t.upsert(None, None, ProjectRef::EId(proj.ev.eid), Utc::now(), None, false, "".to_string(), vec![], true)?;
-		Ok(())
+		Ok(t.search(Some(TimeblockFilter::Open(true)), None)?.into_iter().filter(|tb| tb.alive).collect())
+	}
+
+	fn punchin(&self, proj: &Project, tags: &[String]) -> Result<Timeblock, Error> {
+		let conn = self.conn();
+		transaction(conn, || {
+			let t: &dyn TimeblockDataSource = conn;
+			let now = Utc::now();
+			let open = self.open_timeblocks()?;
+			if !open.is_empty() {
+				match config_get(conn, "timers.concurrent")?.as_str() {
+					"deny" => {
+						return Err(Error::TTError("A timer is already running; use `tt switch` or `tt punchout` first".to_string()));
+					}
+					"switch" => {
+						for tb in open.iter() {
+							close_timeblock(conn, tb, now)?;
+						}
+					}
+					_ => { }
+				}
+			}
+			t.upsert(None, None, ProjectRef::EId(proj.ev.eid), now, None, false, "".to_string(), tags.to_vec(), vec![], true, Origin::User)
+		})
 	}
 	
-	fn punchout(&self, proj: Option<&Project>) -> Result<(), Error> {
-		let t: &dyn TimeblockDataSource = self.conn();
-		let p: &dyn ProjectDataSource = self.conn();
-		let s = t.search(Some(TimeblockFilter::Open(true)))?;
+	/// Closes the open timeblocks of `proj`, or every open timeblock when no project is given.
+	fn punchout(&self, proj: Option<&Project>) -> Result<Vec<Timeblock>, Error> {
+		let conn = self.conn();
+		transaction(conn, || {
+			let now = Utc::now();
+			let s: Vec<Timeblock> = self.open_timeblocks()?.into_iter().filter(|tb| {
+				match proj {
+					Some(proj) => { tb.project_eid() == Some(proj.ev.eid) }
+					None => { true }
+				}
+			}).collect();
+			if s.is_empty() {
+				return Err(Error::NotFound("No running timer".to_string()));
+			}
+			let mut out = Vec::new();
+			for tb in s.iter() {
+				out.push(close_timeblock(conn, tb, now)?);
+			}
+			Ok(out)
+		})
+	}
 
-		let tb = match proj {
-			Some(proj) => {
-				s.iter().filter(|ref tb| p.get(tb.project.clone(), None).unwrap().unwrap().ev.eid == proj.ev.eid).next()
+	/// Closes every open timeblock and opens one on `proj`, all with the same timestamp.
+	fn switch(&self, proj: &Project) -> Result<(Vec<Timeblock>, Timeblock), Error> {
+		let conn = self.conn();
+		transaction(conn, || {
+			let t: &dyn TimeblockDataSource = conn;
+			let now = Utc::now();
+			let mut closed = Vec::new();
+			for tb in self.open_timeblocks()?.iter() {
+				closed.push(close_timeblock(conn, tb, now)?);
 			}
-			_ => {
-				s.iter().next()
+			let opened = t.upsert(None, None, ProjectRef::EId(proj.ev.eid), now, None, false, "".to_string(), vec![], vec![], true, Origin::User)?;
+			Ok((closed, opened))
+		})
+	}
+
+	/// Starts a break on every running timer.
+	fn pause(&self) -> Result<Vec<Timeblock>, Error> {
+		let conn = self.conn();
+		transaction(conn, || {
+			let now = Utc::now();
+			let mut out = Vec::new();
+			for tb in self.open_timeblocks()? {
+				if tb.pauses.iter().any(|p| p.1.is_none()) {
+					continue;
+				}
+				let mut pauses = tb.pauses.clone();
+				pauses.push((now, None));
+				out.push(set_pauses(conn, &tb, pauses)?);
 			}
-		};
+			if out.is_empty() {
+				return Err(Error::NotFound("No running timer to pause".to_string()));
+			}
+			Ok(out)
+		})
+	}
 
-		match tb {
-			Some(ref tb) => {
-				let pr = ProjectRef::EId(p.get(tb.project.clone(), None)?.unwrap().ev.eid);
-				t.upsert(Some(TimeblockRef::EId(tb.ev.eid)), tb.remote_id.clone(), pr, tb.start, Some(Utc::now()), tb.billable, tb.notes.clone(), tb.tags.clone(), tb.alive)?;
-				Ok(())
+	/// Ends the current break on every paused timer.
+	fn resume(&self) -> Result<Vec<Timeblock>, Error> {
+		let conn = self.conn();
+		transaction(conn, || {
+			let now = Utc::now();
+			let mut out = Vec::new();
+			for tb in self.open_timeblocks()? {
+				if !tb.pauses.iter().any(|p| p.1.is_none()) {
+					continue;
+				}
+				let pauses = tb.pauses.iter().map(|&(ps, pe)| (ps, Some(pe.unwrap_or(now)))).collect();
+				out.push(set_pauses(conn, &tb, pauses)?);
 			}
-			_ => {
-				Err(Error::TTError("No timeblock".to_string()))
+			if out.is_empty() {
+				return Err(Error::NotFound("No paused timer to resume".to_string()));
+			}
+			Ok(out)
+		})
+	}
+
+	/// Closed timeblocks that have not been pushed yet, with the net duration to push for each.
+	fn unsynced(&self) -> Result<Vec<(Timeblock, Duration)>, Error> {
+		let t: &dyn TimeblockDataSource = self.conn();
+		let mut out = Vec::new();
+		for tb in t.search(Some(TimeblockFilter::Open(false)), None)? {
+			if !tb.alive || tb.remote_id.is_some() {
+				continue;
+			}
+			if let Some(end) = tb.end {
+				let d = net_duration(&tb, end);
+				out.push((tb, d));
 			}
 		}
+		Ok(out)
+	}
+
+	/// With `sync.breaks` set to `split`, replaces each unpushed timeblock that has breaks with one
+	/// timeblock per stretch of work, so the breaks show upstream; run by `up` before pushing.
+	fn split_breaks(&self) -> Result<(), Error> {
+		let conn = self.conn();
+		let t: &dyn TimeblockDataSource = conn;
+		if config_get(conn, "sync.breaks")? != "split" {
+			return Ok(());
+		}
+		transaction(conn, || {
+			for (tb, _) in self.unsynced()? {
+				let end = match tb.end {
+					Some(end) if !tb.pauses.is_empty() => { end }
+					_ => { continue }
+				};
+				let mut segments = Vec::new();
+				let mut cur = tb.start;
+				for &(ps, pe) in tb.pauses.iter() {
+					let pe = pe.unwrap_or(end);
+					if ps > cur {
+						segments.push((cur, std::cmp::min(ps, end)));
+					}
+					cur = std::cmp::max(cur, pe);
+				}
+				if end > cur {
+					segments.push((cur, end));
+				}
+				for (i, &(s, e)) in segments.iter().enumerate() {
+					let r = if i == 0 { Some(TimeblockRef::EId(tb.ev.eid)) } else { None };
+					t.upsert(r, None, tb.project.clone(), s, Some(e), tb.billable, tb.notes.clone(), tb.tags.clone(), vec![], true, Origin::Sync)?;
+				}
+			}
+			Ok(())
+		})
+	}
+
+	/// Reverts the last `n` timeblock changes made here by appending versions that restore the
+	/// previous state; versions brought in by sync are never undone.
+	fn undo(&self, n: usize, force: bool) -> Result<Vec<Timeblock>, Error> {
+		let conn = self.conn();
+		transaction(conn, || {
+			let mut output = Vec::new();
+			for _ in 0..n {
+				// The latest local version that is neither an undo itself nor already undone.
+				let x = conn.prepare("
+					SELECT tb.eid, tb.vid, e.last_sync_vid FROM timeblock AS tb
+					INNER JOIN timeblock_entity AS e ON e.id=tb.eid
+					WHERE tb.origin='user' AND NOT EXISTS (SELECT 1 FROM undo AS u WHERE u.eid=tb.eid AND (u.undone_vid=tb.vid OR u.restore_vid=tb.vid))
+					ORDER BY tb.vtime DESC, tb.vid DESC LIMIT 1
+				")?.query_map(&[], |row| {
+					let eid: DbId = row.get(0);
+					let vid: DbId = row.get(1);
+					let last_sync_vid: Option<DbId> = row.get(2);
+					(eid, vid, last_sync_vid)
+				})?.next();
+				let (eid, vid, last_sync_vid) = match x {
+					Some(x) => { x? }
+					None => { break }
+				};
+				let cur = timeblock_version(conn, eid, vid)?;
+				let synced = cur.remote_id.is_some() || last_sync_vid.is_some_and(|s| s >= vid);
+				if synced {
+					if !force {
+						return Err(Error::TTError(format!("Timeblock {} version {} has already been synced; use --force to undo it anyway", eid, vid)));
+					}
+					eprintln!("warning: timeblock {} version {} has already been synced upstream", eid, vid);
+				}
+				let restored = if vid == 0 {
+					restore_timeblock(conn, &cur, false)?
+				} else {
+					let prev = previous_timeblock_version(conn, eid, vid)?;
+					let alive = prev.alive;
+					restore_timeblock(conn, &prev, alive)?
+				};
+				conn.execute("INSERT INTO undo (eid, undone_vid, restore_vid, vtime) VALUES (?, ?, ?, ?)", &[&eid, &vid, &restored.ev.vid, &chrono_to_sql(restored.ev.vtime)])?;
+				output.push(restored);
+			}
+			if output.is_empty() {
+				return Err(Error::NotFound("Nothing to undo".to_string()));
+			}
+			Ok(output)
+		})
+	}
+
+	/// Re-applies the last `n` undone changes, provided nothing else changed since.
+	fn redo(&self, n: usize) -> Result<Vec<Timeblock>, Error> {
+		let conn = self.conn();
+		transaction(conn, || {
+			let mut output = Vec::new();
+			for _ in 0..n {
+				// Only undos newer than any ordinary local change can be redone.
+				let x = conn.prepare("
+					SELECT u.id, u.eid, u.undone_vid FROM undo AS u
+					WHERE u.redo_vid IS NULL AND u.vtime >= IFNULL((
+						SELECT MAX(tb.vtime) FROM timeblock AS tb
+						WHERE tb.origin='user' AND NOT EXISTS (SELECT 1 FROM undo AS u2 WHERE u2.eid=tb.eid AND (u2.restore_vid=tb.vid OR u2.redo_vid=tb.vid))
+					), '')
+					ORDER BY u.id DESC LIMIT 1
+				")?.query_map(&[], |row| {
+					let id: DbId = row.get(0);
+					let eid: DbId = row.get(1);
+					let vid: DbId = row.get(2);
+					(id, eid, vid)
+				})?.next();
+				let (id, eid, vid) = match x {
+					Some(x) => { x? }
+					None => { break }
+				};
+				let undone = timeblock_version(conn, eid, vid)?;
+				let alive = undone.alive;
+				let redone = restore_timeblock(conn, &undone, alive)?;
+				conn.execute("UPDATE undo SET redo_vid=? WHERE id=?", &[&redone.ev.vid, &id])?;
+				output.push(redone);
+			}
+			if output.is_empty() {
+				return Err(Error::NotFound("Nothing to redo".to_string()));
+			}
+			Ok(output)
+		})
 	}
 }
 
-fn upgrade(conn: &Connection, vto: i32) -> Result<i32, rusqlite::Error> {
-	let mut vfrom: i32 = -1;
 
-	match conn.prepare("SELECT version FROM metadata") {
-		Ok(mut stmt) => {
-			stmt.query_map(&[], |row| {
-				vfrom = row.get(0)
-			}).unwrap().next();
+/// Whether durations are shown as decimal hours rather than `hh:mm:ss`.
+static DECIMAL_HOURS: AtomicBool = AtomicBool::new(false);
 
-			println!("metadata: found, version={}...", vfrom);
-		}
-		_ => {
-			println!("metadata: not found...");
-		}
+fn format_duration(d: Duration) -> String {
+	if DECIMAL_HOURS.load(Ordering::Relaxed) {
+		return format!("{:.2}", d.num_seconds() as f64 / 3600.0);
 	}
+	let mut sr = d.num_seconds();
+	let h = (sr/60)/60;
+	sr -= h*60*60;
+	let m = (sr)/60;
+	sr -= m*60;
+	format!("{:>02}:{:>02}:{:>02}", h, m, sr)
+}
 
-	for v in vfrom+1..vto+1 {
-		println!("Updating to version {}...", v);
-		match v {
-			0 => {
-				conn.execute("
-					CREATE TABLE metadata (
-						version INT NOT NULL DEFAULT 0,
-						teamwork_api_key TEXT,
-						teamwork_base_url TEXT,
-						teamwork_user_id INTEGER,
-						CHECK (
-							(teamwork_api_key IS NULL AND teamwork_base_url IS NULL AND teamwork_user_id IS NULL) OR
-							(teamwork_api_key IS NOT NULL AND teamwork_base_url IS NOT NULL AND teamwork_user_id IS NOT NULL)
-						)
-					);
-				", &[])?;
-				conn.execute("
-					INSERT INTO metadata (version) VALUES (0);
-				", &[])?;
-				conn.execute(SQL_0_0[0], &[])?;
-				conn.execute(SQL_0_0[1], &[])?;
-				conn.execute(SQL_0_1[0], &[])?;
-				conn.execute(SQL_0_1[1], &[])?;
-			}
-			_ => {
+/// Scores how much each project is used, weighting recent timeblocks more than old ones.
+fn project_usage(conn: &Connection) -> Result<std::collections::HashMap<DbId, f64>, Error> {
+	let now = Utc::now();
+	let mut stmt = conn.prepare("SELECT tb.project_eid, tb.start FROM timeblock AS tb WHERE tb.vid=0")?;
+	let rows = stmt.query_map(&[], |row| {
+		let eid: DbId = row.get(0);
+		let start: String = row.get(1);
+		(eid, start)
+	})?;
+	let mut out = std::collections::HashMap::new();
+	for r in rows {
+		let (eid, start) = r?;
+		let age = (now - sql_to_chrono(start)?).num_days().max(0) as f64;
+		*out.entry(eid).or_insert(0.0) += 1.0 / (1.0 + age);
+	}
+	Ok(out)
+}
+
+/// Live projects with their full names, most used first.
+fn project_choices(s: &dyn TimeTracker) -> Result<Vec<(Project, String)>, Error> {
+	let usage = project_usage(s.conn())?;
+	let all = s.conn().list(None)?;
+	let names = projects::fqns(&all);
+	let mut projects: Vec<(Project, String)> = all.into_iter().filter(|p| p.alive).map(|p| {
+		let fqn = names[&p.ev.eid].clone();
+		(p, fqn)
+	}).collect();
+	projects.sort_by(|a, b| {
+		let ua = usage.get(&a.0.ev.eid).cloned().unwrap_or(0.0);
+		let ub = usage.get(&b.0.ev.eid).cloned().unwrap_or(0.0);
+		ub.partial_cmp(&ua).unwrap_or(std::cmp::Ordering::Equal).then(a.1.cmp(&b.1))
+	});
+	Ok(projects)
+}
+
+/// Lets the user pick a live project interactively, most used first.
+fn choose_project(s: &dyn TimeTracker) -> Result<Project, Error> {
+	let projects = project_choices(s)?;
+	let fqns: Vec<String> = projects.iter().map(|p| p.1.clone()).collect();
+	match picker::pick("project ", &fqns)? {
+		Some(i) => { Ok(projects[i].0.clone()) }
+		None => { Err(Error::TTError("No project chosen".to_string())) }
+	}
+}
+
+static TIMEBLOCK_COLUMNS: [&str; 7] = ["id", "project", "start", "end", "duration", "tags", "notes"];
+
+/// The stable JSON representation of a timeblock, with its duration in seconds up to `when`.
+fn timeblock_json(conn: &Connection, tb: &Timeblock, when: Option<DateTime<Utc>>) -> Result<serde_json::Value, Error> {
+	let psrc: &dyn ProjectDataSource = conn;
+	Ok(json!({
+		"id": tb.ev.eid,
+		"project": psrc.fqn(tb.project.clone(), when)?,
+		"start": chrono_to_sql(tb.start),
+		"end": tb.end.map(chrono_to_sql),
+		"duration": net_duration(tb, when.unwrap_or(Utc::now())).num_seconds(),
+		"billable": tb.billable,
+		"notes": tb.notes,
+		"tags": tb.tags
+	}))
+}
+
+/// Parses a query from the command line, with project names and relative dates as at `when`.
+fn parse_query(conn: &Connection, q: &str, when: Option<DateTime<Utc>>) -> Result<Option<TimeblockFilter>, Error> {
+	let psrc: &dyn ProjectDataSource = conn;
+	let ctx = query::Context { fqns: projects::fqns(&psrc.list(when)?), now: when.unwrap_or(Utc::now()) };
+	query::parse(q, &ctx)
+}
+
+/// Resolves a possibly partial project name typed by the user to a live project.
+fn find_project(s: &dyn TimeTracker, name: &str) -> Result<Project, Error> {
+	resolve_project(s, name, false)
+}
+
+/// Like `find_project`, optionally also matching archived projects.
+fn resolve_project(s: &dyn TimeTracker, name: &str, archived: bool) -> Result<Project, Error> {
+	if let Some(eid) = alias_get(s.conn(), name)? {
+		let psrc: &dyn ProjectDataSource = s.conn();
+		return match psrc.get(ProjectRef::EId(eid), None)? {
+			Some(p) => { Ok(p) }
+			None => { Err(Error::NotFound(format!("Alias '{}' points to a missing project: {}", name, eid))) }
+		};
+	}
+	let all = s.conn().list(None)?;
+	let names = projects::fqns(&all);
+	let projects: Vec<Project> = all.into_iter().filter(|p| archived || p.alive).collect();
+	let fqns: Vec<String> = projects.iter().map(|p| names[&p.ev.eid].clone()).collect();
+	match matching::find(&fqns, name) {
+		matching::Match::One(i) => { Ok(projects[i].clone()) }
+		matching::Match::Ambiguous(v) => {
+			let shown: Vec<String> = v.iter().take(10).map(|&i| format!("  {}", fqns[i])).collect();
+			let more = if v.len() > 10 { format!("\n  ... and {} more", v.len()-10) } else { "".to_string() };
+			Err(Error::Usage(format!("Project '{}' is ambiguous, did you mean:\n{}{}", name, shown.join("\n"), more)))
+		}
+		matching::Match::Fuzzy(v) => {
+			// A fuzzy hit is a guess, so only take it once the user has confirmed it in the picker.
+			if termion::is_tty(&std::io::stdin()) {
+				let candidates: Vec<String> = v.iter().map(|&i| fqns[i].clone()).collect();
+				return match picker::pick(&format!("'{}' ", name), &candidates)? {
+					Some(c) => { Ok(projects[v[c]].clone()) }
+					None => { Err(Error::TTError("No project chosen".to_string())) }
+				};
 			}
+			let shown: Vec<String> = v.iter().take(10).map(|&i| format!("  {}", fqns[i])).collect();
+			Err(Error::NotFound(format!("No project matches '{}', did you mean:\n{}", name, shown.join("\n"))))
+		}
+		matching::Match::Nothing => {
+			Err(Error::NotFound(format!("No project matches '{}'", name)))
 		}
-		
-		conn.execute("
-			UPDATE metadata SET version=?
-		", &[&v])?;
 	}
-
-	Ok(vto)
 }
 
 fn dispatch(m: &clap::ArgMatches, s: &dyn TimeTracker) -> Result<(), Error> {
+	let as_of = match m.value_of("as-of") {
+		Some(t) => { Some(parse_time(t)?) }
+		None => { None }
+	};
+	let fmt = output::Format::parse(m.value_of("format"))?;
+	let duration = match m.value_of("duration") {
+		Some(d) => { d.to_string() }
+		None => { config_get(s.conn(), "format.duration")? }
+	};
+	DECIMAL_HOURS.store(duration == "decimal", Ordering::Relaxed);
 	match m.subcommand() {
 		("completions", Some(m)) => {
-			let mut app = cli::build_cli();
-			app.gen_completions("tt", m.value_of("shell").unwrap().parse::<clap::Shell>().unwrap(), ".");
+			let name = m.value_of("shell").unwrap();
+			match complete::script(name) {
+				Some(script) => { print!("{}", script) }
+				None => {
+					let shell = name.parse::<clap::Shell>().map_err(Error::Usage)?;
+					cli::build_cli().gen_completions_to("tt", shell, &mut std::io::stdout());
+				}
+			}
+		}
+		("__complete", Some(m)) => {
+			let words: Vec<String> = m.values_of("words").map(|v| v.map(|w| w.to_string()).collect()).unwrap_or(vec![]);
+			for c in complete::candidates(s, &words)? {
+				println!("{}", c);
+			}
+		}
+		("audit", Some(audit_matches)) => {
+			match audit_matches.subcommand() {
+				("verify", Some(_)) => {
+					let (checked, unhashed, breaks) = audit::verify(s.conn())?;
+					let ls: Vec<serde_json::Value> = breaks.iter().map(|b| json!({"entity": b.entity, "id": b.id, "vid": b.vid, "problem": b.problem})).collect();
+					output::print(&fmt, &json!(ls), &["entity", "id", "vid", "problem"])?;
+					if unhashed > 0 {
+						eprintln!("{} version(s) from before hashing was added were not checked", unhashed);
+					}
+					if !breaks.is_empty() {
+						return Err(Error::TTError(format!("{} of {} hashed version(s) failed verification", breaks.len(), checked)));
+					}
+					eprintln!("{} hashed version(s) verified", checked);
+				}
+				("export", Some(m)) => {
+					let now = Utc::now();
+					let (from, to) = match (m.value_of("from"), m.value_of("to")) {
+						(Some(from), Some(to)) => { (parse_time(from)?, parse_time(to)?) }
+						_ => { period_bounds(m.value_of("period").unwrap(), as_of.unwrap_or(now))? }
+					};
+					let query = m.values_of("query").map(|w| w.collect::<Vec<_>>().join(" "));
+					let filter = match query {
+						Some(ref q) => { parse_query(s.conn(), q, as_of)? }
+						None => { None }
+					};
+					let report = audit::export(s.conn(), from, to, query.as_deref(), filter, as_of, now)?;
+					output::print_with(&fmt, &report, &report["timeblocks"], &["id", "project", "start", "end", "duration", "billable", "notes", "hash"])?;
+					eprintln!("Digest {}", report["digest"].as_str().unwrap_or(""));
+					if report["intact"] != json!(true) {
+						eprintln!("warning: some timeblocks have no hash or a broken hash chain; see `tt audit verify`");
+					}
+				}
+				_ => { }
+			}
+		}
+		("db", Some(db_matches)) => {
+			if let ("compact", Some(compact_matches)) = db_matches.subcommand() {
+				let days = compact_matches.value_of("keep-days").unwrap().parse::<i64>().map_err(|e| Error::Usage(format!("Invalid number of days: {}", e)))?;
+				let removed = db::compact(s.conn(), Utc::now() - Duration::days(days), compact_matches.is_present("dry-run"))?;
+				let ls: Vec<serde_json::Value> = removed.iter().map(|&(t, n, r)| json!({"table": t, "versions": n, "removed": r})).collect();
+				output::print(&fmt, &json!(ls), &["table", "versions", "removed"])?;
+			}
+		}
+		("doctor", Some(doctor_matches)) => {
+			let problems = doctor::check(s.conn())?;
+			let fix = doctor_matches.is_present("fix");
+			let (rows, left) = doctor::report(s.conn(), &problems, fix);
+			output::print(&fmt, &rows, &["check", "entity", "id", "problem", "fix"])?;
+			if problems.is_empty() {
+				eprintln!("No problems found");
+			} else if !fix && doctor::fixable(&problems) {
+				return Err(Error::TTError(format!("{} problem(s) found; run `tt doctor --fix` to apply the suggested fixes", problems.len())));
+			} else if left > 0 {
+				return Err(Error::TTError(format!("{} problem(s) left to look at by hand", left)));
+			}
 		}
 		("down", Some(_)) => {
-			s.down()?;
-		}
-		("status", Some(_)) => {
-			let d: Vec<Vec<String>> = s.status()?.open.iter().map(|&(ref p, d)| {
-				let mut sr = d.num_seconds();
-				let h = (sr/60)/60;
-				sr -= h*60*60;
-				let m = (sr)/60;
-				sr -= m*60;
-				let ds = format!("{:>02}:{:>02}:{:>02}", h, m, sr);
-				vec![s.conn().fqn(ProjectRef::EId(p.ev.eid), None).unwrap(), ds]
-			}).collect();
-			println!("{}", serde_json::to_string(&json!({"open": d}))?);
+			with_sync_lock(s.conn(), || s.down())?;
+		}
+		("up", Some(_)) => {
+			with_sync_lock(s.conn(), || s.up())?;
+		}
+		("pause", Some(_)) => {
+			let mut ls = Vec::new();
+			for tb in s.pause()?.iter() {
+				ls.push(timeblock_json(s.conn(), tb, None)?);
+			}
+			output::print(&fmt, &json!(ls), &TIMEBLOCK_COLUMNS)?;
+		}
+		("resume", Some(_)) => {
+			let mut ls = Vec::new();
+			for tb in s.resume()?.iter() {
+				ls.push(timeblock_json(s.conn(), tb, None)?);
+			}
+			output::print(&fmt, &json!(ls), &TIMEBLOCK_COLUMNS)?;
+		}
+		("shell", Some(_)) => {
+			shell::run(s)?;
+		}
+		("tags", Some(tags_matches)) => {
+			let values = |m: &clap::ArgMatches, name: &str| -> Vec<String> {
+				m.values_of(name).map(|v| v.map(|t| t.to_string()).collect()).unwrap_or(vec![])
+			};
+			let id = |m: &clap::ArgMatches| -> Result<DbId, Error> {
+				m.value_of("id").unwrap().parse::<DbId>().map_err(|e| Error::Usage(format!("Invalid timeblock id: {}", e)))
+			};
+			let changed = match tags_matches.subcommand() {
+				("add", Some(m)) => { vec![tags::add(s.conn(), id(m)?, &values(m, "tags"))?] }
+				("rm", Some(m)) => { vec![tags::remove(s.conn(), id(m)?, &values(m, "tags"))?] }
+				("rename", Some(m)) => { tags::rename(s.conn(), m.value_of("from").unwrap(), m.value_of("to").unwrap())? }
+				("merge", Some(m)) => { tags::merge(s.conn(), &values(m, "from"), m.value_of("into").unwrap())? }
+				_ => {
+					let ls: Vec<serde_json::Value> = tags::list(s.conn(), as_of)?.into_iter().map(|(t, n)| json!({"tag": t, "timeblocks": n})).collect();
+					output::print(&fmt, &json!(ls), &["tag", "timeblocks"])?;
+					return Ok(());
+				}
+			};
+			let mut ls = Vec::new();
+			for tb in changed.iter() {
+				ls.push(timeblock_json(s.conn(), tb, None)?);
+			}
+			output::print(&fmt, &json!(ls), &TIMEBLOCK_COLUMNS)?;
+		}
+		("watch", Some(_)) => {
+			watch::run(s)?;
+		}
+		("status", Some(status_matches)) => {
+			let status = s.status(as_of)?;
+			if status_matches.is_present("quiet") {
+				if status.open.iter().any(|x| !x.2) {
+					return Ok(());
+				}
+				return Err(Error::Idle);
+			}
+
+			let now = as_of.unwrap_or(Utc::now());
+			let (from, to) = period_bounds("today", now)?;
+			let today = projects::totals(s.conn(), from, to, None, as_of)?.values().fold(Duration::zero(), |a, &b| a + b);
+			let names = projects::fqns(&s.conn().list(as_of)?);
+			let mut d = Vec::new();
+			let mut vars = Vec::new();
+			for &(ref p, dur, paused) in status.open.iter() {
+				let state = if paused { "paused" } else { "running" };
+				let fqn = names.get(&p.ev.eid).cloned().unwrap_or(p.name.clone());
+				d.push(json!({
+					"project": fqn,
+					"duration": dur.num_seconds(),
+					"state": state
+				}));
+				let mut v = std::collections::HashMap::new();
+				v.insert("project", fqn);
+				v.insert("name", p.name.clone());
+				v.insert("elapsed", format_duration(dur));
+				v.insert("state", state.to_string());
+				v.insert("today", format_duration(today));
+				vars.push(v);
+			}
+
+			if status_matches.is_present("prompt") || status_matches.is_present("waybar") || status_matches.is_present("i3blocks") {
+				let template = match status_matches.value_of("template") {
+					Some(t) => { t.to_string() }
+					None => { config_get(s.conn(), "status.template")? }
+				};
+				let text = vars.iter().map(|v| prompt::fill(&template, v)).collect::<Vec<String>>().join(" | ");
+				let state = match status.open.iter().find(|x| !x.2) {
+					Some(_) => { "running" }
+					None if !status.open.is_empty() => { "paused" }
+					None => { "idle" }
+				};
+				if status_matches.is_present("waybar") {
+					let mut tooltip: Vec<String> = vars.iter().map(|v| format!("{} {} ({})", v["project"], v["elapsed"], v["state"])).collect();
+					tooltip.push(format!("Today: {}", format_duration(today)));
+					println!("{}", prompt::waybar(&text, &tooltip.join("\n"), state));
+				} else if status_matches.is_present("i3blocks") {
+					let short = vars.iter().map(|v| v["elapsed"].clone()).collect::<Vec<String>>().join(" | ");
+					println!("{}", prompt::i3blocks(&text, &short, state));
+				} else if !text.is_empty() {
+					println!("{}", text);
+				}
+				return Ok(());
+			}
+
+			let v = json!({"open": d, "today": today.num_seconds()});
+			let mut rows = d.clone();
+			rows.push(json!({"project": "(today)", "duration": today.num_seconds()}));
+			output::print_with(&fmt, &v, &json!(rows), &["project", "duration", "state"])?;
 		}
 		("punchin", Some(punchin_matches)) => {
-			let name = punchin_matches.value_of("project").unwrap();
-			let projects = s.conn().list(None)?;
-			let index = projects.iter().position(|p| {
-				s.conn().fqn(ProjectRef::EId(p.ev.eid), None).unwrap() == name
-			}).unwrap();
-			let proj = projects.get(index).unwrap();
-			let t = s.punchin(proj)?;
-			println!("{}", serde_json::to_string_pretty(&t)?);
+			let proj = match punchin_matches.value_of("project") {
+				Some(name) if !punchin_matches.is_present("interactive") => { find_project(s, name)? }
+				_ => { choose_project(s)? }
+			};
+			let tags: Vec<String> = punchin_matches.values_of("tag").map(|v| v.map(|t| t.to_string()).collect()).unwrap_or(vec![]);
+			let t = s.punchin(&proj, &tags)?;
+			output::print(&fmt, &timeblock_json(s.conn(), &t, None)?, &TIMEBLOCK_COLUMNS)?;
 		}
 		("punchout", Some(punchout_matches)) => {
-			let name = punchout_matches.value_of("project");
-			let projects = s.conn().list(None)?;
-			let proj = match name {
-				Some(name) => {
-					let index = projects.iter().position(|p| {
-						s.conn().fqn(ProjectRef::EId(p.ev.eid), None).unwrap() == name
-					}).unwrap();
-					projects.get(index)
-				}
+			let proj = match punchout_matches.value_of("project") {
+				Some(name) => { Some(find_project(s, name)?) }
 				_ => None
 			};
-			let t = s.punchout(proj)?;
-			println!("{}", serde_json::to_string_pretty(&t)?);
+			let mut ls = Vec::new();
+			for tb in s.punchout(proj.as_ref())?.iter() {
+				ls.push(timeblock_json(s.conn(), tb, None)?);
+			}
+			output::print(&fmt, &json!(ls), &TIMEBLOCK_COLUMNS)?;
+		}
+		("switch", Some(switch_matches)) => {
+			let proj = find_project(s, switch_matches.value_of("project").unwrap())?;
+			let (closed, opened) = s.switch(&proj)?;
+			let mut ls = Vec::new();
+			for tb in closed.iter() {
+				ls.push(timeblock_json(s.conn(), tb, None)?);
+			}
+			let o = timeblock_json(s.conn(), &opened, None)?;
+			let v = json!({"closed": ls, "opened": o});
+			ls.push(o);
+			output::print_with(&fmt, &v, &json!(ls), &TIMEBLOCK_COLUMNS)?;
+		}
+		("alias", Some(alias_matches)) => {
+			match alias_matches.subcommand() {
+				("add", Some(m)) => {
+					let proj = find_project(s, m.value_of("project").unwrap())?;
+					s.conn().execute("INSERT OR REPLACE INTO alias (name, project_eid) VALUES (?, ?)", &[&m.value_of("name").unwrap(), &proj.ev.eid])?;
+				}
+				("rm", Some(m)) => {
+					let name = m.value_of("name").unwrap();
+					if s.conn().execute("DELETE FROM alias WHERE name=?", &[&name])? == 0 {
+						return Err(Error::NotFound(format!("No such alias: {}", name)));
+					}
+				}
+				_ => {
+					let mut ls = serde_json::Map::new();
+					for (name, eid) in alias_list(s.conn())? {
+						ls.insert(name, json!(s.conn().fqn(ProjectRef::EId(eid), None)?));
+					}
+					output::print_map(&fmt, &ls, &["alias", "project"])?;
+				}
+			}
 		}
-		("projects", Some(_)) => {
-			let ls: Vec<String> = s.conn().list(None)?.iter().map(|p| {
-				let remote_id = p.remote_id.clone();
-				s.conn().fqn(ProjectRef::Obj(p.clone()), None).unwrap()
+		("favorites", Some(favorites_matches)) => {
+			let n = favorites_matches.value_of("n").unwrap_or("10").parse::<usize>().map_err(|e| Error::Usage(format!("Invalid count: {}", e)))?;
+			let mut usage: Vec<(DbId, f64)> = project_usage(s.conn())?.into_iter().collect();
+			usage.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+			let alive: std::collections::HashSet<DbId> = s.conn().list(None)?.into_iter().filter(|p| p.alive).map(|p| p.ev.eid).collect();
+			let mut ls = Vec::new();
+			for (eid, _) in usage.into_iter().filter(|u| alive.contains(&u.0)).take(n) {
+				ls.push(s.conn().fqn(ProjectRef::EId(eid), None)?);
+			}
+			output::print(&fmt, &json!(ls), &["project"])?;
+		}
+		("recent", Some(recent_matches)) => {
+			let n = recent_matches.value_of("n").unwrap_or("10").parse::<i64>().map_err(|e| Error::Usage(format!("Invalid count: {}", e)))?;
+			let mut stmt = s.conn().prepare("SELECT tb.project_eid FROM timeblock AS tb WHERE tb.vid=0 GROUP BY tb.project_eid ORDER BY MAX(tb.start) DESC LIMIT ?")?;
+			let eids = stmt.query_map(&[&n], |row| { let eid: DbId = row.get(0); eid })?;
+			let mut ls = Vec::new();
+			for eid in eids {
+				ls.push(s.conn().fqn(ProjectRef::EId(eid?), None)?);
+			}
+			output::print(&fmt, &json!(ls), &["project"])?;
+		}
+		("config", Some(config_matches)) => {
+			match (config_matches.value_of("key"), config_matches.value_of("value")) {
+				(Some(key), Some(value)) => {
+					config_set(s.conn(), key, value)?;
+				}
+				(Some(key), None) => {
+					println!("{}", config_get(s.conn(), key)?);
+				}
+				_ => {
+					let mut m = serde_json::Map::new();
+					for c in CONFIG.iter() {
+						m.insert(c.0.to_string(), json!(config_get(s.conn(), c.0)?));
+					}
+					output::print_map(&fmt, &m, &["key", "value"])?;
+				}
+			}
+		}
+		("projects", Some(projects_matches)) => {
+			let p = match projects_matches.subcommand() {
+				("add", Some(m)) => {
+					Some(projects::add(s.conn(), m.value_of("path").unwrap())?)
+				}
+				("rename", Some(m)) => {
+					let proj = find_project(s, m.value_of("path").unwrap())?;
+					Some(projects::rename(s.conn(), &proj, m.value_of("name").unwrap())?)
+				}
+				("move", Some(m)) => {
+					let proj = find_project(s, m.value_of("path").unwrap())?;
+					let parent = match m.value_of("parent").unwrap() {
+						"" | "/" => { None }
+						name => { Some(find_project(s, name)?) }
+					};
+					Some(projects::reparent(s.conn(), &proj, parent.as_ref())?)
+				}
+				("archive", Some(m)) => {
+					let proj = find_project(s, m.value_of("path").unwrap())?;
+					Some(projects::set_alive(s.conn(), &proj, false)?)
+				}
+				("unarchive", Some(m)) => {
+					let proj = resolve_project(s, m.value_of("path").unwrap(), true)?;
+					Some(projects::set_alive(s.conn(), &proj, true)?)
+				}
+				("reset", Some(m)) => {
+					let proj = resolve_project(s, m.value_of("path").unwrap(), true)?;
+					projects::reset(s.conn(), &proj)?;
+					Some(proj)
+				}
+				_ => { None }
+			};
+			match p {
+				Some(p) => {
+					let v = json!({"id": p.ev.eid, "name": s.conn().fqn(ProjectRef::Obj(p.clone()), None)?, "archived": !p.alive});
+					output::print(&fmt, &v, &["id", "name", "archived"])?;
+				}
+				None => {
+					let period = match projects_matches.value_of("period") {
+						Some(name) => { Some(period_bounds(name, as_of.unwrap_or(Utc::now()))?) }
+						None => { None }
+					};
+					let depth = match projects_matches.value_of("depth") {
+						Some(d) => { Some(d.parse::<usize>().map_err(|e| Error::Usage(format!("Invalid depth: {}", e)))?) }
+						None => { None }
+					};
+					let filter = match projects_matches.value_of("query") {
+						Some(q) => { parse_query(s.conn(), q, as_of)? }
+						None => { None }
+					};
+					let ls = projects::listing(s.conn(), as_of, projects::Listing {
+						tree: projects_matches.is_present("tree"),
+						active: projects_matches.is_present("active"),
+						search: projects_matches.value_of("search").map(|x| x.to_string()),
+						depth,
+						period,
+						filter,
+					})?;
+					let columns: &[&str] = if period.is_some() { &["name", "time", "archived"] } else { &["name", "archived"] };
+					output::print(&fmt, &ls, if projects_matches.is_present("tree") || period.is_some() { columns } else { &["project"] })?;
+				}
+			}
+		}
+		("log", Some(log_matches)) => {
+			let t: &dyn TimeblockDataSource = s.conn();
+			let now = as_of.unwrap_or(Utc::now());
+			let mut filter = TimeblockFilter::Alive(true);
+			if let Some(tag) = log_matches.value_of("tag") {
+				filter = filter.and(TimeblockFilter::Tag(tag.to_string()));
+			}
+			if let Some(words) = log_matches.values_of("query") {
+				if let Some(q) = parse_query(s.conn(), &words.collect::<Vec<_>>().join(" "), as_of)? {
+					filter = filter.and(q);
+				}
+			}
+			let mut ls = Vec::new();
+			for tb in t.search(Some(filter), as_of)? {
+				ls.push(timeblock_json(s.conn(), &tb, as_of)?);
+			}
+			output::print(&fmt, &json!(ls), &TIMEBLOCK_COLUMNS)?;
+		}
+		("undo", Some(undo_matches)) => {
+			let n = undo_matches.value_of("n").unwrap_or("1").parse::<usize>().map_err(|e| Error::Usage(format!("Invalid count: {}", e)))?;
+			let ls: Vec<serde_json::Value> = s.undo(n, undo_matches.is_present("force"))?.iter().map(|tb| json!({"id": tb.ev.eid, "vid": tb.ev.vid})).collect();
+			output::print(&fmt, &json!(ls), &["id", "vid"])?;
+		}
+		("redo", Some(redo_matches)) => {
+			let n = redo_matches.value_of("n").unwrap_or("1").parse::<usize>().map_err(|e| Error::Usage(format!("Invalid count: {}", e)))?;
+			let ls: Vec<serde_json::Value> = s.redo(n)?.iter().map(|tb| json!({"id": tb.ev.eid, "vid": tb.ev.vid})).collect();
+			output::print(&fmt, &json!(ls), &["id", "vid"])?;
+		}
+		("history", Some(history_matches)) => {
+			let id = history_matches.value_of("id").unwrap();
+			let eid = id.parse::<DbId>().ok();
+			let versions = match history_matches.value_of("entity").unwrap() {
+				"project" => {
+					let r = match eid {
+						Some(eid) => { ProjectRef::EId(eid) }
+						None => { ProjectRef::RemoteId(id.to_string()) }
+					};
+					let psrc: &dyn ProjectDataSource = s.conn();
+					psrc.history(r)?.into_iter().map(|p| (p.ev.clone(), p.fields())).collect()
+				}
+				_ => {
+					let r = match eid {
+						Some(eid) => { TimeblockRef::EId(eid) }
+						None => { TimeblockRef::RemoteId(id.to_string()) }
+					};
+					let tbsrc: &dyn TimeblockDataSource = s.conn();
+					tbsrc.history(r)?.into_iter().map(|tb| (tb.ev.clone(), tb.fields())).collect()
+				}
+			};
+			let v = diff_versions(versions);
+			let rows: Vec<serde_json::Value> = v.as_array().unwrap().iter().map(|x| {
+				let changes: Vec<String> = x["changes"].as_object().unwrap().iter().map(|(k, c)| {
+					match c.get("old") {
+						Some(old) => { format!("{}: {} -> {}", k, old, c["new"]) }
+						None => { format!("{}: {}", k, c["new"]) }
+					}
+				}).collect();
+				json!({"vid": x["vid"], "vtime": x["vtime"], "changes": changes.join("; ")})
 			}).collect();
-			println!("{}", serde_json::to_string_pretty(&ls)?);
+			output::print_with(&fmt, &v, &json!(rows), &["vid", "vtime", "changes"])?;
 		}
 		_ => {
-			Error::TTError("No commands specified.".to_string());
+			return Err(Error::Usage("No command specified; see `tt --help`".to_string()));
 		}
 	}
 	Ok(())
 }
 
 
+/// A clap error message without the "error:" it starts with, colored or not, which `main` adds.
+fn usage_message(message: &str) -> String {
+	match message.find("error:") {
+		Some(i) if i < 10 => { message[i+6..].trim_start_matches("\u{1b}[0m").trim_start().to_string() }
+		_ => { message.to_string() }
+	}
+}
+
 fn main2() -> Result<(), Error> {
-    let m = cli::build_cli().get_matches();
-			
+	let m = match cli::build_cli().get_matches_safe() {
+		Ok(m) => { m }
+		Err(ref e) if e.kind == clap::ErrorKind::HelpDisplayed || e.kind == clap::ErrorKind::VersionDisplayed => {
+			println!("{}", e.message);
+			return Ok(());
+		}
+		Err(ref e) if e.kind == clap::ErrorKind::MissingArgumentOrSubcommand => {
+			eprintln!("{}", e.message);
+			return Err(Error::Usage("A subcommand is required".to_string()));
+		}
+		Err(e) => { return Err(Error::Usage(usage_message(&e.message))) }
+	};
+
 	//TODO Handle --db option here.
-	let search = vec![std::env::home_dir().unwrap(), std::env::current_dir().unwrap()];
+	let mut search = Vec::new();
+	search.extend(std::env::home_dir());
+	search.extend(std::env::current_dir().ok());
 	let mut conn: Option<Connection> = None;
 	for dir in search {
-		let mut path = std::path::PathBuf::from(dir);
+		let mut path = dir;
 		path.push(".tt.sqlite");
-		conn = match Connection::open(path.as_path()) {
-			Ok(c) => { Some(c) }
-			_ => { None }
-		};
+		conn = Connection::open(path.as_path()).ok();
 		if conn.is_some() {
 			break
 		}
 	}
 	//TODO Handle choosing the sub-system
-	let c = &conn.unwrap();
-	upgrade(c, 0)?;
-	let t = teamwork::Teamwork::new(c)?;
-	Ok(dispatch(&m, &t)?)
+	let c = match conn {
+		Some(ref c) => { c }
+		None => { return Err(Error::TTError("Could not open .tt.sqlite in the home or current directory".to_string())) }
+	};
+	// Readers never block the writer under WAL, and writers wait for each other rather than failing.
+	c.execute_batch("PRAGMA journal_mode=WAL")?;
+	c.busy_timeout(std::time::Duration::from_millis(BUSY_TIMEOUT_MS))?;
+	if let ("db", Some(db_matches)) = m.subcommand() {
+		if let ("migrate", Some(migrate_matches)) = db_matches.subcommand() {
+			let fmt = output::Format::parse(m.value_of("format"))?;
+			let todo = if migrate_matches.is_present("dry-run") { db::pending(c)? } else { db::migrate(c)? };
+			let ls: Vec<serde_json::Value> = todo.iter().map(|&(v, d)| json!({"version": v, "description": d})).collect();
+			return output::print(&fmt, &json!(ls), &["version", "description"]);
+		}
+	}
+	db::migrate(c)?;
+	// Only syncing needs the backend, whose setup asks for credentials; everything else, including
+	// the shell until it runs `up` or `down`, works on the local database alone.
+	match m.subcommand_name() {
+		Some("up") | Some("down") | Some("shell") => { dispatch(&m, &teamwork::OnDemand { conn: c }) }
+		_ => { dispatch(&m, &Offline { conn: c }) }
+	}
 }
 
 fn main() {
 	match main2() {
 		Ok(_) => { }
-		Err(e) => panic!("error: {:?}", e)
+		Err(Error::Idle) => {
+			std::process::exit(Error::Idle.exit_code());
+		}
+		Err(e) => {
+			eprintln!("error: {}", e);
+			std::process::exit(e.exit_code());
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn at(s: &str) -> DateTime<Utc> {
+		s.parse::<DateTime<Utc>>().unwrap()
+	}
+
+	/// Moves a version to `vtime`, as if it had been written then.
+	fn backdate(conn: &Connection, table: &str, eid: DbId, vid: DbId, vtime: &str) {
+		conn.execute(&format!("UPDATE {} SET vtime=? WHERE eid=? AND vid=?", table), &[&vtime, &eid, &vid]).unwrap();
+	}
+
+	#[test]
+	fn usage_errors_drop_the_prefix_clap_adds() {
+		assert_eq!(usage_message("error: Found argument '--x'"), "Found argument '--x'");
+		assert_eq!(usage_message("\u{1b}[1;31merror:\u{1b}[0m Found argument"), "Found argument");
+		assert_eq!(usage_message("Invalid value"), "Invalid value");
+		let e = cli::build_cli().get_matches_from_safe(vec!["tt", "--bogus"]).unwrap_err();
+		assert_eq!(Error::Usage(usage_message(&e.message)).exit_code(), 2);
+	}
+
+	#[test]
+	fn as_of_reads_the_latest_version_written_by_then() {
+		let conn = db::memory();
+		let psrc: &dyn ProjectDataSource = &conn;
+		let tbsrc: &dyn TimeblockDataSource = &conn;
+		let p = psrc.upsert("Acme".to_string(), "/projects/1".to_string(), None, true).unwrap();
+		let tb = tbsrc.upsert(None, None, ProjectRef::EId(p.ev.eid), at("2020-01-01T09:00:00Z"), None, false, "first".to_string(), vec![], vec![], true, Origin::User).unwrap();
+		tbsrc.upsert(Some(TimeblockRef::EId(tb.ev.eid)), None, ProjectRef::EId(p.ev.eid), tb.start, None, false, "second".to_string(), vec![], vec![], true, Origin::User).unwrap();
+		tbsrc.upsert(Some(TimeblockRef::EId(tb.ev.eid)), None, ProjectRef::EId(p.ev.eid), tb.start, None, false, "third".to_string(), vec![], vec![], true, Origin::User).unwrap();
+		backdate(&conn, "project", p.ev.eid, 0, "2020-01-01T08:00:00+00:00");
+		backdate(&conn, "timeblock", tb.ev.eid, 0, "2020-01-01T09:00:00+00:00");
+		backdate(&conn, "timeblock", tb.ev.eid, 1, "2020-01-01T10:00:00+00:00");
+		backdate(&conn, "timeblock", tb.ev.eid, 2, "2020-01-01T11:00:00+00:00");
+
+		let notes = |when: &str| -> Vec<String> {
+			tbsrc.search(None, Some(at(when))).unwrap().into_iter().map(|tb| tb.notes).collect()
+		};
+		assert_eq!(notes("2020-01-01T08:30:00Z"), Vec::<String>::new());
+		assert_eq!(notes("2020-01-01T09:00:00Z"), vec!["first"]);
+		assert_eq!(notes("2020-01-01T10:59:59Z"), vec!["second"]);
+		assert_eq!(notes("2020-01-02T00:00:00Z"), vec!["third"]);
+		assert_eq!(tbsrc.search(None, None).unwrap()[0].notes, "third");
+		assert_eq!(tbsrc.get(TimeblockRef::EId(tb.ev.eid), Some(at("2020-01-01T10:30:00Z"))).unwrap().unwrap().ev.vid, 1);
+		assert!(psrc.get(ProjectRef::EId(p.ev.eid), Some(at("2020-01-01T07:00:00Z"))).unwrap().is_none());
+	}
+
+	#[test]
+	fn as_of_skips_versions_written_later() {
+		let conn = db::memory();
+		let psrc: &dyn ProjectDataSource = &conn;
+		let p = psrc.upsert("Acme".to_string(), "/projects/1".to_string(), None, true).unwrap();
+		psrc.upsert("Acme Corp".to_string(), "/projects/1".to_string(), None, true).unwrap();
+		backdate(&conn, "project", p.ev.eid, 0, "2020-01-01T08:00:00+00:00");
+		backdate(&conn, "project", p.ev.eid, 1, "2020-01-01T12:00:00+00:00");
+		let name = |when: &str| psrc.get(ProjectRef::EId(p.ev.eid), Some(at(when))).unwrap().unwrap().name;
+		assert_eq!(name("2020-01-01T11:00:00Z"), "Acme");
+		assert_eq!(name("2020-01-01T12:00:00Z"), "Acme Corp");
+		assert_eq!(psrc.list(None).unwrap()[0].name, "Acme Corp");
+	}
+
+	/// A project and a local timeblock on it whose notes went from "first" to "second".
+	fn edited_timeblock(conn: &Connection) -> (Project, Timeblock) {
+		let psrc: &dyn ProjectDataSource = conn;
+		let tbsrc: &dyn TimeblockDataSource = conn;
+		let p = psrc.upsert("Acme".to_string(), "/projects/1".to_string(), None, true).unwrap();
+		let tb = tbsrc.upsert(None, None, ProjectRef::EId(p.ev.eid), at("2020-01-01T09:00:00Z"), None, false, "first".to_string(), vec![], vec![], true, Origin::User).unwrap();
+		let tb = tbsrc.upsert(Some(TimeblockRef::EId(tb.ev.eid)), None, ProjectRef::EId(p.ev.eid), tb.start, None, false, "second".to_string(), vec![], vec![], true, Origin::User).unwrap();
+		(p, tb)
+	}
+
+	fn current(conn: &Connection, eid: DbId) -> Timeblock {
+		let tbsrc: &dyn TimeblockDataSource = conn;
+		tbsrc.get(TimeblockRef::EId(eid), None).unwrap().unwrap()
+	}
+
+	#[test]
+	fn undo_and_redo_step_through_local_changes() {
+		let conn = db::memory();
+		let s = Offline { conn: &conn };
+		let (_, tb) = edited_timeblock(&conn);
+
+		s.undo(1, false).unwrap();
+		assert_eq!(current(&conn, tb.ev.eid).notes, "first");
+		s.undo(1, false).unwrap();
+		assert!(!current(&conn, tb.ev.eid).alive);
+		assert!(s.undo(1, false).is_err());
+
+		s.redo(2).unwrap();
+		let tb2 = current(&conn, tb.ev.eid);
+		assert!(tb2.alive);
+		assert_eq!(tb2.notes, "second");
+		assert!(s.redo(1).is_err());
+	}
+
+	#[test]
+	fn a_new_change_drops_the_redo_history() {
+		let conn = db::memory();
+		let s = Offline { conn: &conn };
+		let tbsrc: &dyn TimeblockDataSource = &conn;
+		let (p, tb) = edited_timeblock(&conn);
+		s.undo(1, false).unwrap();
+		tbsrc.upsert(Some(TimeblockRef::EId(tb.ev.eid)), None, ProjectRef::EId(p.ev.eid), tb.start, None, false, "third".to_string(), vec![], vec![], true, Origin::User).unwrap();
+		assert!(s.redo(1).is_err());
+		assert_eq!(current(&conn, tb.ev.eid).notes, "third");
+	}
+
+	#[test]
+	fn undo_skips_versions_brought_in_by_sync() {
+		let conn = db::memory();
+		let s = Offline { conn: &conn };
+		let tbsrc: &dyn TimeblockDataSource = &conn;
+		let (p, tb) = edited_timeblock(&conn);
+		let synced = tbsrc.upsert(None, Some("/time_entries/1".to_string()), ProjectRef::EId(p.ev.eid), at("2020-01-02T09:00:00Z"), Some(at("2020-01-02T10:00:00Z")), false, "".to_string(), vec![], vec![], true, Origin::Sync).unwrap();
+
+		let undone = s.undo(1, false).unwrap();
+		assert_eq!(undone[0].ev.eid, tb.ev.eid);
+		assert_eq!(current(&conn, tb.ev.eid).notes, "first");
+		assert_eq!(current(&conn, synced.ev.eid).ev.vid, 0);
+
+		// Sync bringing in more does not stand in the way of redoing.
+		tbsrc.upsert(None, Some("/time_entries/2".to_string()), ProjectRef::EId(p.ev.eid), at("2020-01-03T09:00:00Z"), Some(at("2020-01-03T10:00:00Z")), false, "".to_string(), vec![], vec![], true, Origin::Sync).unwrap();
+		s.redo(1).unwrap();
+		assert_eq!(current(&conn, tb.ev.eid).notes, "second");
+	}
+
+	#[test]
+	fn breaks_are_versioned_with_their_timeblock() {
+		let conn = db::memory();
+		let s = Offline { conn: &conn };
+		let psrc: &dyn ProjectDataSource = &conn;
+		let p = psrc.upsert("Acme".to_string(), "/projects/1".to_string(), None, true).unwrap();
+		let tb = s.punchin(&p, &[]).unwrap();
+		let paused = s.pause().unwrap().remove(0);
+		assert!(paused.ev.vid > tb.ev.vid);
+		assert!(is_paused(&current(&conn, tb.ev.eid), Utc::now()));
+		s.resume().unwrap();
+		assert!(!is_paused(&current(&conn, tb.ev.eid), Utc::now()));
+		// The version from before resuming still has the break running.
+		let tbsrc: &dyn TimeblockDataSource = &conn;
+		assert_eq!(tbsrc.get(TimeblockRef::EId(tb.ev.eid), Some(paused.ev.vtime)).unwrap().unwrap().pauses, paused.pauses);
+		s.undo(1, false).unwrap();
+		assert!(current(&conn, tb.ev.eid).pauses[0].1.is_none());
+	}
+
+	#[test]
+	fn split_breaks_pushes_each_stretch_of_work() {
+		let conn = db::memory();
+		let s = Offline { conn: &conn };
+		let psrc: &dyn ProjectDataSource = &conn;
+		let tbsrc: &dyn TimeblockDataSource = &conn;
+		let p = psrc.upsert("Acme".to_string(), "/projects/1".to_string(), None, true).unwrap();
+		let pauses = vec![(at("2020-01-01T10:00:00Z"), Some(at("2020-01-01T10:30:00Z")))];
+		let tb = tbsrc.upsert(None, None, ProjectRef::EId(p.ev.eid), at("2020-01-01T09:00:00Z"), Some(at("2020-01-01T12:00:00Z")), false, "".to_string(), vec![], pauses, true, Origin::User).unwrap();
+		assert_eq!(s.unsynced().unwrap()[0].1, Duration::minutes(150));
+
+		s.split_breaks().unwrap();
+		assert_eq!(s.unsynced().unwrap().len(), 1);
+		config_set(&conn, "sync.breaks", "split").unwrap();
+		s.split_breaks().unwrap();
+		let pushed: Vec<(DateTime<Utc>, Duration)> = s.unsynced().unwrap().into_iter().map(|(tb, d)| (tb.start, d)).collect();
+		assert_eq!(pushed, vec![(at("2020-01-01T09:00:00Z"), Duration::minutes(60)), (at("2020-01-01T10:30:00Z"), Duration::minutes(90))]);
+		// Splitting is part of syncing, so undo goes back to creating the timeblock.
+		let undone = s.undo(1, false).unwrap();
+		assert_eq!(undone[0].ev.eid, tb.ev.eid);
+		assert!(!undone[0].alive);
+	}
+
+	#[test]
+	fn project_subtrees_follow_the_parents_at_the_time() {
+		let conn = db::memory();
+		let psrc: &dyn ProjectDataSource = &conn;
+		let tbsrc: &dyn TimeblockDataSource = &conn;
+		let acme = psrc.upsert("Acme".to_string(), "/projects/1".to_string(), None, true).unwrap();
+		let web = psrc.upsert("Web".to_string(), "/projects/2".to_string(), Some(acme.ev.eid), true).unwrap();
+		let api = psrc.upsert("Api".to_string(), "/projects/3".to_string(), Some(web.ev.eid), true).unwrap();
+		let other = psrc.upsert("Other".to_string(), "/projects/4".to_string(), None, true).unwrap();
+		for p in [&acme, &web, &api, &other].iter() {
+			backdate(&conn, "project", p.ev.eid, 0, "2020-01-01T08:00:00+00:00");
+			let tb = tbsrc.upsert(None, None, ProjectRef::EId(p.ev.eid), at("2020-01-01T09:00:00Z"), None, false, p.name.clone(), vec![], vec![], true, Origin::User).unwrap();
+			backdate(&conn, "timeblock", tb.ev.eid, 0, "2020-01-01T09:00:00+00:00");
+		}
+		psrc.upsert("Web".to_string(), "/projects/2".to_string(), Some(other.ev.eid), true).unwrap();
+		backdate(&conn, "project", web.ev.eid, 1, "2020-01-02T08:00:00+00:00");
+
+		let notes = |root: ProjectRef, when: Option<&str>| -> Vec<String> {
+			let mut v: Vec<String> = tbsrc.search(Some(TimeblockFilter::ProjectSubtree(root)), when.map(at)).unwrap().into_iter().map(|tb| tb.notes).collect();
+			v.sort();
+			v
+		};
+		assert_eq!(notes(ProjectRef::EId(acme.ev.eid), Some("2020-01-01T12:00:00Z")), vec!["Acme", "Api", "Web"]);
+		assert_eq!(notes(ProjectRef::EId(acme.ev.eid), None), vec!["Acme"]);
+		assert_eq!(notes(ProjectRef::RemoteId("/projects/4".to_string()), None), vec!["Api", "Other", "Web"]);
+		assert_eq!(notes(ProjectRef::RemoteId("/projects/9".to_string()), None), Vec::<String>::new());
 	}
 }
@@ -0,0 +1,173 @@
+/// Splits a fully qualified project name into its components, honouring `\/` escapes.
+pub fn split_fqn(fqn: &str) -> Vec<String> {
+	let mut out = Vec::new();
+	let mut cur = String::new();
+	let mut chars = fqn.chars().peekable();
+	while let Some(c) = chars.next() {
+		match c {
+			'\\' if chars.peek() == Some(&'/') => {
+				cur.push('/');
+				chars.next();
+			}
+			'/' => {
+				out.push(cur);
+				cur = String::new();
+			}
+			_ => {
+				cur.push(c);
+			}
+		}
+	}
+	out.push(cur);
+	out
+}
+
+/// Stands between path components when matching, so a '/' inside a name never passes for one.
+const SEP: char = '\u{1f}';
+
+/// Lowercases the components of a split fqn and joins them with `SEP`.
+fn joined(path: &[String]) -> String {
+	path.iter().map(|c| c.to_lowercase()).collect::<Vec<String>>().join(&SEP.to_string())
+}
+
+/// Scores `query` as a case-insensitive subsequence of `fqn`, favouring runs and component starts.
+///
+/// Both are split into components first, so `a/b` only matches across a component boundary and
+/// `a\/b` only within a name.
+pub fn fuzzy_score(fqn: &str, query: &str) -> Option<i64> {
+	fuzzy(&joined(&split_fqn(fqn)), &joined(&split_fqn(query)))
+}
+
+fn fuzzy(s: &str, query: &str) -> Option<i64> {
+	let s: Vec<char> = s.chars().collect();
+	let mut score = 0;
+	let mut i = 0;
+	let mut last: Option<usize> = None;
+	for q in query.chars() {
+		while i < s.len() && s[i] != q {
+			i += 1;
+		}
+		if i == s.len() {
+			return None;
+		}
+		score += match last {
+			Some(l) if l+1 == i => { 5 }
+			_ if i == 0 || s[i-1] == SEP || s[i-1] == ' ' => { 10 }
+			Some(l) => { 1 - ((i - l) as i64).min(5) }
+			None => { 1 }
+		};
+		last = Some(i);
+		i += 1;
+	}
+	Some(score)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Match {
+	One(usize),
+	Ambiguous(Vec<usize>),
+	/// Only fuzzy matches, best first; these need confirming before use.
+	Fuzzy(Vec<usize>),
+	Nothing,
+}
+
+fn pick(v: Vec<usize>) -> Option<Match> {
+	match v.len() {
+		0 => { None }
+		1 => { Some(Match::One(v[0])) }
+		_ => { Some(Match::Ambiguous(v)) }
+	}
+}
+
+/// Finds `query` among `fqns` by, in order of preference: exact name, unique trailing path
+/// components, substring, and finally fuzzy score. Ambiguous and fuzzy results are ranked best
+/// first.
+pub fn find(fqns: &[String], query: &str) -> Match {
+	let q = split_fqn(query);
+	let paths: Vec<Vec<String>> = fqns.iter().map(|f| split_fqn(f)).collect();
+
+	if let Some(m) = pick((0..fqns.len()).filter(|&i| paths[i] == q).collect()) {
+		return m;
+	}
+
+	let lq: Vec<String> = q.iter().map(|c| c.to_lowercase()).collect();
+	if let Some(m) = pick((0..fqns.len()).filter(|&i| {
+		let p = &paths[i];
+		p.len() >= lq.len() && p[p.len()-lq.len()..].iter().zip(lq.iter()).all(|(a, b)| a.to_lowercase() == *b)
+	}).collect()) {
+		return m;
+	}
+
+	let lquery = joined(&q);
+	let lpaths: Vec<String> = paths.iter().map(|p| joined(p)).collect();
+	let mut substr: Vec<usize> = (0..fqns.len()).filter(|&i| lpaths[i].contains(lquery.as_str())).collect();
+	substr.sort_by_key(|&i| fqns[i].len());
+	if let Some(m) = pick(substr) {
+		return m;
+	}
+
+	let mut scored: Vec<(usize, i64)> = (0..fqns.len()).filter_map(|i| fuzzy(&lpaths[i], &lquery).map(|s| (i, s))).collect();
+	scored.sort_by(|a, b| b.1.cmp(&a.1).then(fqns[a.0].len().cmp(&fqns[b.0].len())));
+	if scored.is_empty() {
+		Match::Nothing
+	} else {
+		Match::Fuzzy(scored.into_iter().map(|(i, _)| i).collect())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn fqns(v: &[&str]) -> Vec<String> {
+		v.iter().map(|s| s.to_string()).collect()
+	}
+
+	#[test]
+	fn splits_on_unescaped_slashes() {
+		assert_eq!(split_fqn("a/b\\/c/d"), vec!["a", "b/c", "d"]);
+		assert_eq!(split_fqn("plain"), vec!["plain"]);
+	}
+
+	#[test]
+	fn prefers_an_exact_name() {
+		let f = fqns(&["acme", "acme/web", "acme/web/api"]);
+		assert_eq!(find(&f, "acme/web"), Match::One(1));
+		assert_eq!(find(&f, "acme"), Match::One(0));
+	}
+
+	#[test]
+	fn matches_trailing_components_ignoring_case() {
+		let f = fqns(&["acme/web", "acme/mobile", "other/mobile"]);
+		assert_eq!(find(&f, "WEB"), Match::One(0));
+		assert_eq!(find(&f, "mobile"), Match::Ambiguous(vec![1, 2]));
+		assert_eq!(find(&f, "other/mobile"), Match::One(2));
+	}
+
+	#[test]
+	fn falls_back_to_substrings_shortest_first() {
+		let f = fqns(&["acme/website", "acme/webshop/old", "beta"]);
+		assert_eq!(find(&f, "webs"), Match::Ambiguous(vec![0, 1]));
+		assert_eq!(find(&f, "site"), Match::One(0));
+	}
+
+	#[test]
+	fn fuzzy_matches_need_confirming() {
+		let f = fqns(&["acme/website", "beta/infra"]);
+		assert_eq!(find(&f, "awst"), Match::Fuzzy(vec![0]));
+		assert_eq!(find(&f, "zzz"), Match::Nothing);
+	}
+
+	#[test]
+	fn tells_a_slash_in_a_name_from_a_path() {
+		let f = fqns(&["A\\/B", "A/B"]);
+		assert_eq!(find(&f, "A/B"), Match::One(1));
+		assert_eq!(find(&f, "A\\/B"), Match::One(0));
+
+		let f = fqns(&["x/A\\/Bee", "x/A/Bee"]);
+		assert_eq!(find(&f, "A/Be"), Match::One(1));
+		assert_eq!(find(&f, "A\\/Be"), Match::One(0));
+		assert_eq!(fuzzy_score("x/A\\/Bee", "a/b"), None);
+		assert!(fuzzy_score("x/A/Bee", "a/b").is_some());
+	}
+}
@@ -0,0 +1,206 @@
+use std::io::Write;
+
+use termion;
+
+use super::chrono::{ DateTime, Local };
+use super::time::Duration;
+use super::serde_json;
+use super::serde_json::Value;
+use super::{ Error, format_duration };
+
+/// Columns holding a number of seconds, shown as a duration outside of JSON.
+static DURATION_COLUMNS: [&str; 2] = ["duration", "time"];
+
+pub enum Format {
+	Table,
+	Json,
+	Csv,
+	Plain,
+}
+
+impl Format {
+	/// The format named on the command line, defaulting to a table on a terminal and JSON otherwise.
+	pub fn parse(name: Option<&str>) -> Result<Format, Error> {
+		match name {
+			Some("table") => { Ok(Format::Table) }
+			Some("json") => { Ok(Format::Json) }
+			Some("csv") => { Ok(Format::Csv) }
+			Some("plain") => { Ok(Format::Plain) }
+			Some(x) => { Err(Error::TTError(format!("Unknown format: {}", x))) }
+			None if termion::is_tty(&::std::io::stdout()) => { Ok(Format::Table) }
+			None => { Ok(Format::Json) }
+		}
+	}
+}
+
+/// Renders one value for a table cell; timestamps are shown in local time, and seconds in a
+/// duration column as set by `format.duration`.
+fn cell(v: &Value, column: Option<&str>) -> String {
+	match *v {
+		Value::Null => { "".to_string() }
+		Value::Number(ref n) if column.is_some_and(|c| DURATION_COLUMNS.contains(&c)) && n.is_i64() => {
+			format_duration(Duration::seconds(n.as_i64().unwrap_or(0)))
+		}
+		Value::String(ref s) => {
+			match DateTime::parse_from_rfc3339(s) {
+				Ok(t) => { t.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string() }
+				Err(_) => { s.clone() }
+			}
+		}
+		Value::Array(ref a) => { a.iter().map(|x| cell(x, column)).collect::<Vec<String>>().join(", ") }
+		Value::Object(ref o) => { o.iter().map(|(k, v)| format!("{}={}", k, cell(v, Some(k)))).collect::<Vec<String>>().join(", ") }
+		ref x => { x.to_string() }
+	}
+}
+
+/// Flattens `rows` into cells, indenting the first column of nested `children`.
+fn cells(rows: &Value, columns: &[&str], depth: usize, out: &mut Vec<Vec<String>>) {
+	let items = match *rows {
+		Value::Array(ref a) => { a.iter().collect() }
+		Value::Null => { vec![] }
+		ref x => { vec![x] }
+	};
+	for item in items {
+		match *item {
+			Value::Object(ref o) => {
+				out.push(columns.iter().enumerate().map(|(i, c)| {
+					let indent = if i == 0 { "  ".repeat(depth) } else { "".to_string() };
+					format!("{}{}", indent, cell(o.get(*c).unwrap_or(&Value::Null), Some(c)))
+				}).collect());
+				if let Some(children) = o.get("children") {
+					cells(children, columns, depth+1, out);
+				}
+			}
+			ref x => {
+				out.push(vec![cell(x, columns.first().cloned())]);
+			}
+		}
+	}
+}
+
+fn csv_field(s: &str) -> String {
+	if s.contains(',') || s.contains('"') || s.contains('\n') {
+		format!("\"{}\"", s.replace("\"", "\"\""))
+	} else {
+		s.to_string()
+	}
+}
+
+/// Prints `v` as JSON, or `rows` as a table, CSV or tab separated lines with the given columns.
+pub fn print_with(format: &Format, v: &Value, rows: &Value, columns: &[&str]) -> Result<(), Error> {
+	let stdout = ::std::io::stdout();
+	let mut out = stdout.lock();
+	write(&mut out, format, v, rows, columns)
+}
+
+/// Like `print_with`, to `out`.
+fn write(out: &mut dyn Write, format: &Format, v: &Value, rows: &Value, columns: &[&str]) -> Result<(), Error> {
+	let mut table = Vec::new();
+	cells(rows, columns, 0, &mut table);
+	match *format {
+		Format::Json => {
+			writeln!(out, "{}", serde_json::to_string_pretty(v)?)?;
+		}
+		Format::Plain => {
+			for row in table {
+				writeln!(out, "{}", row.join("\t"))?;
+			}
+		}
+		Format::Csv => {
+			writeln!(out, "{}", columns.join(","))?;
+			for row in table {
+				writeln!(out, "{}", row.iter().map(|c| csv_field(c)).collect::<Vec<String>>().join(","))?;
+			}
+		}
+		Format::Table => {
+			let header: Vec<String> = columns.iter().map(|c| c.to_uppercase()).collect();
+			let mut widths: Vec<usize> = header.iter().map(|h| h.chars().count()).collect();
+			for row in table.iter() {
+				for (i, c) in row.iter().enumerate() {
+					if i < widths.len() {
+						widths[i] = widths[i].max(c.chars().count());
+					}
+				}
+			}
+			for row in ::std::iter::once(&header).chain(table.iter()) {
+				let line: Vec<String> = row.iter().enumerate().map(|(i, c)| {
+					let w = widths.get(i).cloned().unwrap_or(0);
+					format!("{}{}", c, " ".repeat(w.saturating_sub(c.chars().count())))
+				}).collect();
+				writeln!(out, "{}", line.join("  ").trim_end())?;
+			}
+		}
+	}
+	Ok(())
+}
+
+pub fn print(format: &Format, v: &Value, columns: &[&str]) -> Result<(), Error> {
+	print_with(format, v, v, columns)
+}
+
+/// Prints a map of names to values as two columns.
+pub fn print_map(format: &Format, v: &serde_json::Map<String, Value>, columns: &[&str; 2]) -> Result<(), Error> {
+	let rows: Vec<Value> = v.iter().map(|(k, x)| {
+		let mut m = serde_json::Map::new();
+		m.insert(columns[0].to_string(), Value::String(k.clone()));
+		m.insert(columns[1].to_string(), x.clone());
+		Value::Object(m)
+	}).collect();
+	print_with(format, &Value::Object(v.clone()), &Value::Array(rows), columns)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn render(format: Format, v: &Value, columns: &[&str]) -> String {
+		let mut out = Vec::new();
+		write(&mut out, &format, v, v, columns).unwrap();
+		String::from_utf8(out).unwrap()
+	}
+
+	fn rows() -> Value {
+		json!([
+			{"project": "Acme", "duration": 5400, "notes": "plan, \"review\"", "children": [
+				{"project": "Web", "duration": 61, "notes": null},
+			]},
+			{"project": "Beta", "duration": 0, "notes": "x", "tags": ["a", "b"]},
+		])
+	}
+
+	#[test]
+	fn tables_align_columns_and_indent_children() {
+		assert_eq!(render(Format::Table, &rows(), &["project", "duration", "notes"]), concat!(
+			"PROJECT  DURATION  NOTES\n",
+			"Acme     01:30:00  plan, \"review\"\n",
+			"  Web    00:01:01\n",
+			"Beta     00:00:00  x\n",
+		));
+	}
+
+	#[test]
+	fn csv_quotes_fields_and_plain_separates_with_tabs() {
+		assert_eq!(render(Format::Csv, &rows(), &["project", "duration", "notes", "tags"]), concat!(
+			"project,duration,notes,tags\n",
+			"Acme,01:30:00,\"plan, \"\"review\"\"\",\n",
+			"  Web,00:01:01,,\n",
+			"Beta,00:00:00,x,\"a, b\"\n",
+		));
+		assert_eq!(render(Format::Plain, &json!([{"time": 3600, "name": "x"}]), &["name", "time"]), "x\t01:00:00\n");
+	}
+
+	#[test]
+	fn json_keeps_durations_in_seconds() {
+		let v = json!([{"project": "Acme", "duration": 5400}]);
+		let out = render(Format::Json, &v, &["project", "duration"]);
+		assert_eq!(serde_json::from_str::<Value>(&out).unwrap(), v);
+		assert!(out.contains("5400"));
+	}
+
+	#[test]
+	fn only_duration_columns_show_seconds_as_durations() {
+		assert_eq!(cell(&json!(90), Some("duration")), "00:01:30");
+		assert_eq!(cell(&json!(90), Some("id")), "90");
+		assert_eq!(cell(&json!({"time": 90, "n": 2}), None), "n=2, time=00:01:30");
+	}
+}
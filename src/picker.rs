@@ -0,0 +1,187 @@
+use std::io;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+
+use libc;
+use termion;
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+use termion::screen::AlternateScreen;
+
+use super::matching;
+use super::Error;
+
+/// The size of the controlling terminal as (columns, rows).
+///
+/// Asks /dev/tty rather than stdout, so it works with output piped or redirected.
+pub fn terminal_size() -> Result<(u16, u16), Error> {
+	let tty = termion::get_tty()?;
+	let mut size = libc::winsize { ws_row: 0, ws_col: 0, ws_xpixel: 0, ws_ypixel: 0 };
+	if unsafe { libc::ioctl(tty.as_raw_fd(), libc::TIOCGWINSZ, &mut size) } == -1 {
+		return Err(Error::IOError(io::Error::last_os_error()));
+	}
+	Ok((size.ws_col, size.ws_row))
+}
+
+/// The number of rows of the controlling terminal, or 24 when it does not say.
+pub fn terminal_height() -> Result<usize, Error> {
+	match terminal_size()? {
+		(_, 0) => { Ok(24) }
+		(_, h) => { Ok(h as usize) }
+	}
+}
+
+/// Ranks `items` against `query`; an empty query keeps the given order.
+fn filter(items: &[String], query: &str) -> Vec<usize> {
+	if query.is_empty() {
+		return (0..items.len()).collect();
+	}
+	let mut scored: Vec<(usize, i64)> = items.iter().enumerate().filter_map(|(i, s)| {
+		matching::fuzzy_score(s, query).map(|x| (i, x))
+	}).collect();
+	scored.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+	scored.into_iter().map(|(i, _)| i).collect()
+}
+
+/// Lets the user choose one of `items` with an incremental search on the terminal.
+///
+/// Items should be given best first. Returns `None` when the user cancels.
+pub fn pick(prompt: &str, items: &[String]) -> Result<Option<usize>, Error> {
+	let tty = termion::get_tty()?;
+	let height = terminal_height()?;
+	let mut out = AlternateScreen::from(termion::get_tty()?.into_raw_mode()?);
+	pick_from(prompt, items, height, &mut tty.keys(), &mut out)
+}
+
+/// Like `pick`, on a terminal of `height` rows that is already in raw mode.
+pub fn pick_from(prompt: &str, items: &[String], height: usize, keys: &mut dyn Iterator<Item=io::Result<Key>>, out: &mut dyn Write) -> Result<Option<usize>, Error> {
+	let mut query = String::new();
+	let mut selected = 0;
+	loop {
+		let matches = filter(items, &query);
+		if selected >= matches.len() {
+			selected = if matches.is_empty() { 0 } else { matches.len()-1 };
+		}
+		let rows = height.saturating_sub(2);
+		let offset = if selected >= rows { selected+1-rows } else { 0 };
+
+		write!(out, "{}{}", termion::clear::All, termion::cursor::Goto(1, 1))?;
+		write!(out, "{}{}/{} > {}\r\n", prompt, matches.len(), items.len(), query)?;
+		for (row, &i) in matches.iter().enumerate().skip(offset).take(rows) {
+			if row == selected {
+				write!(out, "{}> {}{}\r\n", termion::style::Invert, items[i], termion::style::Reset)?;
+			} else {
+				write!(out, "  {}\r\n", items[i])?;
+			}
+		}
+		let width = prompt.len() + format!("{}/{} > ", matches.len(), items.len()).len() + query.chars().count();
+		write!(out, "{}", termion::cursor::Goto(width as u16 + 1, 1))?;
+		out.flush()?;
+
+		match keys.next() {
+			Some(Ok(Key::Char('\n'))) => {
+				return Ok(matches.get(selected).cloned());
+			}
+			Some(Ok(Key::Esc)) | Some(Ok(Key::Ctrl('c'))) | Some(Ok(Key::Ctrl('g'))) | None => {
+				return Ok(None);
+			}
+			Some(Ok(Key::Up)) | Some(Ok(Key::Ctrl('p'))) => {
+				selected = selected.saturating_sub(1);
+			}
+			Some(Ok(Key::Down)) | Some(Ok(Key::Ctrl('n'))) => {
+				selected += 1;
+			}
+			Some(Ok(Key::Backspace)) => {
+				query.pop();
+				selected = 0;
+			}
+			Some(Ok(Key::Ctrl('u'))) => {
+				query.clear();
+				selected = 0;
+			}
+			Some(Ok(Key::Char(c))) => {
+				query.push(c);
+				selected = 0;
+			}
+			Some(Ok(_)) => { }
+			Some(Err(e)) => {
+				return Err(Error::IOError(e));
+			}
+		}
+	}
+}
+
+/// Reads one line of text on a terminal in raw mode, starting from `initial`.
+///
+/// Returns `None` when the user cancels.
+pub fn input(prompt: &str, initial: &str, keys: &mut dyn Iterator<Item=io::Result<Key>>, out: &mut dyn Write) -> Result<Option<String>, Error> {
+	let mut line = initial.to_string();
+	loop {
+		write!(out, "{}{}{}{}{}", termion::cursor::Goto(1, 1), termion::clear::All, termion::cursor::Show, prompt, line)?;
+		out.flush()?;
+		match keys.next() {
+			Some(Ok(Key::Char('\n'))) => {
+				return Ok(Some(line));
+			}
+			Some(Ok(Key::Esc)) | Some(Ok(Key::Ctrl('c'))) | Some(Ok(Key::Ctrl('g'))) | None => {
+				return Ok(None);
+			}
+			Some(Ok(Key::Backspace)) => {
+				line.pop();
+			}
+			Some(Ok(Key::Ctrl('u'))) => {
+				line.clear();
+			}
+			Some(Ok(Key::Char(c))) => {
+				line.push(c);
+			}
+			Some(Ok(_)) => { }
+			Some(Err(e)) => {
+				return Err(Error::IOError(e));
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn pick_keys(items: &[&str], keys: Vec<Key>) -> Option<usize> {
+		let items: Vec<String> = items.iter().map(|s| s.to_string()).collect();
+		let mut out = Vec::new();
+		pick_from("project ", &items, 5, &mut keys.into_iter().map(Ok), &mut out).unwrap()
+	}
+
+	fn chars(s: &str) -> Vec<Key> {
+		s.chars().map(Key::Char).collect()
+	}
+
+	#[test]
+	fn picks_with_the_keyboard() {
+		let items = ["Acme/Web", "Acme/Api", "Beta", "Gamma", "Delta", "Epsilon"];
+		assert_eq!(pick_keys(&items, chars("\n")), Some(0));
+		assert_eq!(pick_keys(&items, vec![Key::Down, Key::Down, Key::Up, Key::Char('\n')]), Some(1));
+		assert_eq!(pick_keys(&items, chars("api\n")), Some(1));
+		assert_eq!(pick_keys(&items, chars("bt\n")), Some(2));
+		assert_eq!(pick_keys(&items, chars("xyz\n")), None);
+		assert_eq!(pick_keys(&items, [chars("xyz"), vec![Key::Ctrl('u')], chars("gam\n")].concat()), Some(3));
+		assert_eq!(pick_keys(&items, [chars("gamx"), vec![Key::Backspace, Key::Char('\n')]].concat()), Some(3));
+		assert_eq!(pick_keys(&items, [vec![Key::Down; 9], chars("\n")].concat()), Some(5));
+		assert_eq!(pick_keys(&items, chars("api").into_iter().chain(vec![Key::Esc]).collect()), None);
+		assert_eq!(pick_keys(&items, vec![Key::Down, Key::Ctrl('c')]), None);
+		assert_eq!(pick_keys(&items, chars("api")), None);
+	}
+
+	#[test]
+	fn scrolls_to_the_selection() {
+		let items: Vec<String> = (0..10).map(|i| format!("item {}", i)).collect();
+		let mut out = Vec::new();
+		let keys = [vec![Key::Down; 6], vec![Key::Esc]].concat();
+		pick_from("", &items, 5, &mut keys.into_iter().map(Ok), &mut out).unwrap();
+		let out = String::from_utf8(out).unwrap();
+		let last = &out[out.rfind("10/10").unwrap()..];
+		assert!(last.contains("item 6") && last.contains("item 4") && !last.contains("item 3"));
+	}
+}
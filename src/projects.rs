@@ -0,0 +1,300 @@
+use std::collections::{ HashMap, HashSet };
+
+use rusqlite::Connection;
+use rusqlite::types::ToSql;
+
+use super::chrono::{ DateTime, Utc };
+use super::time::Duration;
+use super::serde_json;
+use super::matching;
+use super::{ Project, ProjectRef, ProjectDataSource, TimeblockFilter, TimeblockDataSource, DbId, Error, LOCAL_REMOTE_ID };
+use super::{ transaction, net_between };
+
+/// Computes the fully qualified name of every project in `projects` without touching the database.
+///
+/// Parents missing from `projects` end the path, as does a cycle in `parent_eid`.
+pub fn fqns(projects: &[Project]) -> HashMap<DbId, String> {
+	let by_eid: HashMap<DbId, &Project> = projects.iter().map(|p| (p.ev.eid, p)).collect();
+	let mut out = HashMap::new();
+	for p in projects {
+		let mut names = Vec::new();
+		let mut seen = HashSet::new();
+		let mut cur = Some(p);
+		while let Some(c) = cur {
+			if !seen.insert(c.ev.eid) {
+				break;
+			}
+			names.push(c.name.replace("/", "\\/"));
+			cur = c.parent_eid.and_then(|eid| by_eid.get(&eid).cloned());
+		}
+		names.reverse();
+		out.insert(p.ev.eid, names.join("/"));
+	}
+	out
+}
+
+/// Time tracked on each project between `from` and `to` on the timeblocks matching `filter`, as
+/// they were at `when`.
+pub fn totals(conn: &Connection, from: DateTime<Utc>, to: DateTime<Utc>, filter: Option<TimeblockFilter>, when: Option<DateTime<Utc>>) -> Result<HashMap<DbId, Duration>, Error> {
+	let tbsrc: &dyn TimeblockDataSource = conn;
+	let now = when.unwrap_or(Utc::now());
+	let to = ::std::cmp::min(to, now);
+	let mut f = TimeblockFilter::Alive(true).and(TimeblockFilter::Between(from, to));
+	if let Some(filter) = filter {
+		f = f.and(filter);
+	}
+	let mut out = HashMap::new();
+	for tb in tbsrc.search(Some(f), when)? {
+		let d = net_between(&tb, from, to);
+		if let Some(eid) = tb.project_eid() {
+			let e = out.entry(eid).or_insert(Duration::zero());
+			*e = *e + d;
+		}
+	}
+	Ok(out)
+}
+
+pub struct Listing {
+	pub tree: bool,
+	pub active: bool,
+	pub search: Option<String>,
+	pub depth: Option<usize>,
+	pub period: Option<(DateTime<Utc>, DateTime<Utc>)>,
+	/// Only count the time on timeblocks matching this.
+	pub filter: Option<TimeblockFilter>,
+}
+
+/// Lists projects as they were at `when`, either as a flat list of names or as a nested tree.
+///
+/// Times, when a period is given, include the time tracked on sub-projects.
+pub fn listing(conn: &Connection, when: Option<DateTime<Utc>>, mut opts: Listing) -> Result<serde_json::Value, Error> {
+	let psrc: &dyn ProjectDataSource = conn;
+	let projects = psrc.list(when)?;
+	let by_eid: HashMap<DbId, &Project> = projects.iter().map(|p| (p.ev.eid, p)).collect();
+	let names = fqns(&projects);
+	let ancestors = |p: &Project| -> Vec<DbId> {
+		let mut out = Vec::new();
+		let mut cur = p.parent_eid;
+		while let Some(eid) = cur {
+			if out.contains(&eid) || eid == p.ev.eid {
+				break;
+			}
+			out.push(eid);
+			cur = by_eid.get(&eid).and_then(|x| x.parent_eid);
+		}
+		out
+	};
+
+	let mut times: HashMap<DbId, Duration> = HashMap::new();
+	if let Some((from, to)) = opts.period {
+		for (eid, d) in totals(conn, from, to, opts.filter.take(), when)? {
+			let mut eids = vec![eid];
+			if let Some(p) = by_eid.get(&eid) {
+				eids.extend(ancestors(p));
+			}
+			for e in eids {
+				let x = times.entry(e).or_insert(Duration::zero());
+				*x = *x + d;
+			}
+		}
+	}
+
+	let search = opts.search.as_ref().map(|s| s.to_lowercase());
+	let shown: Vec<&Project> = projects.iter().filter(|p| {
+		let anc = ancestors(p);
+		(!opts.active || (p.alive && anc.iter().all(|e| by_eid.get(e).is_none_or(|x| x.alive)))) &&
+		opts.depth.is_none_or(|d| anc.len() < d) &&
+		search.as_ref().is_none_or(|s| names[&p.ev.eid].to_lowercase().contains(s.as_str()))
+	}).collect();
+
+	let node = |p: &Project| -> serde_json::Value {
+		let mut m = serde_json::Map::new();
+		m.insert("id".to_string(), json!(p.ev.eid));
+		m.insert("name".to_string(), json!(if opts.tree { p.name.clone() } else { names[&p.ev.eid].clone() }));
+		if !p.alive {
+			m.insert("archived".to_string(), json!(true));
+		}
+		if opts.period.is_some() {
+			m.insert("time".to_string(), json!(times.get(&p.ev.eid).cloned().unwrap_or(Duration::zero()).num_seconds()));
+		}
+		serde_json::Value::Object(m)
+	};
+
+	if !opts.tree {
+		if opts.period.is_none() {
+			return Ok(json!(shown.iter().map(|p| names[&p.ev.eid].clone()).collect::<Vec<String>>()));
+		}
+		return Ok(json!(shown.iter().map(|p| node(p)).collect::<Vec<serde_json::Value>>()));
+	}
+
+	// Matches are shown in context, so keep their ancestors too.
+	let mut visible: HashSet<DbId> = HashSet::new();
+	for p in shown.iter() {
+		visible.insert(p.ev.eid);
+		visible.extend(ancestors(p));
+	}
+	let mut children: HashMap<Option<DbId>, Vec<&Project>> = HashMap::new();
+	for p in projects.iter().filter(|p| visible.contains(&p.ev.eid)) {
+		let parent = p.parent_eid.filter(|e| visible.contains(e) && *e != p.ev.eid);
+		children.entry(parent).or_default().push(p);
+	}
+	for v in children.values_mut() {
+		v.sort_by(|a, b| a.name.cmp(&b.name));
+	}
+	fn build(parent: Option<DbId>, children: &HashMap<Option<DbId>, Vec<&Project>>, node: &dyn Fn(&Project) -> serde_json::Value, seen: &mut HashSet<DbId>) -> Vec<serde_json::Value> {
+		let mut out = Vec::new();
+		for p in children.get(&parent).map(|v| v.as_slice()).unwrap_or(&[]) {
+			if !seen.insert(p.ev.eid) {
+				continue;
+			}
+			let mut n = node(p);
+			let sub = build(Some(p.ev.eid), children, node, seen);
+			if !sub.is_empty() {
+				n["children"] = json!(sub);
+			}
+			out.push(n);
+		}
+		out
+	}
+	Ok(json!(build(None, &children, &node, &mut HashSet::new())))
+}
+
+/// Records a local edit of a synced project so the next `down` does not revert it.
+fn set_override(conn: &Connection, p: &Project, column: &str, value: &dyn ToSql) -> Result<(), Error> {
+	if p.is_local() {
+		return Ok(());
+	}
+	conn.execute("INSERT OR IGNORE INTO project_override (project_eid) VALUES (?)", &[&p.ev.eid])?;
+	conn.execute(&format!("UPDATE project_override SET {}=? WHERE project_eid=?", column), &[value, &p.ev.eid])?;
+	Ok(())
+}
+
+/// Drops the local edits of a synced project, so the next `down` takes the remote's name, parent
+/// and state again.
+pub fn reset(conn: &Connection, p: &Project) -> Result<(), Error> {
+	if p.is_local() {
+		return Err(Error::TTError("Local projects have no remote to reset to".to_string()));
+	}
+	if conn.execute("DELETE FROM project_override WHERE project_eid=?", &[&p.ev.eid])? == 0 {
+		return Err(Error::NotFound(format!("Project has no local edits: {}", p.ev.eid)));
+	}
+	Ok(())
+}
+
+/// The next free remote id for a local project.
+///
+/// Numbered on their own rather than after the entity ids, so a local id never depends on what
+/// sync brought in.
+fn next_local_remote_id(conn: &Connection) -> Result<String, Error> {
+	let n: i64 = conn.query_row(
+		"SELECT IFNULL(MAX(CAST(SUBSTR(remote_id, ?) AS INTEGER)), 0)+1 FROM project WHERE remote_id GLOB ?",
+		&[&(LOCAL_REMOTE_ID.len() as i64 + 1), &format!("{}[0-9]*", LOCAL_REMOTE_ID)],
+		|row| row.get(0))?;
+	Ok(format!("{}{}", LOCAL_REMOTE_ID, n))
+}
+
+fn child(conn: &Connection, parent_eid: Option<DbId>, name: &str) -> Result<Option<Project>, Error> {
+	let psrc: &dyn ProjectDataSource = conn;
+	Ok(psrc.list(None)?.into_iter().find(|p| p.parent_eid == parent_eid && p.name == name))
+}
+
+/// Creates the local project at `path`, along with any missing parents.
+pub fn add(conn: &Connection, path: &str) -> Result<Project, Error> {
+	transaction(conn, || {
+		let psrc: &dyn ProjectDataSource = conn;
+		let mut parent: Option<Project> = None;
+		let mut created = false;
+		for name in matching::split_fqn(path) {
+			if name.is_empty() {
+				return Err(Error::TTError(format!("Invalid project path: {}", path)));
+			}
+			let parent_eid = parent.as_ref().map(|p| p.ev.eid);
+			parent = match child(conn, parent_eid, &name)? {
+				Some(p) => { Some(p) }
+				None => {
+					created = true;
+					Some(psrc.upsert(name, next_local_remote_id(conn)?, parent_eid, true)?)
+				}
+			};
+		}
+		if !created {
+			return Err(Error::TTError(format!("Project already exists: {}", path)));
+		}
+		Ok(parent.unwrap())
+	})
+}
+
+pub fn rename(conn: &Connection, p: &Project, name: &str) -> Result<Project, Error> {
+	transaction(conn, || {
+		let psrc: &dyn ProjectDataSource = conn;
+		if name.is_empty() {
+			return Err(Error::TTError("Project names cannot be empty".to_string()));
+		}
+		if let Some(other) = child(conn, p.parent_eid, name)? {
+			return Err(Error::TTError(format!("A project named '{}' already exists there: {}", name, other.ev.eid)));
+		}
+		set_override(conn, p, "name", &name)?;
+		psrc.upsert(name.to_string(), p.remote_id.clone(), p.parent_eid, p.alive)
+	})
+}
+
+/// Moves `p` under `parent`, or to the top level when `parent` is `None`.
+pub fn reparent(conn: &Connection, p: &Project, parent: Option<&Project>) -> Result<Project, Error> {
+	transaction(conn, || {
+		let psrc: &dyn ProjectDataSource = conn;
+		let parent_eid = parent.map(|x| x.ev.eid);
+		if let Some(parent) = parent {
+			if psrc.parents(ProjectRef::EId(parent.ev.eid), None)?.iter().any(|x| x.ev.eid == p.ev.eid) {
+				return Err(Error::TTError("Cannot move a project under itself".to_string()));
+			}
+		}
+		if child(conn, parent_eid, &p.name)?.is_some() {
+			return Err(Error::TTError(format!("A project named '{}' already exists there", p.name)));
+		}
+		set_override(conn, p, "parent_eid", &parent_eid)?;
+		set_override(conn, p, "reparented", &true)?;
+		psrc.upsert(p.name.clone(), p.remote_id.clone(), parent_eid, p.alive)
+	})
+}
+
+pub fn set_alive(conn: &Connection, p: &Project, alive: bool) -> Result<Project, Error> {
+	transaction(conn, || {
+		let psrc: &dyn ProjectDataSource = conn;
+		if p.alive == alive {
+			return Err(Error::TTError(format!("Project is already {}", if alive { "active" } else { "archived" })));
+		}
+		set_override(conn, p, "alive", &alive)?;
+		psrc.upsert(p.name.clone(), p.remote_id.clone(), p.parent_eid, alive)
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::db;
+
+	#[test]
+	fn numbers_local_projects_apart_from_synced_ones() {
+		let conn = db::memory();
+		let psrc: &dyn ProjectDataSource = &conn;
+		psrc.upsert("Synced".to_string(), "/projects/7".to_string(), None, true).unwrap();
+		psrc.upsert("Other".to_string(), "/projects/8".to_string(), None, true).unwrap();
+		let a = add(&conn, "Mine/Sub").unwrap();
+		assert_eq!(a.remote_id, "/local/2");
+		assert_eq!(psrc.get(ProjectRef::EId(a.parent_eid.unwrap()), None).unwrap().unwrap().remote_id, "/local/1");
+		assert_eq!(add(&conn, "More").unwrap().remote_id, "/local/3");
+	}
+
+	#[test]
+	fn reset_lets_sync_win_again() {
+		let conn = db::memory();
+		let psrc: &dyn ProjectDataSource = &conn;
+		let p = psrc.upsert("Remote".to_string(), "/projects/1".to_string(), None, true).unwrap();
+		let p = rename(&conn, &p, "Mine").unwrap();
+		assert_eq!(psrc.upsert("Remote".to_string(), "/projects/1".to_string(), None, true).unwrap().name, "Mine");
+
+		reset(&conn, &p).unwrap();
+		assert_eq!(psrc.upsert("Remote".to_string(), "/projects/1".to_string(), None, true).unwrap().name, "Remote");
+		assert!(reset(&conn, &p).is_err());
+	}
+}
@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+
+use super::serde_json::Value;
+
+/// Replaces each `{key}` in `template` with its value from `vars`; unknown keys are kept as is.
+pub fn fill(template: &str, vars: &HashMap<&str, String>) -> String {
+	let mut out = String::new();
+	let mut rest = template;
+	while let Some(i) = rest.find('{') {
+		out.push_str(&rest[..i]);
+		rest = &rest[i..];
+		match rest.find('}') {
+			Some(j) => {
+				match vars.get(&rest[1..j]) {
+					Some(v) => { out.push_str(v) }
+					None => { out.push_str(&rest[..j+1]) }
+				}
+				rest = &rest[j+1..];
+			}
+			None => { break }
+		}
+	}
+	out.push_str(rest);
+	out
+}
+
+/// The output of a waybar custom module with `return-type` set to `json`.
+///
+/// The class is `running`, `paused` or `idle`, for styling.
+pub fn waybar(text: &str, tooltip: &str, state: &str) -> Value {
+	json!({
+		"text": text,
+		"tooltip": tooltip,
+		"class": state,
+		"alt": state
+	})
+}
+
+/// The output of an i3blocks block with `format=json`.
+pub fn i3blocks(text: &str, short: &str, state: &str) -> Value {
+	let mut v = json!({
+		"full_text": text,
+		"short_text": short
+	});
+	if state == "paused" {
+		v["color"] = json!("#FFCC00");
+	}
+	v
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn fills_known_keys_and_keeps_the_rest() {
+		let mut vars = HashMap::new();
+		vars.insert("project", "Acme/Web".to_string());
+		vars.insert("elapsed", "01:30".to_string());
+		assert_eq!(fill("{project} {elapsed}", &vars), "Acme/Web 01:30");
+		assert_eq!(fill("[{project}] {unknown}", &vars), "[Acme/Web] {unknown}");
+		assert_eq!(fill("no keys", &vars), "no keys");
+		assert_eq!(fill("{project} {elapsed", &vars), "Acme/Web {elapsed");
+		assert_eq!(fill("{}{project}", &vars), "{}Acme/Web");
+	}
+
+	#[test]
+	fn formats_bar_blocks() {
+		assert_eq!(waybar("Acme 01:30", "Acme since 09:00", "running"), json!({"text": "Acme 01:30", "tooltip": "Acme since 09:00", "class": "running", "alt": "running"}));
+		assert_eq!(i3blocks("Acme 01:30", "01:30", "running"), json!({"full_text": "Acme 01:30", "short_text": "01:30"}));
+		assert_eq!(i3blocks("Acme 01:30", "01:30", "paused")["color"], json!("#FFCC00"));
+	}
+}
@@ -0,0 +1,430 @@
+//! A compact text syntax for timeblock filters, such as
+//! `project:acme/* tag:meeting since:monday -billable`.
+//!
+//! Terms are joined with AND unless separated by `or`; `not` or a leading `-` negates a term and
+//! parentheses group. The terms are:
+//!
+//! - `project:GLOB`, matching full project names case-insensitively, where `*` matches anything
+//!   (including `/`) and `?` one character, and `project:GLOB/**`, matching those projects and
+//!   every project under them;
+//! - `tag:TAG`, matching TAG and the tags under it, or every tag starting with TAG when it ends in `*`;
+//! - `since:DATE`, `until:DATE` and `on:DATE`, where DATE is a date or time, `now`, a period
+//!   (`today`, `yesterday`, `week`, `month`, `year`) or a weekday meaning the last such day;
+//! - `id:N`, `notes:TEXT`, `billable`, `open` and `closed`;
+//! - any other word, which must appear in the notes.
+//!
+//! Values with spaces can be quoted, as in `notes:"weekly sync"`.
+
+use std::collections::HashMap;
+
+use super::chrono::{ DateTime, Utc, Local, Datelike, Weekday };
+use super::time::Duration;
+use super::{ TimeblockFilter, TimeblockRef, ProjectRef, DbId, Error };
+use super::{ parse_time, period_bounds, local_to_utc };
+
+/// What a query needs to know to resolve project names and relative dates.
+pub struct Context {
+	pub fqns: HashMap<DbId, String>,
+	pub now: DateTime<Utc>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Kind {
+	Open,
+	Close,
+	Minus,
+	Word(String, bool),
+}
+
+struct Token {
+	kind: Kind,
+	/// Offset and length in characters, for pointing at the token in errors.
+	pos: usize,
+	len: usize,
+}
+
+fn error(query: &str, pos: usize, len: usize, message: &str) -> Error {
+	Error::Usage(format!("{}\n  {}\n  {}{}", message, query, " ".repeat(pos), "^".repeat(len.max(1))))
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token>, Error> {
+	let chars: Vec<char> = query.chars().collect();
+	let mut out = Vec::new();
+	let mut i = 0;
+	while i < chars.len() {
+		let c = chars[i];
+		if c.is_whitespace() {
+			i += 1;
+			continue;
+		}
+		let start = i;
+		let kind = match c {
+			'(' => { i += 1; Kind::Open }
+			')' => { i += 1; Kind::Close }
+			'-' if i+1 < chars.len() && !chars[i+1].is_whitespace() => { i += 1; Kind::Minus }
+			_ => {
+				let mut word = String::new();
+				let mut quoted = false;
+				while i < chars.len() && !chars[i].is_whitespace() && chars[i] != '(' && chars[i] != ')' {
+					if chars[i] == '"' {
+						quoted = true;
+						let open = i;
+						i += 1;
+						while i < chars.len() && chars[i] != '"' {
+							word.push(chars[i]);
+							i += 1;
+						}
+						if i == chars.len() {
+							return Err(error(query, open, 1, "Unterminated quote"));
+						}
+					} else {
+						word.push(chars[i]);
+					}
+					i += 1;
+				}
+				Kind::Word(word, quoted)
+			}
+		};
+		out.push(Token { kind, pos: start, len: i-start });
+	}
+	Ok(out)
+}
+
+/// Matches `s` against a glob where `*` matches any run of characters and `?` any one.
+///
+/// On a mismatch only the last `*` is retried one character further, so this takes at most
+/// `pattern.len() * s.len()` steps however many stars there are.
+fn glob(pattern: &[char], s: &[char]) -> bool {
+	let (mut p, mut i) = (0, 0);
+	let mut star: Option<(usize, usize)> = None;
+	while i < s.len() {
+		if p < pattern.len() && pattern[p] == '*' {
+			star = Some((p, i));
+			p += 1;
+		} else if p < pattern.len() && (pattern[p] == '?' || pattern[p] == s[i]) {
+			p += 1;
+			i += 1;
+		} else if let Some((sp, si)) = star {
+			star = Some((sp, si+1));
+			p = sp+1;
+			i = si+1;
+		} else {
+			return false;
+		}
+	}
+	pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn weekday(name: &str) -> Option<Weekday> {
+	match name {
+		"monday" | "mon" => { Some(Weekday::Mon) }
+		"tuesday" | "tue" => { Some(Weekday::Tue) }
+		"wednesday" | "wed" => { Some(Weekday::Wed) }
+		"thursday" | "thu" => { Some(Weekday::Thu) }
+		"friday" | "fri" => { Some(Weekday::Fri) }
+		"saturday" | "sat" => { Some(Weekday::Sat) }
+		"sunday" | "sun" => { Some(Weekday::Sun) }
+		_ => { None }
+	}
+}
+
+/// The span of time a date value stands for: a whole period or day, or an instant.
+fn span(value: &str, now: DateTime<Utc>) -> Result<(DateTime<Utc>, DateTime<Utc>), String> {
+	let v = value.to_lowercase();
+	match v.as_str() {
+		"now" => { return Ok((now, now)) }
+		"today" | "yesterday" | "week" | "month" | "year" => {
+			return period_bounds(&v, now).map_err(|e| format!("{}", e));
+		}
+		_ => { }
+	}
+	if let Some(day) = weekday(&v) {
+		let today = now.with_timezone(&Local).date().naive_local();
+		let back = (7 + today.weekday().num_days_from_monday() - day.num_days_from_monday()) % 7;
+		let d = today - Duration::days(back as i64);
+		let from = local_to_utc(d.and_hms(0, 0, 0)).map_err(|e| format!("{}", e))?;
+		return Ok((from, from + Duration::days(1)));
+	}
+	match parse_time(value) {
+		Ok(t) if value.len() <= 10 => { Ok((t, t + Duration::days(1))) }
+		Ok(t) => { Ok((t, t)) }
+		Err(_) => { Err(format!("Invalid date: {}", value)) }
+	}
+}
+
+struct Parser<'a> {
+	query: &'a str,
+	tokens: Vec<Token>,
+	next: usize,
+	ctx: &'a Context,
+}
+
+impl<'a> Parser<'a> {
+	fn peek(&self) -> Option<&Token> {
+		self.tokens.get(self.next)
+	}
+
+	fn is_keyword(&self, word: &str) -> bool {
+		match self.peek() {
+			Some(&Token { kind: Kind::Word(ref w, false), .. }) => { w.eq_ignore_ascii_case(word) }
+			_ => { false }
+		}
+	}
+
+	fn or_expr(&mut self) -> Result<TimeblockFilter, Error> {
+		let mut f = self.and_expr()?;
+		while self.is_keyword("or") {
+			self.next += 1;
+			f = f.or(self.and_expr()?);
+		}
+		Ok(f)
+	}
+
+	fn and_expr(&mut self) -> Result<TimeblockFilter, Error> {
+		let mut f = self.unary()?;
+		loop {
+			if self.is_keyword("and") {
+				self.next += 1;
+			}
+			match self.peek() {
+				None => { break }
+				Some(&Token { kind: Kind::Close, .. }) => { break }
+				Some(_) if self.is_keyword("or") => { break }
+				Some(_) => { f = f.and(self.unary()?) }
+			}
+		}
+		Ok(f)
+	}
+
+	fn unary(&mut self) -> Result<TimeblockFilter, Error> {
+		let (pos, len, end) = match self.peek() {
+			Some(t) => { (t.pos, t.len, false) }
+			None => { (self.query.chars().count(), 1, true) }
+		};
+		if end {
+			return Err(error(self.query, pos, len, "Expected a search term"));
+		}
+		if self.is_keyword("not") {
+			self.next += 1;
+			return Ok(TimeblockFilter::Not(Box::new(self.unary()?)));
+		}
+		if self.is_keyword("and") || self.is_keyword("or") {
+			return Err(error(self.query, pos, len, "Expected a search term before this"));
+		}
+		self.next += 1;
+		match self.tokens[self.next-1].kind {
+			Kind::Minus => {
+				Ok(TimeblockFilter::Not(Box::new(self.unary()?)))
+			}
+			Kind::Open => {
+				let f = self.or_expr()?;
+				match self.peek() {
+					Some(&Token { kind: Kind::Close, .. }) => {
+						self.next += 1;
+						Ok(f)
+					}
+					Some(t) => { Err(error(self.query, t.pos, t.len, "Expected )")) }
+					None => { Err(error(self.query, pos, len, "Unclosed (")) }
+				}
+			}
+			Kind::Close => {
+				Err(error(self.query, pos, len, "Unexpected )"))
+			}
+			Kind::Word(ref w, quoted) => {
+				self.term(w, quoted).map_err(|e| error(self.query, pos, len, &e))
+			}
+		}
+	}
+
+	/// The projects whose full name matches `pattern`, in id order.
+	fn projects(&self, pattern: &str) -> Vec<DbId> {
+		let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+		let mut eids: Vec<DbId> = self.ctx.fqns.iter().filter(|&(_, f)| {
+			glob(&pattern, &f.to_lowercase().chars().collect::<Vec<char>>())
+		}).map(|(&e, _)| e).collect();
+		eids.sort();
+		eids
+	}
+
+	fn term(&self, word: &str, quoted: bool) -> Result<TimeblockFilter, String> {
+		if !quoted {
+			match word.to_lowercase().as_str() {
+				"billable" => { return Ok(TimeblockFilter::Billable(true)) }
+				"open" => { return Ok(TimeblockFilter::Open(true)) }
+				"closed" => { return Ok(TimeblockFilter::Open(false)) }
+				_ => { }
+			}
+		}
+		let (key, value) = match word.find(':') {
+			Some(i) if !quoted || !word[..i].contains(' ') => { (word[..i].to_lowercase(), &word[i+1..]) }
+			_ => { return Ok(TimeblockFilter::NotesContain(word.to_string())) }
+		};
+		if value.is_empty() {
+			return Err(format!("Missing value for {}:", key));
+		}
+		let far = self.ctx.now + Duration::days(365*1000);
+		let epoch = self.ctx.now - Duration::days(365*1000);
+		match key.as_str() {
+			"project" | "p" => {
+				if let Some(root) = value.strip_suffix("/**") {
+					let subtrees = self.projects(root).into_iter().map(|e| TimeblockFilter::ProjectSubtree(ProjectRef::EId(e)));
+					return Ok(subtrees.reduce(|a, b| a.or(b)).unwrap_or(TimeblockFilter::ProjectIn(vec![])));
+				}
+				Ok(TimeblockFilter::ProjectIn(self.projects(value)))
+			}
+			"tag" | "t" => { Ok(TimeblockFilter::Tag(value.to_string())) }
+			"notes" => { Ok(TimeblockFilter::NotesContain(value.to_string())) }
+			"id" => {
+				let eid = value.parse::<DbId>().map_err(|e| format!("Invalid id: {}", e))?;
+				Ok(TimeblockFilter::Ref(TimeblockRef::EId(eid)))
+			}
+			"since" => {
+				let (from, _) = span(value, self.ctx.now)?;
+				Ok(TimeblockFilter::Between(from, far))
+			}
+			"until" => {
+				let (_, to) = span(value, self.ctx.now)?;
+				Ok(TimeblockFilter::Between(epoch, to))
+			}
+			"on" => {
+				let (from, to) = span(value, self.ctx.now)?;
+				Ok(TimeblockFilter::Between(from, to))
+			}
+			_ => { Err(format!("Unknown search key: {}", key)) }
+		}
+	}
+}
+
+/// Parses `query` into a filter; an empty query gives `None`.
+pub fn parse(query: &str, ctx: &Context) -> Result<Option<TimeblockFilter>, Error> {
+	let tokens = tokenize(query)?;
+	if tokens.is_empty() {
+		return Ok(None);
+	}
+	let mut p = Parser { query, tokens, next: 0, ctx };
+	let f = p.or_expr()?;
+	match p.peek() {
+		Some(t) => { Err(error(query, t.pos, t.len, "Unexpected )")) }
+		None => { Ok(Some(f)) }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::{ ProjectDataSource, TimeblockDataSource, Origin, db, projects };
+	use super::super::rusqlite::Connection;
+
+	fn kinds(query: &str) -> Vec<Kind> {
+		tokenize(query).unwrap().into_iter().map(|t| t.kind).collect()
+	}
+
+	fn word(w: &str, quoted: bool) -> Kind {
+		Kind::Word(w.to_string(), quoted)
+	}
+
+	#[test]
+	fn tokenizes_words_quotes_and_groups() {
+		assert_eq!(kinds("(a -b) notes:\"weekly sync\" - c"), vec![
+			Kind::Open, word("a", false), Kind::Minus, word("b", false), Kind::Close,
+			word("notes:weekly sync", true), word("-", false), word("c", false),
+		]);
+		let t = tokenize("x  since:monday").unwrap();
+		assert_eq!((t[1].pos, t[1].len), (3, 12));
+		assert!(tokenize("notes:\"open").is_err());
+		assert!(kinds("  ").is_empty());
+	}
+
+	fn matches(pattern: &str, s: &str) -> bool {
+		glob(&pattern.chars().collect::<Vec<_>>(), &s.chars().collect::<Vec<_>>())
+	}
+
+	#[test]
+	fn globs_stars_and_single_characters() {
+		assert!(matches("acme/*", "acme/web/api"));
+		assert!(!matches("acme/*", "acme"));
+		assert!(matches("*/web", "acme/web"));
+		assert!(matches("a?me", "acme"));
+		assert!(!matches("a?me", "ame"));
+		assert!(matches("*", ""));
+		assert!(matches("a**b*", "axxbyy"));
+		assert!(!matches("a*b", "axxbyy"));
+	}
+
+	#[test]
+	fn globs_many_stars_in_linear_passes() {
+		let s = "a".repeat(200);
+		assert!(!matches(&format!("{}b", "a*".repeat(30)), &s));
+		assert!(matches(&"a*".repeat(30), &s));
+	}
+
+	/// Acme, Acme/Web and Other, with a timeblock on each on 2020-01-01.
+	fn tracked() -> (Connection, Context) {
+		let conn = db::memory();
+		{
+			let psrc: &dyn ProjectDataSource = &conn;
+			let tbsrc: &dyn TimeblockDataSource = &conn;
+			let acme = psrc.upsert("Acme".to_string(), "/projects/1".to_string(), None, true).unwrap();
+			let web = psrc.upsert("Web".to_string(), "/projects/2".to_string(), Some(acme.ev.eid), true).unwrap();
+			let other = psrc.upsert("Other".to_string(), "/projects/3".to_string(), None, true).unwrap();
+			let start = "2020-01-01T09:00:00Z".parse::<DateTime<Utc>>().unwrap();
+			for &(p, billable, notes, tag) in [(&acme, true, "planning", "meeting"), (&web, false, "weekly sync", "meeting:standup"), (&other, false, "coding", "dev")].iter() {
+				tbsrc.upsert(None, None, ProjectRef::EId(p.ev.eid), start, Some(start + Duration::hours(1)), billable, notes.to_string(), vec![tag.to_string()], vec![], true, Origin::User).unwrap();
+			}
+		}
+		let fqns = projects::fqns(&(&conn as &dyn ProjectDataSource).list(None).unwrap());
+		(conn, Context { fqns, now: "2020-01-02T12:00:00Z".parse::<DateTime<Utc>>().unwrap() })
+	}
+
+	fn notes(conn: &Connection, ctx: &Context, query: &str) -> Vec<String> {
+		let tbsrc: &dyn TimeblockDataSource = conn;
+		let mut v: Vec<String> = tbsrc.search(parse(query, ctx).unwrap(), None).unwrap().into_iter().map(|tb| tb.notes).collect();
+		v.sort();
+		v
+	}
+
+	#[test]
+	fn filters_timeblocks_by_each_term() {
+		let (conn, ctx) = tracked();
+		assert_eq!(notes(&conn, &ctx, ""), vec!["coding", "planning", "weekly sync"]);
+		assert_eq!(notes(&conn, &ctx, "project:acme/*"), vec!["weekly sync"]);
+		assert_eq!(notes(&conn, &ctx, "p:ACME/**"), vec!["planning", "weekly sync"]);
+		assert_eq!(notes(&conn, &ctx, "project:nothing/**"), Vec::<String>::new());
+		assert_eq!(notes(&conn, &ctx, "tag:meeting"), vec!["planning", "weekly sync"]);
+		assert_eq!(notes(&conn, &ctx, "billable"), vec!["planning"]);
+		assert_eq!(notes(&conn, &ctx, "notes:\"weekly sync\""), vec!["weekly sync"]);
+		assert_eq!(notes(&conn, &ctx, "coding"), vec!["coding"]);
+		assert_eq!(notes(&conn, &ctx, "on:2020-01-01T09:30:00Z"), vec!["coding", "planning", "weekly sync"]);
+		assert_eq!(notes(&conn, &ctx, "since:today"), Vec::<String>::new());
+		assert_eq!(notes(&conn, &ctx, "closed"), vec!["coding", "planning", "weekly sync"]);
+	}
+
+	#[test]
+	fn combines_terms_with_and_or_not_and_groups() {
+		let (conn, ctx) = tracked();
+		assert_eq!(notes(&conn, &ctx, "tag:meeting -billable"), vec!["weekly sync"]);
+		assert_eq!(notes(&conn, &ctx, "tag:meeting and not billable"), vec!["weekly sync"]);
+		assert_eq!(notes(&conn, &ctx, "billable or tag:dev"), vec!["coding", "planning"]);
+		assert_eq!(notes(&conn, &ctx, "-(billable or tag:dev)"), vec!["weekly sync"]);
+		assert_eq!(notes(&conn, &ctx, "tag:meeting (billable OR sync)"), vec!["planning", "weekly sync"]);
+	}
+
+	#[test]
+	fn points_at_what_it_cannot_parse() {
+		let (_, ctx) = tracked();
+		let message = |query: &str| -> String {
+			match parse(query, &ctx) {
+				Err(Error::Usage(m)) => { m }
+				_ => { panic!("expected a usage error for {}", query) }
+			}
+		};
+		assert_eq!(message("tag:a foo:bar"), "Unknown search key: foo\n  tag:a foo:bar\n        ^^^^^^^");
+		assert!(message("(billable").starts_with("Unclosed ("));
+		assert!(message("billable)").starts_with("Unexpected )"));
+		assert!(message("or billable").starts_with("Expected a search term before this"));
+		assert!(message("billable or").starts_with("Expected a search term"));
+		assert!(message("since:someday").starts_with("Invalid date: someday"));
+		assert!(message("id:x").starts_with("Invalid id"));
+		assert!(message("tag:").starts_with("Missing value for tag:"));
+	}
+}
@@ -0,0 +1,363 @@
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{ BufRead, Write };
+
+use termion;
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+
+use super::cli;
+use super::complete;
+use super::std;
+use super::{ TimeTracker, Error };
+use super::dispatch;
+
+const PROMPT: &str = "tt> ";
+const HISTORY_FILE: &str = ".tt_history";
+const HISTORY_SIZE: usize = 1000;
+
+/// Splits a command line into words, honouring single quotes, double quotes and backslash escapes.
+///
+/// Backslashes only escape whitespace, quotes and backslashes, so `\/` in project names is kept.
+pub fn split_words(line: &str) -> Result<Vec<String>, Error> {
+	let mut out = Vec::new();
+	let mut cur: Option<String> = None;
+	let mut chars = line.chars().peekable();
+	while let Some(c) = chars.next() {
+		match c {
+			'\'' => {
+				let w = cur.get_or_insert_with(String::new);
+				loop {
+					match chars.next() {
+						Some('\'') => { break }
+						Some(x) => { w.push(x) }
+						None => { return Err(Error::Usage("Unterminated quote".to_string())) }
+					}
+				}
+			}
+			'"' => {
+				let w = cur.get_or_insert_with(String::new);
+				loop {
+					match chars.next() {
+						Some('"') => { break }
+						Some('\\') if chars.peek() == Some(&'"') || chars.peek() == Some(&'\\') => {
+							w.push(chars.next().unwrap_or('\\'));
+						}
+						Some(x) => { w.push(x) }
+						None => { return Err(Error::Usage("Unterminated quote".to_string())) }
+					}
+				}
+			}
+			'\\' if chars.peek().is_some_and(|x| x.is_whitespace() || *x == '\'' || *x == '"' || *x == '\\') => {
+				let x = chars.next().unwrap_or('\\');
+				cur.get_or_insert_with(String::new).push(x);
+			}
+			x if x.is_whitespace() => {
+				out.extend(cur.take());
+			}
+			x => {
+				cur.get_or_insert_with(String::new).push(x);
+			}
+		}
+	}
+	out.extend(cur.take());
+	Ok(out)
+}
+
+/// Quotes `s` so that `split_words` reads it back as one word.
+fn quote(s: &str) -> String {
+	if s.is_empty() || s.chars().any(|c| c.is_whitespace() || c == '\'' || c == '"') {
+		format!("\"{}\"", s.replace("\\", "\\\\").replace("\"", "\\\""))
+	} else {
+		s.to_string()
+	}
+}
+
+/// Where the word under the cursor starts, and whether it is the first word on the line.
+fn current_word(line: &[char]) -> (usize, bool) {
+	let mut start = 0;
+	let mut words = 0;
+	let mut quote: Option<char> = None;
+	for (i, &c) in line.iter().enumerate() {
+		match quote {
+			Some(q) if c == q => { quote = None }
+			Some(_) => { }
+			None if c == '"' || c == '\'' => { quote = Some(c) }
+			None if c.is_whitespace() => {
+				if i > start {
+					words += 1;
+				}
+				start = i+1;
+			}
+			None => { }
+		}
+	}
+	(start, words == 0)
+}
+
+fn common_prefix(v: &[String]) -> String {
+	let mut prefix: Vec<char> = v[0].chars().collect();
+	for s in v.iter().skip(1) {
+		let n = prefix.iter().zip(s.chars()).take_while(|&(a, b)| a.to_lowercase().eq(b.to_lowercase())).count();
+		prefix.truncate(n);
+	}
+	prefix.into_iter().collect()
+}
+
+/// Reads a line from the terminal with editing, history and tab completion.
+///
+/// Returns `None` at the end of input.
+fn read_line(s: &dyn TimeTracker, history: &[String]) -> Result<Option<String>, Error> {
+	let mut out = io::stdout().into_raw_mode()?;
+	let mut line: Vec<char> = Vec::new();
+	let mut pos = 0;
+	let mut hist = history.len();
+	let mut draft: Vec<char> = Vec::new();
+	let stdin = io::stdin();
+	let mut keys = stdin.lock().keys();
+	loop {
+		let text: String = line.iter().collect();
+		write!(out, "\r{}{}{}\r", termion::clear::CurrentLine, PROMPT, text)?;
+		let col = PROMPT.len() + pos;
+		if col > 0 {
+			write!(out, "{}", termion::cursor::Right(col as u16))?;
+		}
+		out.flush()?;
+
+		match keys.next() {
+			None => {
+				write!(out, "\r\n")?;
+				return Ok(None);
+			}
+			Some(Err(e)) => {
+				return Err(Error::IOError(e));
+			}
+			Some(Ok(key)) => {
+				match key {
+					Key::Char('\n') => {
+						write!(out, "\r\n")?;
+						return Ok(Some(text));
+					}
+					Key::Ctrl('d') if line.is_empty() => {
+						write!(out, "\r\n")?;
+						return Ok(None);
+					}
+					Key::Ctrl('c') => {
+						write!(out, "^C\r\n")?;
+						return Ok(Some("".to_string()));
+					}
+					Key::Char('\t') => {
+						let (start, first) = current_word(&line[..pos]);
+						let word: String = line[start..pos].iter().collect();
+						let mut words = split_words(&line[..start].iter().collect::<String>()).unwrap_or(vec![]);
+						words.push(word.clone());
+						let mut found = complete::candidates(s, &words)?;
+						if first && "exit".starts_with(word.as_str()) {
+							found.push("exit".to_string());
+						}
+						let word: String = word.chars().filter(|&c| c != '"' && c != '\'').collect();
+						let replacement = match found.len() {
+							0 => { None }
+							1 => { Some(format!("{} ", quote(&found[0]))) }
+							_ => {
+								let prefix = common_prefix(&found);
+								if prefix.chars().count() > word.chars().count() {
+									Some(prefix)
+								} else {
+									write!(out, "\r\n{}\r\n", found.join("  "))?;
+									None
+								}
+							}
+						};
+						if let Some(r) = replacement {
+							let r: Vec<char> = r.chars().collect();
+							let n = r.len();
+							line.splice(start..pos, r);
+							pos = start + n;
+						}
+					}
+					Key::Char(c) => {
+						line.insert(pos, c);
+						pos += 1;
+					}
+					Key::Backspace | Key::Ctrl('h') if pos > 0 => {
+						pos -= 1;
+						line.remove(pos);
+					}
+					Key::Delete | Key::Ctrl('d') if pos < line.len() => {
+						line.remove(pos);
+					}
+					Key::Left | Key::Ctrl('b') if pos > 0 => {
+						pos -= 1;
+					}
+					Key::Right | Key::Ctrl('f') if pos < line.len() => {
+						pos += 1;
+					}
+					Key::Home | Key::Ctrl('a') => {
+						pos = 0;
+					}
+					Key::End | Key::Ctrl('e') => {
+						pos = line.len();
+					}
+					Key::Ctrl('u') => {
+						line.drain(..pos);
+						pos = 0;
+					}
+					Key::Ctrl('k') => {
+						line.truncate(pos);
+					}
+					Key::Ctrl('w') => {
+						let mut start = pos;
+						while start > 0 && line[start-1].is_whitespace() {
+							start -= 1;
+						}
+						while start > 0 && !line[start-1].is_whitespace() {
+							start -= 1;
+						}
+						line.drain(start..pos);
+						pos = start;
+					}
+					Key::Up | Key::Ctrl('p') if hist > 0 => {
+						if hist == history.len() {
+							draft = line.clone();
+						}
+						hist -= 1;
+						line = history[hist].chars().collect();
+						pos = line.len();
+					}
+					Key::Down | Key::Ctrl('n') if hist < history.len() => {
+						hist += 1;
+						line = if hist == history.len() { draft.clone() } else { history[hist].chars().collect() };
+						pos = line.len();
+					}
+					_ => { }
+				}
+			}
+		}
+	}
+}
+
+fn history_path() -> Option<std::path::PathBuf> {
+	std::env::home_dir().map(|mut p| {
+		p.push(HISTORY_FILE);
+		p
+	})
+}
+
+/// Runs one command line; returns false when the shell should exit.
+fn execute(s: &dyn TimeTracker, line: &str) -> bool {
+	let words = match split_words(line) {
+		Ok(w) => { w }
+		Err(e) => {
+			eprintln!("error: {}", e);
+			return true;
+		}
+	};
+	match words.first().map(|w| w.as_str()) {
+		None => { return true }
+		Some("exit") | Some("quit") => { return false }
+		Some("shell") => {
+			eprintln!("error: Already in the shell");
+			return true;
+		}
+		_ => { }
+	}
+	let args = std::iter::once("tt".to_string()).chain(words);
+	match cli::build_cli().get_matches_from_safe(args) {
+		Ok(m) => {
+			match dispatch(&m, s) {
+				Ok(_) | Err(Error::Idle) => { }
+				Err(e) => {
+					eprintln!("error: {}", e);
+				}
+			}
+		}
+		Err(e) => {
+			eprintln!("{}", e.message);
+		}
+	}
+	true
+}
+
+/// Reads commands until `exit` or the end of input, sharing one database connection and backend.
+///
+/// Commands are read from the terminal with history and completion, or line by line when
+/// standard input is not a terminal.
+pub fn run(s: &dyn TimeTracker) -> Result<(), Error> {
+	if !termion::is_tty(&io::stdin()) {
+		let stdin = io::stdin();
+		for line in stdin.lock().lines() {
+			if !execute(s, &line?) {
+				break;
+			}
+		}
+		return Ok(());
+	}
+
+	let path = history_path();
+	let mut history: Vec<String> = match path.as_ref().and_then(|p| std::fs::File::open(p).ok()) {
+		Some(f) => { io::BufReader::new(f).lines().map_while(|l| l.ok()).collect() }
+		None => { Vec::new() }
+	};
+	let skip = history.len().saturating_sub(HISTORY_SIZE);
+	history.drain(..skip);
+
+	while let Some(line) = read_line(s, &history)? {
+		let line = line.trim().to_string();
+		if line.is_empty() {
+			continue;
+		}
+		if history.last() != Some(&line) {
+			history.push(line.clone());
+			if let Some(ref p) = path {
+				if let Ok(mut f) = OpenOptions::new().create(true).append(true).open(p) {
+					writeln!(f, "{}", line)?;
+				}
+			}
+		}
+		if !execute(s, &line) {
+			break;
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn split(line: &str) -> Vec<String> {
+		split_words(line).unwrap()
+	}
+
+	#[test]
+	fn splits_on_whitespace_outside_quotes() {
+		assert_eq!(split("  punchin   Acme "), vec!["punchin", "Acme"]);
+		assert_eq!(split("punchin 'Acme Corp/Web'"), vec!["punchin", "Acme Corp/Web"]);
+		assert_eq!(split("log --notes \"say \\\"hi\\\" \\\\ bye\""), vec!["log", "--notes", "say \"hi\" \\ bye"]);
+		assert_eq!(split("'it''s' \"a\"b"), vec!["its", "ab"]);
+		assert_eq!(split("'' x"), vec!["", "x"]);
+		assert_eq!(split("'a \\ b'"), vec!["a \\ b"]);
+		assert!(split("").is_empty());
+	}
+
+	#[test]
+	fn reads_backslash_escapes() {
+		assert_eq!(split("Acme\\ Corp \\'x\\\" \\\\"), vec!["Acme Corp", "'x\"", "\\"]);
+		assert_eq!(split("C:\\temp \"a\\nb\""), vec!["C:\\temp", "a\\nb"]);
+	}
+
+	#[test]
+	fn rejects_unterminated_quotes() {
+		assert!(split_words("punchin 'Acme").is_err());
+		assert!(split_words("log \"x \\\"").is_err());
+	}
+
+	#[test]
+	fn reads_quoted_words_back() {
+		for w in ["Acme Corp", "it's", "say \"hi\"", "a\\b", "", "tab\there"].iter() {
+			assert_eq!(split(&quote(w)), vec![w.to_string()]);
+		}
+	}
+}
@@ -0,0 +1,259 @@
+use rusqlite::Connection;
+use rusqlite::types::ToSql;
+
+use super::chrono::{ DateTime, Utc };
+use super::{ Timeblock, TimeblockRef, TimeblockDataSource, Origin, DbId, Error };
+use super::{ transaction, versions };
+
+/// Separates the levels of a hierarchical tag such as `client:acme`.
+pub const SEPARATOR: char = ':';
+
+/// Trims `tags` and drops empty and repeated ones, keeping the first occurrence.
+pub fn normalize(tags: Vec<String>) -> Vec<String> {
+	let mut out: Vec<String> = Vec::new();
+	for t in tags {
+		let t = t.trim().to_string();
+		if !t.is_empty() && !out.contains(&t) {
+			out.push(t);
+		}
+	}
+	out
+}
+
+/// Whether `tag` is `pattern` or lies under it, as `client:acme` lies under `client`.
+///
+/// A pattern ending in `*` matches every tag starting with the rest of it.
+pub fn matches(tag: &str, pattern: &str) -> bool {
+	if let Some(prefix) = pattern.strip_suffix('*') {
+		return tag.starts_with(prefix);
+	}
+	tag == pattern || (tag.starts_with(pattern) && tag[pattern.len()..].starts_with(SEPARATOR))
+}
+
+/// Escapes the wildcards of a GLOB pattern.
+fn glob_escape(s: &str) -> String {
+	s.chars().map(|c| {
+		match c {
+			'*' | '?' | '[' => { format!("[{}]", c) }
+			c => { c.to_string() }
+		}
+	}).collect()
+}
+
+/// A condition on `tb` for a tag pattern as understood by `matches`, and its parameters.
+pub fn where_clause(pattern: &str) -> (String, Vec<String>) {
+	let exists = "EXISTS (SELECT 1 FROM timeblock_tag AS tt WHERE tt.timeblock_eid=tb.eid AND tt.timeblock_vid=tb.vid AND";
+	if let Some(prefix) = pattern.strip_suffix('*') {
+		(format!("{} tt.tag GLOB ?)", exists), vec![format!("{}*", glob_escape(prefix))])
+	} else {
+		(format!("{} (tt.tag=? OR tt.tag GLOB ?))", exists), vec![pattern.to_string(), format!("{}{}*", glob_escape(pattern), SEPARATOR)])
+	}
+}
+
+/// Records the tags of one timeblock version.
+pub fn insert(conn: &Connection, eid: DbId, vid: DbId, tags: &[String]) -> Result<(), Error> {
+	let mut stmt = conn.prepare("INSERT OR IGNORE INTO timeblock_tag (timeblock_eid, timeblock_vid, tag) VALUES (?, ?, ?)")?;
+	for t in tags {
+		stmt.execute(&[&eid, &vid, t])?;
+	}
+	Ok(())
+}
+
+/// Every tag on the live timeblocks as they were at `when`, with the number of timeblocks.
+pub fn list(conn: &Connection, when: Option<DateTime<Utc>>) -> Result<Vec<(String, i64)>, Error> {
+	let (tbs, args) = versions("timeblock", "tb", when);
+	let mut stmt = conn.prepare(&format!("
+		SELECT tt.tag, COUNT(*) FROM {}
+		INNER JOIN timeblock_tag AS tt ON tt.timeblock_eid=tb.eid AND tt.timeblock_vid=tb.vid
+		WHERE tb.alive
+		GROUP BY tt.tag ORDER BY tt.tag
+	", tbs))?;
+	let rows = stmt.query_map(&args.iter().map(|a| a as &dyn ToSql).collect::<Vec<_>>(), |row| (row.get(0), row.get(1)))?;
+	let mut out = Vec::new();
+	for r in rows {
+		out.push(r?);
+	}
+	Ok(out)
+}
+
+/// Live timeblocks with a tag matching `pattern`.
+fn tagged(conn: &Connection, pattern: &str) -> Result<Vec<Timeblock>, Error> {
+	let (tbs, _) = versions("timeblock", "tb", None);
+	let (clause, args) = where_clause(pattern);
+	let mut stmt = conn.prepare(&format!("SELECT tb.* FROM {} WHERE tb.alive AND {}", tbs, clause))?;
+	let params: Vec<&dyn ToSql> = args.iter().map(|a| a as &dyn ToSql).collect();
+	let rows = stmt.query_map(&params, Timeblock::from_row)?;
+	let mut out = Vec::new();
+	for tb in rows {
+		out.push(tb??);
+	}
+	Ok(out)
+}
+
+/// Saves a new version of `tb` with `tags`, unless they are unchanged.
+fn retag(conn: &Connection, tb: &Timeblock, tags: Vec<String>) -> Result<Option<Timeblock>, Error> {
+	let tags = normalize(tags);
+	if tags == tb.tags {
+		return Ok(None);
+	}
+	let t: &dyn TimeblockDataSource = conn;
+	Ok(Some(t.upsert(Some(TimeblockRef::EId(tb.ev.eid)), tb.remote_id.clone(), tb.project.clone(), tb.start, tb.end, tb.billable, tb.notes.clone(), tags, tb.pauses.clone(), tb.alive, Origin::User)?))
+}
+
+fn find(conn: &Connection, eid: DbId) -> Result<Timeblock, Error> {
+	let t: &dyn TimeblockDataSource = conn;
+	match t.get(TimeblockRef::EId(eid), None)? {
+		Some(tb) => { Ok(tb) }
+		None => { Err(Error::NotFound(format!("Failed finding timeblock: {}", eid))) }
+	}
+}
+
+pub fn add(conn: &Connection, eid: DbId, tags: &[String]) -> Result<Timeblock, Error> {
+	transaction(conn, || {
+		let tb = find(conn, eid)?;
+		let mut v = tb.tags.clone();
+		v.extend(tags.iter().cloned());
+		Ok(retag(conn, &tb, v)?.unwrap_or(tb))
+	})
+}
+
+pub fn remove(conn: &Connection, eid: DbId, tags: &[String]) -> Result<Timeblock, Error> {
+	transaction(conn, || {
+		let tb = find(conn, eid)?;
+		let v = tb.tags.iter().filter(|t| !tags.contains(t)).cloned().collect();
+		Ok(retag(conn, &tb, v)?.unwrap_or(tb))
+	})
+}
+
+/// Replaces `from`, and the tags under it, by `to` on every live timeblock.
+fn replace(conn: &Connection, from: &str, to: &str) -> Result<Vec<Timeblock>, Error> {
+	if from.ends_with('*') {
+		return Err(Error::Usage(format!("Cannot rename a tag pattern: {}", from)));
+	}
+	let mut out = Vec::new();
+	for tb in tagged(conn, from)? {
+		let v = tb.tags.iter().map(|t| {
+			if matches(t, from) { format!("{}{}", to, &t[from.len()..]) } else { t.clone() }
+		}).collect();
+		out.extend(retag(conn, &tb, v)?);
+	}
+	Ok(out)
+}
+
+/// Renames the tag `from`, and the tags under it, to `to`.
+pub fn rename(conn: &Connection, from: &str, to: &str) -> Result<Vec<Timeblock>, Error> {
+	transaction(conn, || {
+		if to.trim().is_empty() {
+			return Err(Error::Usage("Tags cannot be empty".to_string()));
+		}
+		let existing = list(conn, None)?;
+		if !existing.iter().any(|t| matches(&t.0, from)) {
+			return Err(Error::NotFound(format!("No such tag: {}", from)));
+		}
+		if existing.iter().any(|t| matches(&t.0, to)) {
+			return Err(Error::Usage(format!("Tag '{}' already exists; use `tt tags merge` to combine them", to)));
+		}
+		replace(conn, from, to)
+	})
+}
+
+/// Replaces each of the tags `from`, and the tags under them, by `into`.
+pub fn merge(conn: &Connection, from: &[String], into: &str) -> Result<Vec<Timeblock>, Error> {
+	transaction(conn, || {
+		if into.trim().is_empty() {
+			return Err(Error::Usage("Tags cannot be empty".to_string()));
+		}
+		let mut out = Vec::new();
+		for f in from {
+			if f != into {
+				out.extend(replace(conn, f, into)?);
+			}
+		}
+		Ok(out)
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::{ ProjectDataSource, ProjectRef, db };
+
+	fn tagged_with(conn: &Connection, tags: &[&str]) -> DbId {
+		let psrc: &dyn ProjectDataSource = conn;
+		let t: &dyn TimeblockDataSource = conn;
+		let p = psrc.upsert("Acme".to_string(), "/projects/1".to_string(), None, true).unwrap();
+		let tags = tags.iter().map(|t| t.to_string()).collect();
+		t.upsert(None, None, ProjectRef::EId(p.ev.eid), Utc::now(), None, false, "".to_string(), tags, vec![], true, Origin::User).unwrap().ev.eid
+	}
+
+	fn tags_of(conn: &Connection, eid: DbId) -> Vec<String> {
+		find(conn, eid).unwrap().tags
+	}
+
+	fn eids(conn: &Connection, pattern: &str) -> Vec<DbId> {
+		let mut v: Vec<DbId> = tagged(conn, pattern).unwrap().iter().map(|tb| tb.ev.eid).collect();
+		v.sort();
+		v
+	}
+
+	#[test]
+	fn matches_a_tag_its_children_and_prefixes() {
+		assert!(matches("client", "client"));
+		assert!(matches("client:acme", "client"));
+		assert!(!matches("clientele", "client"));
+		assert!(matches("clientele", "client*"));
+		assert!(!matches("client", "client:acme"));
+	}
+
+	#[test]
+	fn finds_tagged_timeblocks_in_the_database_alike() {
+		let conn = db::memory();
+		let a = tagged_with(&conn, &["client:acme"]);
+		let b = tagged_with(&conn, &["clientele"]);
+		let c = tagged_with(&conn, &["50%*off"]);
+		assert_eq!(eids(&conn, "client"), vec![a]);
+		assert_eq!(eids(&conn, "client*"), vec![a, b]);
+		assert_eq!(eids(&conn, "50%*"), vec![c]);
+		assert_eq!(eids(&conn, "50*"), vec![c]);
+		assert_eq!(eids(&conn, "5?%*"), Vec::<DbId>::new());
+	}
+
+	#[test]
+	fn adds_and_removes_tags_without_repeats() {
+		let conn = db::memory();
+		let eid = tagged_with(&conn, &["a"]);
+		add(&conn, eid, &[" b ".to_string(), "a".to_string(), "".to_string()]).unwrap();
+		assert_eq!(tags_of(&conn, eid), vec!["a", "b"]);
+		let vid = find(&conn, eid).unwrap().ev.vid;
+		add(&conn, eid, &["b".to_string()]).unwrap();
+		assert_eq!(find(&conn, eid).unwrap().ev.vid, vid);
+		remove(&conn, eid, &["a".to_string()]).unwrap();
+		assert_eq!(tags_of(&conn, eid), vec!["b"]);
+	}
+
+	#[test]
+	fn renames_a_tag_and_the_tags_under_it() {
+		let conn = db::memory();
+		let a = tagged_with(&conn, &["client:acme", "meeting"]);
+		let b = tagged_with(&conn, &["client", "clientele"]);
+		let changed = rename(&conn, "client", "customer").unwrap();
+		assert_eq!(changed.len(), 2);
+		assert_eq!(tags_of(&conn, a), vec!["customer:acme", "meeting"]);
+		assert_eq!(tags_of(&conn, b), vec!["customer", "clientele"]);
+
+		assert!(matches!(rename(&conn, "customer", "meeting"), Err(Error::Usage(_))));
+		assert!(matches!(rename(&conn, "nothing", "else"), Err(Error::NotFound(_))));
+		assert!(matches!(rename(&conn, "client*", "x"), Err(Error::Usage(_))));
+	}
+
+	#[test]
+	fn merges_tags_into_one() {
+		let conn = db::memory();
+		let a = tagged_with(&conn, &["mtg", "meeting"]);
+		let b = tagged_with(&conn, &["call:standup"]);
+		merge(&conn, &["mtg".to_string(), "call".to_string()], "meeting").unwrap();
+		assert_eq!(tags_of(&conn, a), vec!["meeting"]);
+		assert_eq!(tags_of(&conn, b), vec!["meeting:standup"]);
+		assert_eq!(list(&conn, None).unwrap(), vec![("meeting".to_string(), 1), ("meeting:standup".to_string(), 1)]);
+	}
+}
@@ -15,7 +15,7 @@ use futures::{
 	Stream,
 };
 
-use super::{ TimeTracker, Project, Error };
+use super::{ TimeTracker, Timeblock, Error };
 use super::std;
 use super::hyper;
 use super::serde_json;
@@ -24,12 +24,12 @@ use super::time::{ Duration };
 use super::chrono::{ 
 	DateTime,
 	Utc,
+	Local,
 };
 
 use super::tokio_core;
 
 use futures::future;
-use futures::stream;
 
 use super::ProjectDataSource;
 use super::TimeblockDataSource;
@@ -41,6 +41,24 @@ pub struct Teamwork<'a> {
 	user_id: i32
 }
 
+/// Sets up the Teamwork backend only once a sync is asked for, so local commands never prompt
+/// for its settings.
+pub struct OnDemand<'a> {
+	pub conn: &'a Connection
+}
+
+impl<'a> TimeTracker for OnDemand<'a> {
+	fn conn(&self) -> &Connection {
+		self.conn
+	}
+	fn down(&self) -> Result<(), Error> {
+		Teamwork::new(self.conn)?.down()
+	}
+	fn up(&self) -> Result<(), Error> {
+		Teamwork::new(self.conn)?.up()
+	}
+}
+
 header! { (XPage, "X-Page") => [i16] }
 header! { (XPages, "X-Pages") => [i16] }
 
@@ -52,239 +70,284 @@ impl<'a> TimeTracker for Teamwork<'a> {
 		let psrc: &dyn ProjectDataSource = self.conn;
 		let tsrc: &dyn TimeblockDataSource = self.conn;
 
-		let last_sync = match tsrc.last_sync()? {
-			Some(t) => {
-				Some(t.format("%Y%m%d"))
-			}
-			_ => {
-				None
-			}
-		};
+		let last_sync = tsrc.last_sync()?.map(|t| t.format("%Y%m%d"));
 
-		let mut core = tokio_core::reactor::Core::new().unwrap();
+		let mut core = tokio_core::reactor::Core::new()?;
 		let handle = core.handle();
 		let client = hyper::Client::configure()
 			//.connector(hyper_tls::HttpsConnector::new(4, &handle).unwrap())
 			.build(&handle);
 
+		#[derive(Deserialize, Debug)]
+		struct TeamworkProject {
+			id: String,
+			name: String
+		}
+		impl TeamworkProject {
+			fn pid(&self) -> String {
+				format!("/projects/{}", self.id)
+			}
+		}
+
+		#[derive(Deserialize, Debug)]
+		struct TeamworkProjectsResult {
+			projects: Vec<TeamworkProject>
+		}
+
 		let mut page = 1;
 		let mut num_pages = 1;
 
 		while page <= num_pages {
 			eprintln!("Teamwork.down: get project entries page {}/{}...", page, num_pages);
-			let req = match last_sync {
+			let uri = match last_sync {
 				Some(ref t) => {
-					self.get(format!("/projects.json?page={}&updatedAfterDate={}", page, t).to_string())?
+					format!("/projects.json?page={}&updatedAfterDate={}", page, t)
 				}
 				_ => {
-					self.get(format!("/projects.json?page={}", page).to_string())?
+					format!("/projects.json?page={}", page)
 				}
 			};
+			let (pages, s) = self.fetch(&mut core, &client, uri)?;
+			num_pages = pages.unwrap_or(num_pages);
+			let r = serde_json::from_str::<TeamworkProjectsResult>(&s)?;
+			for t in r.projects {
+				psrc.upsert(t.name.clone(), t.pid(), None, true)?;
+			}
+			page += 1;
+		}
 
-			let work = client.request(req).and_then(|res| {
-				assert_eq!(res.status(), hyper::Ok);
-
-				#[derive(Deserialize, Debug)]
-				struct TeamworkProject {
-					id: String,
-					name: String
-				}
-				impl TeamworkProject {
-					fn pid(&self) -> String {
-						format!("/projects/{}", self.id)
-					}
-				}
-
-				#[derive(Deserialize, Debug)]
-				struct TeamworkProjectsResult {
-					#[serde(rename="STATUS")]
-					status: String,
-					projects: Vec<TeamworkProject>
-				}
+		#[derive(Deserialize, Debug)]
+		struct TeamworkTask {
+			id: serde_json::Value,
+			#[serde(rename="content")]
+			name: String,
+			#[serde(rename="project-id")]
+			project: i32
+		}
+		impl TeamworkTask {
+			fn pid(&self) -> String {
+				format!("/tasks/{}", self.id)
+			}
+			fn ppid(&self) -> String {
+				format!("/projects/{}", self.project)
+			}
+		}
 
-				Teamwork::body(res).and_then(|s| {
-					//println!("{:?}", s);
-			 		let r = serde_json::from_str::<TeamworkProjectsResult>(&s).unwrap();
-					let x = r.projects.into_iter().map(|t| {
-						let n = t.name.clone();
-						psrc.upsert(n, t.pid(), None)
-					});
-					stream::iter_ok::<_, hyper::Error>(x).collect()
-				})
-			});
-			core.run(work).unwrap();
-			page = page+1;
+		#[derive(Deserialize, Debug)]
+		struct TeamworkTasksResult {
+			#[serde(rename="todo-items")]
+			tasks: Vec<TeamworkTask>
 		}
-		
+
 		page = 1;
 		num_pages = 1;
 
 		while page <= num_pages {
 			eprintln!("Teamwork.down: get task entries page {}/{}...", page, num_pages);
-			let req = match last_sync {
+			let uri = match last_sync {
 				Some(ref t) => {
-					self.get(format!("/tasks.json?page={}&showDeleted=yes&includeCompletedTasks=true&includeCompletedSubtasks=true&updatedAfterDate={}", page, t).to_string())?
+					format!("/tasks.json?page={}&showDeleted=yes&includeCompletedTasks=true&includeCompletedSubtasks=true&updatedAfterDate={}", page, t)
 				}
 				_ => {
-					self.get(format!("/tasks.json?page={}&showDeleted=yes&includeCompletedTasks=true&includeCompletedSubtasks=true", page).to_string())?
+					format!("/tasks.json?page={}&showDeleted=yes&includeCompletedTasks=true&includeCompletedSubtasks=true", page)
 				}
 			};
-
-			let work = client.request(req).and_then(|res| {
-				//assert_eq!(res.status(), hyper::Ok);
-				Teamwork::body(res).and_then(|s|{
-					//println!("{:?}", s);
-					#[derive(Deserialize, Debug)]
-					struct TeamworkTask {
-						id: serde_json::Value,
-						#[serde(rename="content")]
-						name: String,
-						#[serde(rename="project-id")]
-						project: i32
+			let (pages, s) = self.fetch(&mut core, &client, uri)?;
+			num_pages = pages.unwrap_or(num_pages);
+			let r = serde_json::from_str::<TeamworkTasksResult>(&s)?;
+			for t in r.tasks {
+				let pref = super::ProjectRef::RemoteId(t.ppid());
+				match psrc.get(pref, None)? {
+					Some(pp) => {
+						psrc.upsert(t.name.clone(), t.pid(), Some(pp.ev.eid), true)?;
 					}
-					impl TeamworkTask {
-						fn pid(&self) -> String {
-							format!("/tasks/{}", self.id)
-						}
-						fn ppid(&self) -> String {
-							format!("/projects/{}", self.project)
-						}
-					}
-
-					#[derive(Deserialize, Debug)]
-					struct TeamworkTasksResult {
-						#[serde(rename="STATUS")]
-						status: String,
-						#[serde(rename="todo-items")]
-						tasks: Vec<TeamworkTask>
+					_ => {
+						eprintln!("Teamwork.down: skip task {} of unknown project {}", t.id, t.project);
 					}
+				}
+			}
+			page += 1;
+		}
 
-					let r = serde_json::from_str::<TeamworkTasksResult>(&s).unwrap();
-					let x = r.tasks.into_iter().map(|t| {
-						let n = t.name.clone();
-						let pref = super::ProjectRef::RemoteId(t.ppid());
-						let p = psrc.get(pref, None)?;
-						match p {
-							Some(pp) => { 
-								psrc.upsert(n, t.pid(), Some(pp.ev.eid))
-							}
-							_ => {
-								Err(Error::TTError(format!(
-									"Failed finding project by ID for: {:?}", t
-								)))
-							}
-						}
-					});
-					stream::iter_ok::<_, hyper::Error>(x).collect()
-				})
-			});
-			core.run(work).unwrap();
-			page = page+1;
+		#[derive(Deserialize, Debug)]
+		struct TeamworkTimeEntriesResult {
+			#[serde(rename="time-entries")]
+			entries: Vec<TeamworkTimeEntry>
 		}
-		
+
 		page = 1;
 		num_pages = 1;
 
 		while page <= num_pages {
 			eprintln!("Teamwork.down: get time entries page {}/{}...", page, num_pages);
 
-			let req = match last_sync {
+			let uri = match last_sync {
 				Some(ref t) => {
-					self.get(format!("/time_entries.json?page={}&userId={}&updatedAfterDate={}", page, self.user_id, t).to_string())?
+					format!("/time_entries.json?page={}&userId={}&updatedAfterDate={}", page, self.user_id, t)
 				}
 				_ => {
-					self.get(format!("/time_entries.json?page={}&userId={}", page, self.user_id).to_string())?
+					format!("/time_entries.json?page={}&userId={}", page, self.user_id)
 				}
 			};
-
-			let work = client.request(req).and_then(|mut res| {
-				assert_eq!(res.status(), hyper::Ok);
-
-				let h = res.headers_mut().clone();
-				num_pages = match h.get::<XPages>().unwrap() {
-					&XPages(i) => { i }
-				};
-
-				#[derive(Deserialize, Debug)]
-				struct TeamworkTimeEntry {
-					id: String,
-					#[serde(rename="project-id")]
-					project_id: String,
-					#[serde(rename="todo-item-id")]
-					task_id: String,
-					minutes: serde_json::value::Value,
-					isbillable: String,
-					date: String,
-					hours: serde_json::value::Value,
-					#[serde(rename="person-last-name")]
-					blah: String,
-					#[serde(rename="todo-item-name")]
-					blah2: String,
+			let (pages, s) = self.fetch(&mut core, &client, uri)?;
+			num_pages = pages.unwrap_or(num_pages);
+			let r = serde_json::from_str::<TeamworkTimeEntriesResult>(&s)?;
+			for e in r.entries {
+				match pull(self.conn, &e) {
+					Ok(_) => { }
+					Err(Error::NotFound(x)) => {
+						eprintln!("Teamwork.down: skip time entry {}: {}", e.id, x);
+					}
+					Err(x) => { return Err(x) }
 				}
+			}
+			page += 1;
+		}
 
-				#[derive(Deserialize, Debug)]
-				struct TeamworkTimeEntriesResult {
-					#[serde(rename="STATUS")]
-					status: String,
-					#[serde(rename="time-entries")]
-					entries: Vec<TeamworkTimeEntry>
+		Ok(())
+	}
+
+	fn up(&self) -> Result<(), Error> {
+		let psrc: &dyn ProjectDataSource = self.conn;
+
+		let mut core = tokio_core::reactor::Core::new()?;
+		let handle = core.handle();
+		let client = hyper::Client::configure()
+			.build(&handle);
+
+		#[derive(Deserialize, Debug)]
+		struct TeamworkTimeLogResult {
+			#[serde(rename="timeLogId")]
+			id: String
+		}
+
+		self.split_breaks()?;
+		for (tb, d) in self.unsynced()? {
+			let proj = match psrc.get(tb.project.clone(), None)? {
+				Some(p) => { p }
+				_ => {
+					return Err(Error::TTError(format!("Failed finding project for timeblock: {:?}", tb)));
 				}
+			};
+			if proj.is_local() {
+				eprintln!("Teamwork.up: skip timeblock {} on local project {}", tb.ev.eid, proj.name);
+				continue;
+			}
+			eprintln!("Teamwork.up: push timeblock {} ({} minutes)...", tb.ev.eid, d.num_minutes());
 
-				Teamwork::body(res).and_then(|s| {
-					let r = serde_json::from_str::<TeamworkTimeEntriesResult>(&s).unwrap();
-					let x = r.entries.into_iter().map(|e| {
-						//2016-01-01T06:17:00Z
-						let start = e.date.parse::<DateTime<Utc>>().unwrap();
-						//println!("{:?}", e);
-						let end = match (e.hours, e.minutes) {
-							(Value::String(hs), Value::String(ms)) => {
-								let h = hs.parse::<i64>().unwrap();
-								let m = ms.parse::<i64>().unwrap();
-								Some(start + Duration::hours(h) + Duration::minutes(m))
-							}
-							(Value::Number(h), Value::Number(m)) => {
-								Some(start + Duration::hours(h.as_i64().unwrap()) + Duration::minutes(m.as_i64().unwrap()))
-							}
-							_ => {
-								None
-							}
-						};
-						let mut billable = false;
-						if e.isbillable == "True" {
-							billable = true;
-						}
-						let pref = if e.task_id != "" {
-							super::ProjectRef::RemoteId(format!("/tasks/{}", e.task_id))
-						} else {
-							super::ProjectRef::RemoteId(format!("/projects/{}", e.project_id))
-						};
-						let tb = tsrc.upsert(
-							None,
-							Some(e.id),
-							pref,
-							start,
-							end,
-							billable,
-							"".to_string(),
-							vec![],
-							true
-						);
-					});
-					stream::iter_ok::<_, hyper::Error>(x).collect()
-				})
+			let body = time_entry(&tb, d, self.user_id);
+			let req = self.post(format!("{}/time_entries.json", proj.remote_id), body.to_string())?;
+			let work = client.request(req).and_then(|res| {
+				let status = res.status();
+				Teamwork::body(res).map(move |s| (status, s))
 			});
-			core.run(work).unwrap();
-			page = page+1;
+			let (status, s) = core.run(work)?;
+			if !status.is_success() {
+				return Err(Error::SyncError(format!("failed pushing timeblock {}: {} {}", tb.ev.eid, status, s)));
+			}
+			let r = serde_json::from_str::<TeamworkTimeLogResult>(&s)?;
+			pushed(self.conn, &tb, r.id)?;
 		}
-
 		Ok(())
 	}
+}
 
-	fn up(&self) -> Result<(), Error> {
-		Ok(())
+#[derive(Deserialize, Debug)]
+struct TeamworkTimeEntry {
+	id: String,
+	#[serde(rename="project-id")]
+	project_id: String,
+	#[serde(rename="todo-item-id")]
+	task_id: String,
+	#[serde(default)]
+	description: String,
+	minutes: serde_json::value::Value,
+	isbillable: String,
+	date: String,
+	hours: serde_json::value::Value,
+}
+
+fn number(v: &Value) -> Option<i64> {
+	match *v {
+		Value::String(ref s) => { s.parse::<i64>().ok() }
+		Value::Number(ref n) => { n.as_i64() }
+		_ => { None }
 	}
 }
 
+/// The body that pushes `tb`, worked for `d`, as a time entry of `user_id`. Teamwork reads the
+/// date and time in the user's own time zone.
+fn time_entry(tb: &Timeblock, d: Duration, user_id: i32) -> Value {
+	let minutes = d.num_minutes();
+	let start = tb.start.with_timezone(&Local);
+	json!({"time-entry": {
+		"description": tb.notes,
+		"person-id": format!("{}", user_id),
+		"date": start.format("%Y%m%d").to_string(),
+		"time": start.format("%H:%M").to_string(),
+		"hours": format!("{}", minutes/60),
+		"minutes": format!("{}", minutes%60),
+		"isbillable": if tb.billable { "1" } else { "0" },
+		"tags": tb.tags.join(",")
+	}})
+}
+
+/// Records that `tb` was pushed as the time entry `id`.
+fn pushed(conn: &Connection, tb: &Timeblock, id: String) -> Result<(), Error> {
+	let tsrc: &dyn TimeblockDataSource = conn;
+	super::transaction(conn, || {
+		let x = tsrc.upsert(Some(super::TimeblockRef::EId(tb.ev.eid)), Some(id.clone()), tb.project.clone(), tb.start, tb.end, tb.billable, tb.notes.clone(), tb.tags.clone(), tb.pauses.clone(), tb.alive, super::Origin::Sync)?;
+		conn.execute("UPDATE timeblock_entity SET last_sync_vid=?, last_sync_time=? WHERE id=?", &[
+			&x.ev.vid,
+			&super::chrono_to_sql(x.ev.vtime),
+			&x.ev.eid
+		])?;
+		Ok(())
+	})
+}
+
+/// Stores the time entry `e` as a timeblock. Teamwork keeps no breaks, so a timeblock already
+/// known locally, such as one `up` pushed, keeps its own tags, breaks and end; its end already
+/// takes the breaks into account, which the entry's duration does not.
+fn pull(conn: &Connection, e: &TeamworkTimeEntry) -> Result<Timeblock, Error> {
+	let tsrc: &dyn TimeblockDataSource = conn;
+	//2016-01-01T06:17:00Z
+	let start = e.date.parse::<DateTime<Utc>>().map_err(|x| {
+		Error::SyncError(format!("Invalid date on time entry {}: {}", e.id, x))
+	})?;
+	let end = match (number(&e.hours), number(&e.minutes)) {
+		(Some(h), Some(m)) => {
+			Some(start + Duration::hours(h) + Duration::minutes(m))
+		}
+		_ => {
+			None
+		}
+	};
+	let billable = e.isbillable == "True";
+	let pref = if !e.task_id.is_empty() {
+		super::ProjectRef::RemoteId(format!("/tasks/{}", e.task_id))
+	} else {
+		super::ProjectRef::RemoteId(format!("/projects/{}", e.project_id))
+	};
+	let (end, tags, pauses) = match tsrc.get(super::TimeblockRef::RemoteId(e.id.clone()), None)? {
+		Some(tb) => { (tb.end, tb.tags, tb.pauses) }
+		None => { (end, vec![], vec![]) }
+	};
+	tsrc.upsert(
+		Some(super::TimeblockRef::RemoteId(e.id.clone())),
+		Some(e.id.clone()),
+		pref,
+		start,
+		end,
+		billable,
+		e.description.clone(),
+		tags,
+		pauses,
+		true,
+		super::Origin::Sync
+	)
+}
+
 impl<'a> Teamwork<'a> {
 	pub fn new(conn: &'a Connection) -> Result<Teamwork<'a>, Error> {
 		let mut stmt = conn.prepare("SELECT teamwork_api_key, teamwork_base_url, teamwork_user_id FROM metadata")?;
@@ -293,11 +356,12 @@ impl<'a> Teamwork<'a> {
 		let mut base_url = None;
 		let mut user_id: Option<i32> = None;
 
-		stmt.query_map(&[], |row| {
-			api_key = row.get(0);
-			base_url = row.get(1);
-			user_id = row.get(2);
-		}).unwrap().next();
+		if let Some(r) = stmt.query_map(&[], |row| (row.get(0), row.get(1), row.get(2)))?.next() {
+			let (a, b, c) = r?;
+			api_key = a;
+			base_url = b;
+			user_id = c;
+		}
 
 		match base_url {
 			Some(_) => { }
@@ -315,7 +379,7 @@ impl<'a> Teamwork<'a> {
 				print!("Teamwork User ID: ");
 				std::io::stdout().flush()?;
 				let s = read_password()?;
-				user_id = Some(s.parse::<i32>().unwrap());
+				user_id = Some(s.trim().parse::<i32>().map_err(|e| Error::Usage(format!("Invalid Teamwork user ID: {}", e)))?);
 			}
 		}
 
@@ -330,9 +394,10 @@ impl<'a> Teamwork<'a> {
 			}
 		}
 		
-		let a = api_key.unwrap();
-		let b = base_url.unwrap();
-		let c = user_id.unwrap();
+		let (a, b, c) = match (api_key, base_url, user_id) {
+			(Some(a), Some(b), Some(c)) => { (a, b, c) }
+			_ => { return Err(Error::TTError("Teamwork is not configured".to_string())) }
+		};
 				
 		conn.execute("UPDATE metadata SET teamwork_api_key=?, teamwork_base_url=?, teamwork_user_id=?", &[
 			&a,
@@ -341,22 +406,47 @@ impl<'a> Teamwork<'a> {
 		])?;
 
 		Ok(Teamwork {
-			conn: conn,
+			conn,
 			api_key: a.clone(),
 			base_url: b.clone(),
-			user_id: c.clone(),
+			user_id: c,
 		})
 	}
 
+	/// Runs a GET request for `uri`, returning the `X-Pages` header, if any, and the body.
+	fn fetch(&self, core: &mut tokio_core::reactor::Core, client: &hyper::Client<hyper::client::HttpConnector>, uri: String) -> Result<(Option<i16>, String), Error> {
+		let req = self.get(uri.clone())?;
+		let work = client.request(req).and_then(|res| {
+			let status = res.status();
+			let pages = res.headers().get::<XPages>().map(|&XPages(i)| i);
+			Teamwork::body(res).map(move |s| (status, pages, s))
+		});
+		let (status, pages, s) = core.run(work)?;
+		if !status.is_success() {
+			return Err(Error::SyncError(format!("GET {} returned {}", uri, status)));
+		}
+		Ok((pages, s))
+	}
+
 	pub fn get(&self, uri: String) -> Result<hyper::Request, hyper::Error> {
-		println!("GET {}", uri);
+		self.request(Method::Get, uri)
+	}
+
+	pub fn post(&self, uri: String, body: String) -> Result<hyper::Request, hyper::Error> {
+		let mut req = self.request(Method::Post, uri)?;
+		req.headers_mut().set(hyper::header::ContentLength(body.len() as u64));
+		req.set_body(body);
+		Ok(req)
+	}
+
+	fn request(&self, method: Method, uri: String) -> Result<hyper::Request, hyper::Error> {
 		let hdr = hyper::header::Basic {
 			username: self.api_key.clone(),
 			password: Some("xxx".to_string())
 		};
 
 		let uri = format!("{}{}", self.base_url.clone(), uri).parse()?;
-		let mut req = Request::new(Method::Get, uri);
+		let mut req = Request::new(method, uri);
 		req.headers_mut().set(hyper::header::Accept::json());
 		req.headers_mut().set(hyper::header::ContentType::json());
 		req.headers_mut().set(hyper::header::Authorization(hdr.clone()));
@@ -368,8 +458,60 @@ impl<'a> Teamwork<'a> {
 			v.extend(&chunk[..]);
 			future::ok::<_, hyper::Error>(v)
 		}).and_then(|chunks| {
-			let s = String::from_utf8(chunks).unwrap().clone();
+			let s = String::from_utf8_lossy(&chunks).into_owned();
 			future::ok::<_, hyper::Error>(s)
 		}))
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::db;
+	use super::super::{ ProjectRef, Origin };
+
+	fn at(s: &str) -> DateTime<Utc> {
+		s.parse::<DateTime<Utc>>().unwrap()
+	}
+
+	fn entry(id: &str, description: &str) -> TeamworkTimeEntry {
+		serde_json::from_value(json!({
+			"id": id,
+			"project-id": "1",
+			"todo-item-id": "",
+			"description": description,
+			"hours": "2",
+			"minutes": "30",
+			"isbillable": "True",
+			"date": "2020-01-01T09:00:00Z"
+		})).unwrap()
+	}
+
+	#[test]
+	fn pulling_a_pushed_entry_keeps_its_local_fields() {
+		let conn = db::memory();
+		let psrc: &dyn ProjectDataSource = &conn;
+		let tbsrc: &dyn TimeblockDataSource = &conn;
+		let p = psrc.upsert("Acme".to_string(), "/projects/1".to_string(), None, true).unwrap();
+		let pauses = vec![(at("2020-01-01T10:00:00Z"), Some(at("2020-01-01T10:30:00Z")))];
+		let tb = tbsrc.upsert(None, None, ProjectRef::EId(p.ev.eid), at("2020-01-01T09:00:00Z"), Some(at("2020-01-01T12:00:00Z")), true, "Design".to_string(), vec!["a".to_string()], pauses.clone(), true, Origin::User).unwrap();
+
+		let body = time_entry(&tb, Duration::minutes(150), 7);
+		let start = tb.start.with_timezone(&Local);
+		assert_eq!(body["time-entry"]["date"], json!(start.format("%Y%m%d").to_string()));
+		assert_eq!(body["time-entry"]["time"], json!(start.format("%H:%M").to_string()));
+		assert_eq!((&body["time-entry"]["hours"], &body["time-entry"]["minutes"]), (&json!("2"), &json!("30")));
+		pushed(&conn, &tb, "42".to_string()).unwrap();
+
+		let x = pull(&conn, &entry("42", "Design")).unwrap();
+		assert_eq!((x.ev.eid, x.ev.vid), (tb.ev.eid, 1));
+		assert_eq!(x.end, Some(at("2020-01-01T12:00:00Z")));
+		assert_eq!((x.notes, x.tags, x.pauses), ("Design".to_string(), vec!["a".to_string()], pauses));
+
+		let y = pull(&conn, &entry("43", "Review")).unwrap();
+		assert_ne!(y.ev.eid, tb.ev.eid);
+		assert_eq!(y.remote_id, Some("43".to_string()));
+		assert_eq!(y.end, Some(at("2020-01-01T11:30:00Z")));
+		assert_eq!(y.notes, "Review");
+	}
+}
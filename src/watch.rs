@@ -0,0 +1,276 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+use std::sync::mpsc;
+use std::thread;
+
+use termion;
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+use termion::screen::AlternateScreen;
+
+use super::chrono::{ Local, Utc };
+use super::time::Duration;
+use super::picker;
+use super::projects;
+use super::{ TimeTracker, Timeblock, TimeblockRef, TimeblockFilter, TimeblockDataSource, ProjectDataSource, Origin, DbId, Error };
+use super::{ net_duration, is_paused, period_bounds, format_duration, project_choices };
+
+const RECENT: usize = 10;
+const HELP: &str = "[s]top  [S]top all  [w] switch  [i] start  [p]ause/resume  [n]otes  [q]uit";
+
+/// Everything shown on one frame of the dashboard.
+struct Frame {
+	open: Vec<(Timeblock, Duration, bool)>,
+	names: HashMap<DbId, String>,
+	today: HashMap<DbId, Duration>,
+	week: HashMap<DbId, Duration>,
+	recent: Vec<Timeblock>,
+}
+
+fn frame(s: &dyn TimeTracker) -> Result<Frame, Error> {
+	let conn = s.conn();
+	let psrc: &dyn ProjectDataSource = conn;
+	let tbsrc: &dyn TimeblockDataSource = conn;
+	let now = Utc::now();
+	let mut open = Vec::new();
+	for tb in s.open_timeblocks()? {
+		let paused = is_paused(&tb, now);
+		let d = net_duration(&tb, now);
+		open.push((tb, d, paused));
+	}
+	open.sort_by_key(|x| x.0.start);
+	let (today_from, today_to) = period_bounds("today", now)?;
+	let (week_from, week_to) = period_bounds("week", now)?;
+	let mut recent: Vec<Timeblock> = tbsrc.search(Some(TimeblockFilter::Open(false)), None)?.into_iter().filter(|tb| tb.alive).collect();
+	recent.sort_by_key(|tb| Reverse(tb.start));
+	recent.truncate(RECENT);
+	Ok(Frame {
+		open,
+		names: projects::fqns(&psrc.list(None)?),
+		today: projects::totals(conn, today_from, today_to, None, None)?,
+		week: projects::totals(conn, week_from, week_to, None, None)?,
+		recent,
+	})
+}
+
+fn project_name(f: &Frame, tb: &Timeblock) -> String {
+	tb.project_eid().and_then(|eid| f.names.get(&eid).cloned()).unwrap_or("?".to_string())
+}
+
+/// The size of the terminal as (columns, rows), or 80 by 24 when it does not say.
+fn screen_size() -> Result<(usize, usize), Error> {
+	match picker::terminal_size()? {
+		(0, _) | (_, 0) => { Ok((80, 24)) }
+		(w, h) => { Ok((w as usize, h as usize)) }
+	}
+}
+
+fn draw(out: &mut dyn Write, f: &Frame, selected: usize, message: &str, (width, height): (usize, usize)) -> Result<(), Error> {
+	let mut lines = Vec::new();
+	let sum = |m: &HashMap<DbId, Duration>| m.values().fold(Duration::zero(), |a, &b| a + b);
+	lines.push(format!("tt watch  {}  today {}  week {}", Local::now().format("%Y-%m-%d %H:%M:%S"), format_duration(sum(&f.today)), format_duration(sum(&f.week))));
+	lines.push("".to_string());
+
+	lines.push("OPEN".to_string());
+	if f.open.is_empty() {
+		lines.push("  no timer running".to_string());
+	}
+	for (i, &(ref tb, d, paused)) in f.open.iter().enumerate() {
+		lines.push(format!("{} {}  {}  {}  {}", if i == selected { ">" } else { " " }, format_duration(d), if paused { "paused " } else { "running" }, project_name(f, tb), tb.notes));
+	}
+	lines.push("".to_string());
+
+	lines.push(format!("{:<10}{:<10}PROJECT", "TODAY", "WEEK"));
+	let mut totals: Vec<(&DbId, &Duration)> = f.week.iter().filter(|x| *x.1 > Duration::zero()).collect();
+	totals.sort_by(|a, b| b.1.cmp(a.1));
+	for (eid, week) in totals {
+		let today = f.today.get(eid).cloned().unwrap_or(Duration::zero());
+		lines.push(format!("{:<10}{:<10}{}", format_duration(today), format_duration(*week), f.names.get(eid).cloned().unwrap_or("?".to_string())));
+	}
+	lines.push("".to_string());
+
+	lines.push("RECENT".to_string());
+	for tb in f.recent.iter() {
+		let end = tb.end.map(|e| e.with_timezone(&Local).format("%H:%M").to_string()).unwrap_or("".to_string());
+		lines.push(format!("{}-{}  {}  {}", tb.start.with_timezone(&Local).format("%a %H:%M"), end, project_name(f, tb), tb.notes));
+	}
+
+	let body = height.saturating_sub(2);
+	write!(out, "{}", termion::cursor::Goto(1, 1))?;
+	for line in lines.iter().take(body) {
+		let line: String = line.chars().take(width).collect();
+		write!(out, "{}{}\r\n", line, termion::clear::UntilNewline)?;
+	}
+	write!(out, "{}", termion::clear::AfterCursor)?;
+	let footer: String = if message.is_empty() { HELP.to_string() } else { message.to_string() };
+	write!(out, "{}{}", termion::cursor::Goto(1, height as u16), footer.chars().take(width).collect::<String>())?;
+	out.flush()?;
+	Ok(())
+}
+
+/// Carries out the action bound to `key` on a screen of `height` rows, returning a message for
+/// the footer.
+fn act(s: &dyn TimeTracker, key: Key, f: &Frame, current: Option<&Timeblock>, rx: &mpsc::Receiver<io::Result<Key>>, height: usize, out: &mut dyn Write) -> Result<String, Error> {
+	match key {
+		Key::Char('s') => {
+			match current {
+				Some(tb) => {
+					let psrc: &dyn ProjectDataSource = s.conn();
+					match psrc.get(tb.project.clone(), None)? {
+						Some(p) => { s.punchout(Some(&p)).map(|_| format!("Stopped {}", project_name(f, tb))) }
+						None => { Ok("".to_string()) }
+					}
+				}
+				None => { Ok("No timer running".to_string()) }
+			}
+		}
+		Key::Char('S') => {
+			s.punchout(None).map(|v| format!("Stopped {} timer(s)", v.len()))
+		}
+		Key::Char('w') | Key::Char('i') => {
+			let choices = project_choices(s)?;
+			let fqns: Vec<String> = choices.iter().map(|p| p.1.clone()).collect();
+			let prompt = if key == Key::Char('w') { "switch to " } else { "start " };
+			write!(out, "{}", termion::cursor::Show)?;
+			let i = picker::pick_from(prompt, &fqns, height, &mut rx.iter(), out)?;
+			write!(out, "{}{}", termion::cursor::Hide, termion::clear::All)?;
+			match i {
+				Some(i) if key == Key::Char('w') => { s.switch(&choices[i].0).map(|_| format!("Switched to {}", fqns[i])) }
+				Some(i) => { s.punchin(&choices[i].0, &[]).map(|_| format!("Started {}", fqns[i])) }
+				None => { Ok("".to_string()) }
+			}
+		}
+		Key::Char('p') => {
+			if f.open.iter().any(|x| !x.2) {
+				s.pause().map(|_| "Paused".to_string())
+			} else {
+				s.resume().map(|_| "Resumed".to_string())
+			}
+		}
+		Key::Char('n') => {
+			match current {
+				Some(tb) => {
+					let notes = picker::input("notes: ", &tb.notes, &mut rx.iter(), out)?;
+					write!(out, "{}{}", termion::cursor::Hide, termion::clear::All)?;
+					match notes {
+						Some(notes) => {
+							let tbsrc: &dyn TimeblockDataSource = s.conn();
+							tbsrc.upsert(Some(TimeblockRef::EId(tb.ev.eid)), tb.remote_id.clone(), tb.project.clone(), tb.start, tb.end, tb.billable, notes, tb.tags.clone(), tb.pauses.clone(), tb.alive, Origin::User).map(|_| "Notes saved".to_string())
+						}
+						None => { Ok("".to_string()) }
+					}
+				}
+				None => { Ok("No timer running".to_string()) }
+			}
+		}
+		_ => { Ok("".to_string()) }
+	}
+}
+
+/// Shows a full-screen dashboard of the open timers, totals and recent timeblocks, refreshed
+/// every second, until the user quits.
+pub fn run(s: &dyn TimeTracker) -> Result<(), Error> {
+	let tty = termion::get_tty()?;
+	let mut out = AlternateScreen::from(termion::get_tty()?.into_raw_mode()?);
+	// Keys are read on their own thread so the screen keeps ticking while waiting for input.
+	let (tx, rx) = mpsc::channel();
+	thread::spawn(move || {
+		for k in tty.keys() {
+			if tx.send(k).is_err() {
+				break;
+			}
+		}
+	});
+	write!(out, "{}{}", termion::cursor::Hide, termion::clear::All)?;
+	let mut selected = 0;
+	let mut message = String::new();
+	let result = loop {
+		let f = frame(s)?;
+		if selected >= f.open.len() {
+			selected = f.open.len().saturating_sub(1);
+		}
+		let size = screen_size()?;
+		draw(&mut out, &f, selected, &message, size)?;
+		let key = match rx.recv_timeout(::std::time::Duration::from_secs(1)) {
+			Ok(Ok(k)) => { k }
+			Ok(Err(e)) => { break Err(Error::IOError(e)) }
+			Err(mpsc::RecvTimeoutError::Timeout) => { continue }
+			Err(mpsc::RecvTimeoutError::Disconnected) => { break Ok(()) }
+		};
+		let r = match key {
+			Key::Char('q') | Key::Esc | Key::Ctrl('c') => { break Ok(()) }
+			Key::Up | Key::Char('k') => {
+				selected = selected.saturating_sub(1);
+				Ok("".to_string())
+			}
+			Key::Down | Key::Char('j') => {
+				selected += 1;
+				Ok("".to_string())
+			}
+			_ => { act(s, key, &f, f.open.get(selected).map(|x| &x.0), &rx, size.1, &mut out) }
+		};
+		// Failed actions are shown in the footer rather than ending the dashboard.
+		message = match r {
+			Ok(m) => { m }
+			Err(e) => { format!("error: {}", e) }
+		};
+	};
+	write!(out, "{}", termion::cursor::Show)?;
+	out.flush()?;
+	result
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::{ db, Offline };
+
+	/// Runs the action bound to `key`, with `typed` as the keys that follow it.
+	fn press(s: &dyn TimeTracker, key: Key, typed: &str) -> String {
+		let (tx, rx) = mpsc::channel();
+		for c in typed.chars() {
+			tx.send(Ok(Key::Char(c))).unwrap();
+		}
+		let f = frame(s).unwrap();
+		let mut out = Vec::new();
+		act(s, key, &f, f.open.first().map(|x| &x.0), &rx, 24, &mut out).unwrap()
+	}
+
+	#[test]
+	fn keys_start_switch_pause_annotate_and_stop_timers() {
+		let conn = db::memory();
+		let s = Offline { conn: &conn };
+		let psrc: &dyn ProjectDataSource = &conn;
+		psrc.upsert("Acme".to_string(), "/projects/1".to_string(), None, true).unwrap();
+		psrc.upsert("Beta".to_string(), "/projects/2".to_string(), None, true).unwrap();
+		let running = |s: &dyn TimeTracker| -> Vec<(String, bool, String)> {
+			let f = frame(s).unwrap();
+			f.open.iter().map(|x| (project_name(&f, &x.0), x.2, x.0.notes.clone())).collect()
+		};
+
+		assert_eq!(press(&s, Key::Char('s'), ""), "No timer running");
+		assert_eq!(press(&s, Key::Char('i'), "beta\n"), "Started Beta");
+		assert_eq!(running(&s), vec![("Beta".to_string(), false, "".to_string())]);
+		assert_eq!(press(&s, Key::Char('i'), "zzz\n"), "");
+
+		assert_eq!(press(&s, Key::Char('p'), ""), "Paused");
+		assert!(running(&s)[0].1);
+		assert_eq!(press(&s, Key::Char('p'), ""), "Resumed");
+		assert!(!running(&s)[0].1);
+
+		assert_eq!(press(&s, Key::Char('n'), "review\n"), "Notes saved");
+		assert_eq!(running(&s)[0].2, "review");
+		assert_eq!(press(&s, Key::Char('w'), "acme\n"), "Switched to Acme");
+		assert_eq!(running(&s), vec![("Acme".to_string(), false, "".to_string())]);
+
+		assert_eq!(press(&s, Key::Char('x'), ""), "");
+		assert_eq!(press(&s, Key::Char('s'), ""), "Stopped Acme");
+		assert!(running(&s).is_empty());
+		press(&s, Key::Char('i'), "acme\n");
+		press(&s, Key::Char('i'), "beta\n");
+		assert_eq!(press(&s, Key::Char('S'), ""), "Stopped 2 timer(s)");
+	}
+}